@@ -0,0 +1,158 @@
+use std::fmt;
+use std::str::FromStr;
+
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::r#const::{COIN, MAX_MONEY};
+
+/// Amount is a number of the smallest coin units, `COIN` units make one coin
+/// the arithmetic is checked and fails on results above `MAX_MONEY`
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(MAX_MONEY);
+
+    pub const fn from_units(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    pub const fn from_coins(coins: u64) -> Amount {
+        Amount(coins * COIN)
+    }
+
+    pub fn units(self) -> u64 {
+        self.0
+    }
+
+    /// amounts above `MAX_MONEY` can't appear in a valid transaction
+    pub fn is_valid(self) -> bool {
+        self.0 <= MAX_MONEY
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0
+            .checked_add(other.0)
+            .map(Amount)
+            .filter(|sum| sum.is_valid())
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    pub fn checked_mul(self, factor: u64) -> Option<Amount> {
+        self.0
+            .checked_mul(factor)
+            .map(Amount)
+            .filter(|product| product.is_valid())
+    }
+
+    /// sum of `amounts`, `None` if it is above `MAX_MONEY`
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |sum, amount| sum.checked_add(amount))
+    }
+}
+
+/// whole coins followed by the fractional part without trailing zeros, like `1.25`
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (coins, units) = (self.0 / COIN, self.0 % COIN);
+        if units == 0 {
+            return write!(f, "{}", coins);
+        }
+        let digits = COIN.ilog10() as usize;
+        let fraction = format!("{:0width$}", units, width = digits);
+        write!(f, "{}.{}", coins, fraction.trim_end_matches('0'))
+    }
+}
+
+/// parses a decimal number of coins, like `10` or `0.5`
+impl FromStr for Amount {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Amount, failure::Error> {
+        let invalid = || format_err!("invalid amount {}", s);
+        let (coins, fraction) = s.trim().split_once('.').unwrap_or((s.trim(), ""));
+        let digits = COIN.ilog10() as usize;
+        if coins.is_empty() && fraction.is_empty() || fraction.len() > digits {
+            return Err(invalid());
+        }
+        let all_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if !all_digits(coins) || !all_digits(fraction) {
+            return Err(invalid());
+        }
+        let coins: u64 = if coins.is_empty() {
+            0
+        } else {
+            coins.parse().map_err(|_| invalid())?
+        };
+        let units: u64 = if fraction.is_empty() {
+            0
+        } else {
+            format!("{:0<width$}", fraction, width = digits)
+                .parse()
+                .map_err(|_| invalid())?
+        };
+        coins
+            .checked_mul(COIN)
+            .and_then(|coins| coins.checked_add(units))
+            .map(Amount)
+            .filter(|amount| amount.is_valid())
+            .ok_or_else(|| format_err!("amount {} is above the money supply", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        for (text, units) in [
+            ("0", 0),
+            ("1", COIN),
+            ("1.5", COIN + COIN / 2),
+            ("0.00000001", 1),
+            ("21000000", MAX_MONEY),
+        ] {
+            let amount: Amount = text.parse().unwrap();
+            assert_eq!(amount.units(), units);
+            assert_eq!(amount.to_string(), text);
+        }
+        assert_eq!(".5".parse::<Amount>().unwrap().to_string(), "0.5");
+        for bad in [
+            "",
+            ".",
+            "-1",
+            "1.000000001",
+            "1e3",
+            "21000000.00000001",
+            "1,5",
+        ] {
+            assert!(bad.parse::<Amount>().is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let one = Amount::from_coins(1);
+        assert_eq!(one.checked_add(one), Some(Amount::from_coins(2)));
+        assert_eq!(Amount::MAX.checked_add(Amount::from_units(1)), None);
+        assert_eq!(Amount::ZERO.checked_sub(one), None);
+        assert_eq!(one.checked_mul(3), Some(Amount::from_coins(3)));
+        assert_eq!(Amount::MAX.checked_mul(2), None);
+        assert_eq!(
+            Amount::checked_sum(vec![one; 3]),
+            Some(Amount::from_coins(3))
+        );
+        assert_eq!(Amount::checked_sum(vec![Amount::MAX, one]), None);
+        assert!(!Amount::from_units(u64::MAX).is_valid());
+    }
+}
@@ -1,134 +1,191 @@
-use bincode::serialize;
-use crypto::digest::Digest;
-use crypto::sha2::Sha256;
-use std::time::SystemTime;
-
+use crate::block_header::{hash_to_bytes, BlockHeader, HEADER_LEN};
 use crate::transaction::Transaction;
+use crate::utils::{hex_decode, now_ms};
 
-use crate::r#const::{DIFFICULTY, VERSION};
+use crate::pow::Target;
+use crate::r#const::BLOCK_VERSION;
 use serde::{Deserialize, Serialize};
 
-use crate::mergetx::MergeTX;
+use crate::mergetx::{MergeTX, MerkleProof};
 use merkle_cbt::merkle_tree::CBMT;
 
-enum MiningResponse {
-    Success(String),
-    Failure,
-}
-
 // specs available at https://twohop.ventures/wp-content/uploads/2019/12/BSVSpec-Blocks-V1.0.pdf
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
-    timestamp: u128,
+    header: BlockHeader,
+    /// cached hash of the header, empty until the block is mined
     hash: String,
-    hash_prev_block: String,
-    transactions: Vec<Transaction>,
-    nonce: i32,
     height: i32,
-    version: i8,
-    difficulty: u32,
+    transactions: Vec<Transaction>,
+}
+
+/// BlockBody is the part of a block that is not covered by the header
+/// it is stored apart from the header so headers can be read on their own
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockBody {
+    pub height: i32,
+    pub transactions: Vec<Transaction>,
 }
 
 impl Block {
+    /// creates an unmined block, use `Miner` to find a valid nonce
     pub fn new(
         transactions: Vec<Transaction>,
         hash_prev_block: String,
         height: i32,
+        bits: u32,
     ) -> Result<Block, failure::Error> {
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_millis();
+        let timestamp = now_ms()?;
 
         let mut block = Block {
-            timestamp,
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_hash: hash_to_bytes(&hash_prev_block)?,
+                merkle_root: [0; 32],
+                timestamp,
+                bits,
+                nonce: 0,
+            },
             hash: "".to_string(), // set during mining phase
-            hash_prev_block,
-            transactions,
-            nonce: 0,
             height,
-            version: VERSION,
-            difficulty: DIFFICULTY,
+            transactions,
         };
-
-        block.mine().expect("Mining error");
+        block.header.merkle_root = block.hash_transactions()?;
         Ok(block)
     }
 
+    /// joins a stored header and body back into a block
+    pub fn from_parts(header: BlockHeader, body: BlockBody) -> Block {
+        Block {
+            hash: header.hash(),
+            header,
+            height: body.height,
+            transactions: body.transactions,
+        }
+    }
+
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    pub fn body(&self) -> BlockBody {
+        BlockBody {
+            height: self.height,
+            transactions: self.transactions.clone(),
+        }
+    }
+
     pub fn get_transaction(&self) -> &Vec<Transaction> {
         &self.transactions
     }
 
+    /// size of the header and the serialized transactions in bytes
+    pub fn size(&self) -> Result<usize, failure::Error> {
+        let mut size = HEADER_LEN;
+        for tx in &self.transactions {
+            size += tx.size()?;
+        }
+        Ok(size)
+    }
+
     pub fn get_hash(&self) -> String {
         self.hash.clone()
     }
 
+    /// hex encoded hash of the parent, the genesis block has no parent
+    /// and returns an empty string
     pub fn get_prev_block_hash(&self) -> String {
-        self.hash_prev_block.clone()
+        if self.header.prev_hash == [0; 32] {
+            return String::new();
+        }
+        self.header.prev_hash_hex()
     }
 
     pub fn get_height(&self) -> i32 {
         self.height
     }
 
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new(vec![coinbase], String::new(), 0).unwrap()
+    pub fn get_timestamp(&self) -> u64 {
+        self.header.timestamp
     }
 
-    // https://stackoverflow.com/questions/38215753/how-do-i-implement-copy-and-clone-for-a-type-that-contains-a-string-or-any-type
-    fn mine(&mut self) -> Result<(), failure::Error> {
-        loop {
-            match self.validate() {
-                Err(_e) => {}
-                Ok(response) => match response {
-                    MiningResponse::Success(valid_hash) => {
-                        self.hash = valid_hash;
-                        return Ok(());
-                    }
-                    MiningResponse::Failure => self.nonce += 1,
-                },
-            }
-        }
+    /// compact encoded target the block hash has to meet
+    pub fn get_bits(&self) -> u32 {
+        self.header.bits
     }
 
-    fn prepare_hash_data(&self) -> Result<Vec<u8>, failure::Error> {
-        let content = (
-            self.hash_prev_block.clone(),
-            self.hash_transactions()?,
-            self.timestamp,
-            DIFFICULTY,
-            self.nonce,
-        );
-        let bytes = serialize(&content)?;
-        Ok(bytes)
+    /// expected number of hashes needed to mine a block at this difficulty
+    pub fn work(&self) -> u128 {
+        Target::from_compact(self.header.bits).work()
     }
 
-    /// HashTransactions returns a hash of the transactions in the block
-    fn hash_transactions(&self) -> Result<Vec<u8>, failure::Error> {
-        let mut transactions = Vec::new();
+    pub fn new_genesis_block(coinbase: Transaction, bits: u32) -> Result<Block, failure::Error> {
+        Block::new(vec![coinbase], String::new(), 0, bits)
+    }
+
+    /// leaves of the merkle tree are the raw 32 byte transaction ids, recomputed from
+    /// the content so the root does not depend on the ids a peer sent along
+    fn merkle_leaves(&self) -> Result<Vec<Vec<u8>>, failure::Error> {
+        let mut leaves = Vec::new();
         for tx in self.transactions.iter() {
-            transactions.push(tx.hash()?.as_bytes().to_owned());
+            leaves.push(hex_decode(&tx.hash()?)?);
+        }
+        Ok(leaves)
+    }
+
+    /// HashTransactions returns a hash of the transactions in the block
+    pub(crate) fn hash_transactions(&self) -> Result<[u8; 32], failure::Error> {
+        let root = CBMT::<Vec<u8>, MergeTX>::build_merkle_root(&self.merkle_leaves()?);
+        if root.is_empty() {
+            return Ok([0; 32]);
         }
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&*transactions);
-        Ok(tree.root())
-    }
-
-    /// prepares hash data with nonce incremented on each failure
-    /// then matches the first four 4 index of data with
-    /// "0000" as this difficulty is set
-    fn validate(&self) -> Result<MiningResponse, failure::Error> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        // print_bytes(&data);
-
-        let mut vec1: Vec<u8> = vec![];
-        vec1.resize(DIFFICULTY as usize, '0' as u8);
-
-        let is_found = &hasher.result_str()[0..DIFFICULTY as usize] == String::from_utf8(vec1)?;
-        if is_found {
-            Ok(MiningResponse::Success(hasher.result_str()))
-        } else {
-            Ok(MiningResponse::Failure)
+        let mut merkle_root = [0u8; 32];
+        merkle_root.copy_from_slice(&root);
+        Ok(merkle_root)
+    }
+
+    /// builds a proof that `txid` is included in this block,
+    /// it can be checked against the header with `verify_merkle_proof`
+    pub fn merkle_proof(&self, txid: &str) -> Result<MerkleProof, failure::Error> {
+        let position = self
+            .transactions
+            .iter()
+            .position(|tx| tx.id == txid)
+            .ok_or_else(|| {
+                failure::format_err!("transaction {} is not in block {}", txid, self.hash)
+            })?;
+        let proof = CBMT::<Vec<u8>, MergeTX>::build_merkle_proof(
+            &self.merkle_leaves()?,
+            &[position as u32],
+        )
+        .ok_or_else(|| failure::format_err!("cannot build merkle proof for {}", txid))?;
+        Ok(MerkleProof {
+            index: proof.indices()[0],
+            lemmas: proof.lemmas().to_vec(),
+        })
+    }
+
+    /// recomputes the block hash from the header
+    pub(crate) fn calculate_hash(&self) -> String {
+        self.header.hash()
+    }
+
+    pub(crate) fn set_nonce(&mut self, nonce: u64) {
+        self.header.nonce = nonce;
+    }
+
+    pub(crate) fn set_hash(&mut self, hash: String) {
+        self.hash = hash;
+    }
+
+    /// changes the extra nonce of the coinbase transaction,
+    /// which gives the miner a fresh merkle root and nonce space
+    pub(crate) fn set_extra_nonce(&mut self, extra_nonce: u64) -> Result<(), failure::Error> {
+        match self.transactions.iter_mut().find(|tx| tx.is_coinbase()) {
+            Some(coinbase) => coinbase.set_extra_nonce(extra_nonce)?,
+            None => return Err(failure::format_err!("block has no coinbase transaction")),
         }
+        self.header.merkle_root = self.hash_transactions()?;
+        Ok(())
     }
 }
@@ -0,0 +1,108 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{hex_decode, hex_encode};
+
+pub const HEADER_LEN: usize = 88;
+
+/// BlockHeader is everything the proof of work commits to
+/// the transactions are only referenced through their merkle root,
+/// so headers can be hashed, stored and synced without the block body
+///
+/// binary layout, integers are little endian
+/// | version 4 | prev_hash 32 | merkle_root 32 | timestamp 8 | bits 4 | nonce 8 |
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub prev_hash: [u8; 32],
+    pub merkle_root: [u8; 32],
+    /// milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub bits: u32,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&self.version.to_le_bytes());
+        bytes[4..36].copy_from_slice(&self.prev_hash);
+        bytes[36..68].copy_from_slice(&self.merkle_root);
+        bytes[68..76].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[76..80].copy_from_slice(&self.bits.to_le_bytes());
+        bytes[80..88].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BlockHeader, failure::Error> {
+        if bytes.len() != HEADER_LEN {
+            return Err(failure::format_err!(
+                "block header must be {} bytes, got {}",
+                HEADER_LEN,
+                bytes.len()
+            ));
+        }
+        let mut prev_hash = [0u8; 32];
+        prev_hash.copy_from_slice(&bytes[4..36]);
+        let mut merkle_root = [0u8; 32];
+        merkle_root.copy_from_slice(&bytes[36..68]);
+        Ok(BlockHeader {
+            version: u32::from_le_bytes(bytes[0..4].try_into()?),
+            prev_hash,
+            merkle_root,
+            timestamp: u64::from_le_bytes(bytes[68..76].try_into()?),
+            bits: u32::from_le_bytes(bytes[76..80].try_into()?),
+            nonce: u64::from_le_bytes(bytes[80..88].try_into()?),
+        })
+    }
+
+    /// hex encoded sha256 of the binary header, this is the block hash
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(&self.to_bytes());
+        hasher.result_str()
+    }
+
+    pub fn prev_hash_hex(&self) -> String {
+        hex_encode(&self.prev_hash)
+    }
+}
+
+/// decodes a hex block hash into the header representation
+/// an empty string stands for the missing parent of the genesis block
+pub fn hash_to_bytes(hash: &str) -> Result<[u8; 32], failure::Error> {
+    let mut bytes = [0u8; 32];
+    if hash.is_empty() {
+        return Ok(bytes);
+    }
+    let decoded = hex_decode(hash)?;
+    if decoded.len() != 32 {
+        return Err(failure::format_err!("hash {} is not 32 bytes long", hash));
+    }
+    bytes.copy_from_slice(&decoded);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = BlockHeader {
+            version: 1,
+            prev_hash: [7; 32],
+            merkle_root: [9; 32],
+            timestamp: 1_676_000_000_000,
+            bits: 0x1f00ffff,
+            nonce: u64::MAX - 1,
+        };
+        let bytes = header.to_bytes();
+        assert_eq!(BlockHeader::from_bytes(&bytes).unwrap(), header);
+
+        let mut other = header.clone();
+        other.version = 2;
+        assert_ne!(other.hash(), header.hash());
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Mutex;
+
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+
+use crate::block::{Block, BlockBody};
+use crate::block_header::BlockHeader;
+use crate::utils::open_db;
+use crate::utxo_set::{BlockUndo, OutPoint, UtxoEntry};
+
+const HEAD_KEY: &[u8] = b"head";
+
+/// Tree is a named keyspace inside a block store
+/// every store keeps the same set of trees so that a single batch
+/// can touch several of them at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tree {
+    /// block hash -> serialized block body, height and transactions
+    Blocks,
+    /// block hash -> fixed size binary header
+    Headers,
+    /// chain wide pointers such as the head hash
+    Meta,
+    /// big endian height -> block hash of the active chain
+    Heights,
+    /// block hash -> cumulative work of the chain ending at that block
+    Work,
+    /// txid -> block hash and position of the transaction in the active chain
+    Txs,
+    /// outpoint -> unspent output at the tip of the active chain
+    Utxos,
+    /// pub key hash followed by outpoint -> nothing, finds the outputs of an address
+    AddressUtxos,
+    /// block hash -> data needed to disconnect the block from the UTXO set
+    Undo,
+}
+
+impl Tree {
+    pub const ALL: [Tree; 9] = [
+        Tree::Blocks,
+        Tree::Headers,
+        Tree::Meta,
+        Tree::Heights,
+        Tree::Work,
+        Tree::Txs,
+        Tree::Utxos,
+        Tree::AddressUtxos,
+        Tree::Undo,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Tree::Blocks => "blocks",
+            Tree::Headers => "headers",
+            Tree::Meta => "meta",
+            Tree::Heights => "heights",
+            Tree::Work => "work",
+            Tree::Txs => "txs",
+            Tree::Utxos => "utxos",
+            Tree::AddressUtxos => "address_utxos",
+            Tree::Undo => "undo",
+        }
+    }
+
+    fn index(&self) -> usize {
+        Tree::ALL.iter().position(|t| t == self).unwrap()
+    }
+}
+
+pub fn height_key(height: i32) -> [u8; 4] {
+    (height as u32).to_be_bytes()
+}
+
+fn address_key(pub_key_hash: &[u8], outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = pub_key_hash.to_vec();
+    key.extend_from_slice(&outpoint.to_key());
+    key
+}
+
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// a `None` value removes the key
+type BatchOp = (Tree, Vec<u8>, Option<Vec<u8>>);
+
+/// StoreBatch collects writes which are applied atomically by `BlockStore::write`
+#[derive(Debug, Default)]
+pub struct StoreBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl StoreBatch {
+    pub fn new() -> StoreBatch {
+        StoreBatch::default()
+    }
+
+    pub fn put(&mut self, tree: Tree, key: &[u8], value: Vec<u8>) {
+        self.ops.push((tree, key.to_vec(), Some(value)));
+    }
+
+    pub fn remove(&mut self, tree: Tree, key: &[u8]) {
+        self.ops.push((tree, key.to_vec(), None));
+    }
+
+    /// removes every key `store` currently holds in `tree`
+    pub fn clear(&mut self, store: &dyn BlockStore, tree: Tree) -> Result<(), failure::Error> {
+        for (key, _) in store.scan_prefix(tree, &[])? {
+            self.remove(tree, &key);
+        }
+        Ok(())
+    }
+
+    /// stores header and body under the block hash
+    pub fn put_block(&mut self, block: &Block) -> Result<(), failure::Error> {
+        let hash = block.get_hash();
+        self.put(
+            Tree::Headers,
+            hash.as_bytes(),
+            block.get_header().to_bytes().to_vec(),
+        );
+        self.put(Tree::Blocks, hash.as_bytes(), serialize(&block.body())?);
+        Ok(())
+    }
+
+    pub fn set_head(&mut self, hash: &str) {
+        self.put(Tree::Meta, HEAD_KEY, hash.as_bytes().to_vec());
+    }
+
+    pub fn set_hash_at_height(&mut self, height: i32, hash: &str) {
+        self.put(Tree::Heights, &height_key(height), hash.as_bytes().to_vec());
+    }
+
+    pub fn remove_hash_at_height(&mut self, height: i32) {
+        self.remove(Tree::Heights, &height_key(height));
+    }
+
+    /// adds `block` to the height and transaction indexes of the active chain
+    pub fn index_block(&mut self, block: &Block) -> Result<(), failure::Error> {
+        let hash = block.get_hash();
+        self.set_hash_at_height(block.get_height(), &hash);
+        for (index, tx) in block.get_transaction().iter().enumerate() {
+            self.put(
+                Tree::Txs,
+                tx.id.as_bytes(),
+                serialize(&(&hash, index as u32))?,
+            );
+        }
+        Ok(())
+    }
+
+    /// removes the transactions of a disconnected block from the index
+    pub fn unindex_transactions(&mut self, block: &Block) {
+        for tx in block.get_transaction() {
+            self.remove(Tree::Txs, tx.id.as_bytes());
+        }
+    }
+
+    pub fn put_utxo(
+        &mut self,
+        outpoint: &OutPoint,
+        entry: &UtxoEntry,
+    ) -> Result<(), failure::Error> {
+        self.put(Tree::Utxos, &outpoint.to_key(), serialize(entry)?);
+        Ok(())
+    }
+
+    pub fn remove_utxo(&mut self, outpoint: &OutPoint) {
+        self.remove(Tree::Utxos, &outpoint.to_key());
+    }
+
+    pub fn put_address_utxo(&mut self, pub_key_hash: &[u8], outpoint: &OutPoint) {
+        self.put(
+            Tree::AddressUtxos,
+            &address_key(pub_key_hash, outpoint),
+            Vec::new(),
+        );
+    }
+
+    pub fn remove_address_utxo(&mut self, pub_key_hash: &[u8], outpoint: &OutPoint) {
+        self.remove(Tree::AddressUtxos, &address_key(pub_key_hash, outpoint));
+    }
+
+    pub fn set_undo(&mut self, hash: &str, undo: &BlockUndo) -> Result<(), failure::Error> {
+        self.put(Tree::Undo, hash.as_bytes(), serialize(undo)?);
+        Ok(())
+    }
+
+    pub fn remove_undo(&mut self, hash: &str) {
+        self.remove(Tree::Undo, hash.as_bytes());
+    }
+
+    pub fn set_chain_work(&mut self, hash: &str, work: u128) {
+        self.put(Tree::Work, hash.as_bytes(), work.to_be_bytes().to_vec());
+    }
+}
+
+/// BlockStore is the persistence layer behind `Blockchain`
+/// implementors only provide raw access to the trees,
+/// block, head and height helpers are built on top of that
+pub trait BlockStore: Debug + Send + Sync {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>, failure::Error>;
+
+    /// applies every operation of the batch or none of them
+    fn write(&self, batch: StoreBatch) -> Result<(), failure::Error>;
+
+    fn flush(&self) -> Result<(), failure::Error>;
+
+    /// every entry of `tree` whose key starts with `prefix`, in key order
+    fn scan_prefix(&self, tree: Tree, prefix: &[u8]) -> Result<Vec<KeyValue>, failure::Error>;
+
+    /// reads only the header, without deserializing any transaction
+    fn get_header(&self, hash: &str) -> Result<Option<BlockHeader>, failure::Error> {
+        match self.get(Tree::Headers, hash.as_bytes())? {
+            Some(data) => Ok(Some(BlockHeader::from_bytes(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_block(&self, hash: &str) -> Result<Option<Block>, failure::Error> {
+        let header = match self.get_header(hash)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        match self.get(Tree::Blocks, hash.as_bytes())? {
+            Some(data) => {
+                let body: BlockBody = deserialize(&data)?;
+                Ok(Some(Block::from_parts(header, body)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn contains_block(&self, hash: &str) -> Result<bool, failure::Error> {
+        Ok(self.get(Tree::Headers, hash.as_bytes())?.is_some())
+    }
+
+    fn get_head(&self) -> Result<Option<String>, failure::Error> {
+        match self.get(Tree::Meta, HEAD_KEY)? {
+            Some(hash) => Ok(Some(String::from_utf8(hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_hash_at_height(&self, height: i32) -> Result<Option<String>, failure::Error> {
+        match self.get(Tree::Heights, &height_key(height))? {
+            Some(hash) => Ok(Some(String::from_utf8(hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// block hash and position of a transaction of the active chain
+    fn get_tx_location(&self, txid: &str) -> Result<Option<(String, u32)>, failure::Error> {
+        match self.get(Tree::Txs, txid.as_bytes())? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<UtxoEntry>, failure::Error> {
+        match self.get(Tree::Utxos, &outpoint.to_key())? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// unspent outputs locked to `pub_key_hash`, read through the address index
+    fn get_address_utxos(
+        &self,
+        pub_key_hash: &[u8],
+    ) -> Result<Vec<(OutPoint, UtxoEntry)>, failure::Error> {
+        let mut utxos = Vec::new();
+        for (key, _) in self.scan_prefix(Tree::AddressUtxos, pub_key_hash)? {
+            let outpoint = OutPoint::from_key(&key[pub_key_hash.len()..])?;
+            let entry = self
+                .get_utxo(&outpoint)?
+                .ok_or_else(|| format_err!("address index points to spent output"))?;
+            utxos.push((outpoint, entry));
+        }
+        Ok(utxos)
+    }
+
+    fn get_undo(&self, hash: &str) -> Result<Option<BlockUndo>, failure::Error> {
+        match self.get(Tree::Undo, hash.as_bytes())? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_chain_work(&self, hash: &str) -> Result<Option<u128>, failure::Error> {
+        match self.get(Tree::Work, hash.as_bytes())? {
+            Some(work) => {
+                let mut bytes = [0u8; 16];
+                bytes.copy_from_slice(&work);
+                Ok(Some(u128::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// SledBlockStore keeps every tree inside one sled database
+/// so that a batch can be committed in a single transaction
+#[derive(Debug, Clone)]
+pub struct SledBlockStore {
+    db: sled::Db,
+    trees: Vec<sled::Tree>,
+}
+
+impl SledBlockStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SledBlockStore, failure::Error> {
+        let db = open_db(path)?;
+        let mut trees = Vec::new();
+        for tree in Tree::ALL.iter() {
+            trees.push(db.open_tree(tree.name())?);
+        }
+        Ok(SledBlockStore { db, trees })
+    }
+}
+
+impl BlockStore for SledBlockStore {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>, failure::Error> {
+        Ok(self.trees[tree.index()].get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn write(&self, batch: StoreBatch) -> Result<(), failure::Error> {
+        self.trees
+            .as_slice()
+            .transaction(|trees| {
+                for (tree, key, value) in &batch.ops {
+                    let t = &trees[tree.index()];
+                    match value {
+                        Some(v) => t.insert(key.as_slice(), v.as_slice())?,
+                        None => t.remove(key.as_slice())?,
+                    };
+                }
+                Ok::<(), ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e: TransactionError<()>| format_err!("block store write failed: {:?}", e))
+    }
+
+    fn flush(&self) -> Result<(), failure::Error> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn scan_prefix(&self, tree: Tree, prefix: &[u8]) -> Result<Vec<KeyValue>, failure::Error> {
+        let mut entries = Vec::new();
+        for entry in self.trees[tree.index()].scan_prefix(prefix) {
+            let (k, v) = entry?;
+            entries.push((k.to_vec(), v.to_vec()));
+        }
+        Ok(entries)
+    }
+}
+
+/// MemoryBlockStore is a non persistent store, used for tests
+/// and for nodes that do not need to survive a restart
+type MemoryTrees = HashMap<Tree, BTreeMap<Vec<u8>, Vec<u8>>>;
+
+#[derive(Debug, Default)]
+pub struct MemoryBlockStore {
+    trees: Mutex<MemoryTrees>,
+}
+
+impl MemoryBlockStore {
+    pub fn new() -> MemoryBlockStore {
+        MemoryBlockStore::default()
+    }
+}
+
+impl BlockStore for MemoryBlockStore {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>, failure::Error> {
+        let trees = self.trees.lock().unwrap();
+        Ok(trees.get(&tree).and_then(|t| t.get(key)).cloned())
+    }
+
+    fn write(&self, batch: StoreBatch) -> Result<(), failure::Error> {
+        let mut trees = self.trees.lock().unwrap();
+        for (tree, key, value) in batch.ops {
+            let t = trees.entry(tree).or_default();
+            match value {
+                Some(v) => t.insert(key, v),
+                None => t.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), failure::Error> {
+        Ok(())
+    }
+
+    fn scan_prefix(&self, tree: Tree, prefix: &[u8]) -> Result<Vec<KeyValue>, failure::Error> {
+        let trees = self.trees.lock().unwrap();
+        Ok(match trees.get(&tree) {
+            Some(t) => t
+                .range(prefix.to_vec()..)
+                .take_while(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+}
@@ -0,0 +1,410 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::block_header::HEADER_LEN;
+use crate::blockchain::Blockchain;
+use crate::mempool::{cmp_fee_rate, Mempool, MempoolEntry};
+use crate::r#const::{BLOCK_VERSION, COINBASE_RESERVED_SIZE};
+use crate::transaction::Transaction;
+use crate::utxo_view::UtxoView;
+
+/// TemplateTransaction is a transaction of a block template with what a miner needs to know
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateTransaction {
+    pub tx: Transaction,
+    pub fee: Amount,
+    pub size: usize,
+    /// positions in the block of the transactions it spends outputs of, the coinbase is at 0
+    pub depends: Vec<usize>,
+}
+
+/// BlockTemplate is the next block short of its coinbase and nonce,
+/// like the result of `getblocktemplate` it lets a miner outside the node work on it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockTemplate {
+    pub version: u32,
+    pub prev_hash: String,
+    pub height: i32,
+    pub bits: u32,
+    /// the block timestamp may not be earlier, in milliseconds
+    pub min_time: u64,
+    /// parents come before their children
+    pub transactions: Vec<TemplateTransaction>,
+    /// the subsidy plus the fees of the transactions, the most the coinbase may pay
+    pub coinbase_value: Amount,
+    /// limits of the finished block, header and coinbase included
+    pub size_limit: usize,
+    pub tx_limit: usize,
+}
+
+impl BlockTemplate {
+    /// the unmined block with `coinbase` in front of the transactions, see `Miner`
+    pub fn into_block(self, coinbase: Transaction) -> Result<Block, failure::Error> {
+        if !coinbase.is_coinbase() {
+            return Err(format_err!("transaction {} is not a coinbase", coinbase.id));
+        }
+        if coinbase.output_value()? > self.coinbase_value {
+            return Err(format_err!(
+                "coinbase pays more than {}",
+                self.coinbase_value
+            ));
+        }
+        if self.transactions.len() + 1 > self.tx_limit {
+            return Err(format_err!("block is above {} transactions", self.tx_limit));
+        }
+        let mut transactions = vec![coinbase];
+        transactions.extend(self.transactions.into_iter().map(|entry| entry.tx));
+        let block = Block::new(transactions, self.prev_hash, self.height, self.bits)?;
+        if block.size()? > self.size_limit {
+            return Err(format_err!("block is above {} bytes", self.size_limit));
+        }
+        Ok(block)
+    }
+}
+
+/// a mempool transaction with its ancestors that are not in the template yet,
+/// a child pays for its parents so it is ranked by their combined fee rate
+struct Package<'a> {
+    txid: &'a str,
+    fee: Amount,
+    size: usize,
+}
+
+impl Ord for Package<'_> {
+    /// higher fee rate first, ties go by txid to keep the order stable
+    fn cmp(&self, other: &Self) -> Ordering {
+        cmp_fee_rate(self.fee, self.size, other.fee, other.size)
+            .then_with(|| other.txid.cmp(self.txid))
+    }
+}
+
+impl PartialOrd for Package<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Package<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Package<'_> {}
+
+/// ancestors of a mempool transaction that are not in the template yet and what they add up to
+struct PackageState {
+    fee: Amount,
+    size: usize,
+    ancestors: HashSet<String>,
+}
+
+/// BlockTemplateBuilder picks the transactions of the next block on top of the tip
+/// mempool transactions are taken by the fee rate of their package until the block is full,
+/// each one is verified on top of the ones before it
+pub struct BlockTemplateBuilder<'a> {
+    chain: &'a Blockchain,
+    mempool: Option<&'a Mempool>,
+    max_size: usize,
+    max_txs: usize,
+}
+
+impl<'a> BlockTemplateBuilder<'a> {
+    /// an empty template within the limits of the chain params
+    pub fn new(chain: &'a Blockchain) -> BlockTemplateBuilder<'a> {
+        let params = chain.config().params();
+        BlockTemplateBuilder {
+            chain,
+            mempool: None,
+            max_size: params.max_block_size,
+            max_txs: params.max_block_txs,
+        }
+    }
+
+    pub fn with_mempool(mut self, mempool: &'a Mempool) -> Self {
+        self.mempool = Some(mempool);
+        self
+    }
+
+    /// a smaller block than the chain params allow, a larger one is not valid
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size.min(self.chain.config().params().max_block_size);
+        self
+    }
+
+    /// fewer transactions than the chain params allow, coinbase included
+    pub fn with_max_transactions(mut self, max_txs: usize) -> Self {
+        self.max_txs = max_txs.min(self.chain.config().params().max_block_txs);
+        self
+    }
+
+    pub fn build(&self) -> Result<BlockTemplate, failure::Error> {
+        let tip = self.chain.get_tip()?;
+        let height = tip.get_height() + 1;
+        let selected = match self.mempool {
+            Some(mempool) => self.select(mempool)?,
+            None => Vec::new(),
+        };
+
+        let mut positions: HashMap<&str, usize> = HashMap::new();
+        let mut transactions = Vec::new();
+        for entry in selected {
+            let mut depends: Vec<usize> = entry
+                .tx
+                .input
+                .iter()
+                .filter_map(|vin| positions.get(vin.txid.as_str()).copied())
+                .collect();
+            depends.sort_unstable();
+            depends.dedup();
+            positions.insert(&entry.tx.id, transactions.len() + 1);
+            transactions.push(TemplateTransaction {
+                tx: entry.tx.clone(),
+                fee: entry.fee,
+                size: entry.size,
+                depends,
+            });
+        }
+        let coinbase_value = Amount::checked_sum(
+            std::iter::once(self.chain.config().params().subsidy(height))
+                .chain(transactions.iter().map(|entry| entry.fee)),
+        )
+        .ok_or_else(|| format_err!("block reward is above the money supply"))?;
+
+        Ok(BlockTemplate {
+            version: BLOCK_VERSION,
+            prev_hash: tip.get_hash(),
+            height,
+            bits: self.chain.next_bits(&tip)?,
+            min_time: self.chain.median_time_past(&tip)?,
+            transactions,
+            coinbase_value,
+            size_limit: self.max_size,
+            tx_limit: self.max_txs,
+        })
+    }
+
+    /// the mempool entries going into the block, parents before children
+    fn select<'m>(&self, mempool: &'m Mempool) -> Result<Vec<&'m MempoolEntry>, failure::Error> {
+        let size_limit = self
+            .max_size
+            .saturating_sub(HEADER_LEN + COINBASE_RESERVED_SIZE);
+        let tx_limit = self.max_txs.saturating_sub(1);
+
+        let mut queue = BinaryHeap::new();
+        let mut packages: HashMap<&str, PackageState> = HashMap::new();
+        for entry in mempool.by_fee_rate() {
+            let ancestors = mempool.ancestors(&entry.tx.id);
+            let mut fee = entry.fee;
+            let mut size = entry.size;
+            for ancestor in ancestors.iter().filter_map(|txid| mempool.get(txid)) {
+                fee = fee
+                    .checked_add(ancestor.fee)
+                    .ok_or_else(|| format_err!("fees are above the money supply"))?;
+                size += ancestor.size;
+            }
+            queue.push(Package {
+                txid: &entry.tx.id,
+                fee,
+                size,
+            });
+            packages.insert(
+                &entry.tx.id,
+                PackageState {
+                    fee,
+                    size,
+                    ancestors,
+                },
+            );
+        }
+
+        let mut view = UtxoView::new(self.chain);
+        let mut selected: Vec<&MempoolEntry> = Vec::new();
+        let mut in_template: HashSet<&str> = HashSet::new();
+        let mut rejected: HashSet<&str> = HashSet::new();
+        let mut size = 0;
+        while let Some(package) = queue.pop() {
+            if in_template.contains(package.txid) || rejected.contains(package.txid) {
+                continue;
+            }
+            let state = &packages[package.txid];
+            // outdated, the package lost ancestors to the template and was queued again
+            if state.fee != package.fee || state.size != package.size {
+                continue;
+            }
+            if state
+                .ancestors
+                .iter()
+                .any(|txid| rejected.contains(txid.as_str()))
+                || size + package.size > size_limit
+                || selected.len() + state.ancestors.len() + 1 > tx_limit
+            {
+                rejected.insert(package.txid);
+                continue;
+            }
+
+            // a child has every ancestor of its parent and the parent itself,
+            // so ordering by the number of ancestors puts parents first
+            let mut members: Vec<&MempoolEntry> = state
+                .ancestors
+                .iter()
+                .map(String::as_str)
+                .chain(std::iter::once(package.txid))
+                .filter_map(|txid| mempool.get(txid))
+                .collect();
+            members.sort_by_key(|entry| {
+                (packages[entry.tx.id.as_str()].ancestors.len(), &entry.tx.id)
+            });
+
+            for member in members {
+                if !self.chain.verify_transaction_in(&member.tx, &view)? {
+                    rejected.insert(&member.tx.id);
+                    break;
+                }
+                view.add_pending(&member.tx);
+                in_template.insert(&member.tx.id);
+                size += member.size;
+                selected.push(member);
+
+                // descendants no longer pay for what is in the template
+                for txid in mempool.descendants(&member.tx.id) {
+                    let descendant = match mempool.get(&txid) {
+                        Some(descendant) => descendant,
+                        None => continue,
+                    };
+                    let state = match packages.get_mut(txid.as_str()) {
+                        Some(state) => state,
+                        None => continue,
+                    };
+                    if !state.ancestors.remove(&member.tx.id) {
+                        continue;
+                    }
+                    state.fee = state.fee.checked_sub(member.fee).unwrap_or(Amount::ZERO);
+                    state.size -= member.size;
+                    queue.push(Package {
+                        txid: &descendant.tx.id,
+                        fee: state.fee,
+                        size: state.size,
+                    });
+                }
+            }
+        }
+        Ok(selected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner::Miner;
+    use crate::test_util::{spend_output, TestChain};
+    use crate::utils::now_ms;
+
+    #[test]
+    fn block_templates() {
+        let mut chain = TestChain::new(3);
+        let (alice, bob, carol) = (&chain.wallets[0], &chain.wallets[1], &chain.wallets[2]);
+        let (alice_key, bob_key) = (chain.secret_key(alice), chain.secret_key(bob));
+        let first_coinbase = chain.genesis_coinbase();
+        let second_coinbase = chain.coinbase(alice);
+        let config = chain.config.clone();
+        let bc = &mut chain.bc;
+        bc.mine_block(vec![second_coinbase.clone()]).unwrap();
+
+        let empty = BlockTemplateBuilder::new(bc).build().unwrap();
+        assert!(empty.transactions.is_empty());
+        assert_eq!(empty.prev_hash, bc.get_tip().unwrap().get_hash());
+        assert_eq!(empty.height, 2);
+        assert_eq!(empty.coinbase_value, config.params().subsidy(2));
+
+        // the child pays for its parent, together they beat the other payment
+        let now = now_ms().unwrap();
+        let mut mempool = Mempool::new(config.mempool_policy().clone());
+        let parent = spend_output(&first_coinbase, 0, &alice_key, bob, Amount::from_units(400));
+        let child = spend_output(&parent, 0, &bob_key, carol, Amount::from_units(10_000));
+        let other = spend_output(
+            &second_coinbase,
+            0,
+            &alice_key,
+            carol,
+            Amount::from_units(2_000),
+        );
+        for tx in [&parent, &child, &other] {
+            mempool.add(tx.clone(), bc, now).unwrap();
+        }
+
+        let template = BlockTemplateBuilder::new(bc)
+            .with_mempool(&mempool)
+            .build()
+            .unwrap();
+        let ids: Vec<&str> = template
+            .transactions
+            .iter()
+            .map(|entry| entry.tx.id.as_str())
+            .collect();
+        assert_eq!(ids, vec![&parent.id, &child.id, &other.id]);
+        let depends: Vec<Vec<usize>> = template
+            .transactions
+            .iter()
+            .map(|entry| entry.depends.clone())
+            .collect();
+        assert_eq!(depends, vec![vec![], vec![1], vec![]]);
+        assert_eq!(
+            template.coinbase_value,
+            config
+                .params()
+                .subsidy(2)
+                .checked_add(Amount::from_units(12_400))
+                .unwrap()
+        );
+
+        let by_count = BlockTemplateBuilder::new(bc)
+            .with_mempool(&mempool)
+            .with_max_transactions(3)
+            .build()
+            .unwrap();
+        assert_eq!(by_count.transactions.len(), 2);
+        assert_eq!(by_count.transactions[1].tx.id, child.id);
+        let by_size = BlockTemplateBuilder::new(bc)
+            .with_mempool(&mempool)
+            .with_max_size(HEADER_LEN + COINBASE_RESERVED_SIZE + other.size().unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(by_size.transactions.len(), 1);
+        assert_eq!(by_size.transactions[0].tx.id, other.id);
+
+        // a miner fills in the coinbase and the nonce
+        assert!(template.clone().into_block(other.clone()).is_err());
+        let greedy = Transaction::new_coinbase(
+            bob.clone(),
+            String::new(),
+            template
+                .coinbase_value
+                .checked_add(Amount::from_units(1))
+                .unwrap(),
+            &config,
+        )
+        .unwrap();
+        assert!(template.clone().into_block(greedy).is_err());
+        let reward =
+            Transaction::new_coinbase(bob.clone(), String::new(), template.coinbase_value, &config)
+                .unwrap();
+        let too_many = BlockTemplate {
+            tx_limit: template.transactions.len(),
+            ..template.clone()
+        };
+        assert!(too_many.into_block(reward.clone()).is_err());
+        let block = template.into_block(reward).unwrap();
+        let block = Miner::default().mine(block).unwrap().block.unwrap();
+        let update = bc.add_block(block).unwrap();
+        assert_eq!(update.connected.len(), 1);
+        mempool.update(&update, bc, now).unwrap();
+        assert_eq!(mempool.len(), 0);
+        assert_eq!(bc.get_best_height().unwrap(), 2);
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+use failure::Fail;
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::pow::{hash_meets_target, Target};
+use crate::r#const::{MAX_FUTURE_BLOCK_TIME_MS, MEDIAN_TIME_SPAN};
+use crate::transaction::Transaction;
+use crate::utils::now_ms;
+
+/// reasons a block received from a peer is rejected
+#[derive(Debug)]
+pub enum BlockValidationError {
+    NoTransactions(String),
+    HashMismatch(String, String),
+    BadMerkleRoot(String),
+    BadDifficulty(String, u32, u32),
+    InsufficientWork(String),
+    UnknownParent(String),
+    BadHeight(i32, i32),
+    TimestampTooOld(u64, u64),
+    TimestampTooNew(u64),
+    BadCoinbaseCount(usize),
+    InvalidTransaction(String),
+    BadTransactionId(String, String),
+    MissingOutput(String, i32),
+    DuplicateOutput(String, u32),
+    ImmatureCoinbase(String, i32),
+    InputsBelowOutputs(String),
+    BadAmount(String),
+    BadCoinbaseValue(Amount, Amount),
+    NonFinalTransaction(String),
+    RelativeLockNotMet(String, i32),
+    DuplicateInput(String, i32),
+    BlockTooLarge(String, usize, usize),
+    TooManyTransactions(String, usize, usize),
+}
+
+impl Display for BlockValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockValidationError::NoTransactions(hash) => {
+                write!(f, "block {} has no transactions", hash)
+            }
+            BlockValidationError::HashMismatch(hash, calculated) => write!(
+                f,
+                "block hash {} does not match its content {}",
+                hash, calculated
+            ),
+            BlockValidationError::BadMerkleRoot(hash) => write!(
+                f,
+                "block {} merkle root does not match its transactions",
+                hash
+            ),
+            BlockValidationError::BadDifficulty(hash, found, expected) => write!(
+                f,
+                "block {} has bits {:08x}, expected {:08x}",
+                hash, found, expected
+            ),
+            BlockValidationError::InsufficientWork(hash) => {
+                write!(f, "block hash {} does not meet the difficulty target", hash)
+            }
+            BlockValidationError::UnknownParent(hash) => {
+                write!(f, "parent block {} is unknown", hash)
+            }
+            BlockValidationError::BadHeight(height, parent) => write!(
+                f,
+                "block height {} does not follow parent height {}",
+                height, parent
+            ),
+            BlockValidationError::TimestampTooOld(time, median) => write!(
+                f,
+                "block timestamp {} is before median time past {}",
+                time, median
+            ),
+            BlockValidationError::TimestampTooNew(time) => {
+                write!(f, "block timestamp {} is too far in the future", time)
+            }
+            BlockValidationError::BadCoinbaseCount(count) => write!(
+                f,
+                "block must contain exactly one coinbase, found {}",
+                count
+            ),
+            BlockValidationError::InvalidTransaction(txid) => {
+                write!(f, "transaction {} failed verification", txid)
+            }
+            BlockValidationError::BadTransactionId(txid, calculated) => write!(
+                f,
+                "transaction id {} does not match its content {}",
+                txid, calculated
+            ),
+            BlockValidationError::MissingOutput(txid, vout) => {
+                write!(f, "output {}:{} is not unspent", txid, vout)
+            }
+            BlockValidationError::DuplicateOutput(txid, vout) => {
+                write!(f, "output {}:{} already exists and is unspent", txid, vout)
+            }
+            BlockValidationError::ImmatureCoinbase(txid, height) => write!(
+                f,
+                "coinbase {} created at height {} is not mature yet",
+                txid, height
+            ),
+            BlockValidationError::InputsBelowOutputs(txid) => write!(
+                f,
+                "transaction {} spends more than its inputs are worth",
+                txid
+            ),
+            BlockValidationError::BadAmount(txid) => {
+                write!(f, "transaction {} moves more than the money supply", txid)
+            }
+            BlockValidationError::BadCoinbaseValue(value, allowed) => write!(
+                f,
+                "coinbase pays {}, subsidy and fees only allow {}",
+                value, allowed
+            ),
+            BlockValidationError::NonFinalTransaction(txid) => {
+                write!(f, "transaction {} is locked until later", txid)
+            }
+            BlockValidationError::RelativeLockNotMet(txid, vout) => write!(
+                f,
+                "output {}:{} is spent before its relative lock expired",
+                txid, vout
+            ),
+            BlockValidationError::DuplicateInput(txid, vout) => {
+                write!(f, "output {}:{} is spent twice", txid, vout)
+            }
+            BlockValidationError::BlockTooLarge(hash, size, max) => write!(
+                f,
+                "block {} has {} bytes, at most {} are allowed",
+                hash, size, max
+            ),
+            BlockValidationError::TooManyTransactions(hash, count, max) => write!(
+                f,
+                "block {} has {} transactions, at most {} are allowed",
+                hash, count, max
+            ),
+        }
+    }
+}
+
+impl Fail for BlockValidationError {}
+
+/// fails with `DuplicateInput` if two inputs of `transactions` spend the same output
+pub fn check_duplicate_inputs(transactions: &[Transaction]) -> Result<(), BlockValidationError> {
+    let mut spent = HashSet::new();
+    for outpoint in transactions.iter().flat_map(|tx| tx.spent_outpoints()) {
+        if !spent.insert(outpoint.clone()) {
+            return Err(BlockValidationError::DuplicateInput(
+                outpoint.txid,
+                outpoint.vout as i32,
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl Blockchain {
+    /// validate_block runs the consensus checks that only need the block and its ancestors
+    /// errors caused by the block itself are returned as `BlockValidationError`
+    pub fn validate_block(&self, block: &Block) -> Result<(), failure::Error> {
+        let hash = block.get_hash();
+        if block.get_transaction().is_empty() {
+            return Err(BlockValidationError::NoTransactions(hash).into());
+        }
+
+        // header checks
+        let calculated = block.calculate_hash();
+        if calculated != hash {
+            return Err(BlockValidationError::HashMismatch(hash, calculated).into());
+        }
+        // the merkle root commits to the ids, which have to match the content
+        for tx in block.get_transaction() {
+            let calculated = tx.hash()?;
+            if calculated != tx.id {
+                return Err(
+                    BlockValidationError::BadTransactionId(tx.id.clone(), calculated).into(),
+                );
+            }
+        }
+        if block.get_header().merkle_root != block.hash_transactions()? {
+            return Err(BlockValidationError::BadMerkleRoot(hash).into());
+        }
+
+        // linkage with the parent
+        let parent = match self.store.get_block(&block.get_prev_block_hash())? {
+            Some(parent) => parent,
+            None => {
+                return Err(BlockValidationError::UnknownParent(block.get_prev_block_hash()).into())
+            }
+        };
+
+        let expected_bits = self.next_bits(&parent)?;
+        if block.get_bits() != expected_bits {
+            return Err(
+                BlockValidationError::BadDifficulty(hash, block.get_bits(), expected_bits).into(),
+            );
+        }
+        if !hash_meets_target(&hash, block.get_bits()) {
+            return Err(BlockValidationError::InsufficientWork(hash).into());
+        }
+
+        if block.get_height() != parent.get_height() + 1 {
+            return Err(
+                BlockValidationError::BadHeight(block.get_height(), parent.get_height()).into(),
+            );
+        }
+
+        let median_time = self.median_time_past(&parent)?;
+        if block.get_timestamp() < median_time {
+            return Err(
+                BlockValidationError::TimestampTooOld(block.get_timestamp(), median_time).into(),
+            );
+        }
+        let now = now_ms()?;
+        if block.get_timestamp() > now + MAX_FUTURE_BLOCK_TIME_MS {
+            return Err(BlockValidationError::TimestampTooNew(block.get_timestamp()).into());
+        }
+
+        // body checks
+        let params = self.config().params();
+        let count = block.get_transaction().len();
+        if count > params.max_block_txs {
+            return Err(BlockValidationError::TooManyTransactions(
+                hash,
+                count,
+                params.max_block_txs,
+            )
+            .into());
+        }
+        let size = block.size()?;
+        if size > params.max_block_size {
+            return Err(
+                BlockValidationError::BlockTooLarge(hash, size, params.max_block_size).into(),
+            );
+        }
+        let coinbase_count = block
+            .get_transaction()
+            .iter()
+            .filter(|tx| tx.is_coinbase())
+            .count();
+        if coinbase_count != 1 {
+            return Err(BlockValidationError::BadCoinbaseCount(coinbase_count).into());
+        }
+        check_duplicate_inputs(block.get_transaction())?;
+        Ok(())
+    }
+
+    /// verifies every transaction of a block that is about to be connected
+    /// previous transactions are looked up in `branch_txs`, the transactions of the
+    /// branch connected so far, and in the active chain up to `fork_height`
+    /// the transactions of `block` are added to `branch_txs` once it is valid
+    pub fn validate_block_transactions(
+        &self,
+        block: &Block,
+        fork_height: i32,
+        branch_txs: &mut HashMap<String, Transaction>,
+    ) -> Result<(), failure::Error> {
+        // lock times are compared with the median time past of the parent,
+        // which the miner can't move forward like the block timestamp
+        let parent = self.get_block(&block.get_prev_block_hash())?;
+        let median_time = self.median_time_past(&parent)?;
+        for tx in block.get_transaction() {
+            if !tx.is_final(block.get_height(), median_time) {
+                return Err(BlockValidationError::NonFinalTransaction(tx.id.clone()).into());
+            }
+            // transactions may spend outputs created earlier in the same block
+            if !self.verify_block_transaction(tx, fork_height, branch_txs) {
+                return Err(BlockValidationError::InvalidTransaction(tx.id.clone()).into());
+            }
+            branch_txs.insert(tx.id.clone(), tx.clone());
+        }
+        Ok(())
+    }
+
+    /// next_bits returns the difficulty a child of `parent` has to be mined at
+    /// every `retarget_interval` blocks the target is scaled by the ratio between
+    /// the time the last interval took and the time it should have taken,
+    /// the adjustment is limited to a factor of four in either direction
+    pub fn next_bits(&self, parent: &Block) -> Result<u32, failure::Error> {
+        let params = self.config().params();
+        let height = parent.get_height() + 1;
+        if height % params.retarget_interval != 0 {
+            return Ok(parent.get_bits());
+        }
+
+        // only headers are needed to walk back to the start of the interval
+        let mut first = parent.get_header().clone();
+        for _ in 1..params.retarget_interval {
+            first = self
+                .store
+                .get_header(&first.prev_hash_hex())?
+                .ok_or_else(|| {
+                    failure::format_err!("missing ancestor of block {}", parent.get_hash())
+                })?;
+        }
+
+        let expected = params.target_block_time_ms * params.retarget_interval as u64;
+        let actual = parent.get_timestamp().saturating_sub(first.timestamp);
+        let actual = actual.clamp(expected / 4, expected * 4);
+
+        let limit = Target::from_compact(params.pow_limit_bits);
+        let target = Target::from_compact(parent.get_bits()).scale(actual, expected);
+        Ok(target.min(limit).to_compact())
+    }
+
+    /// median timestamp of the last `MEDIAN_TIME_SPAN` blocks ending at `tip`
+    pub(crate) fn median_time_past(&self, tip: &Block) -> Result<u64, failure::Error> {
+        let mut timestamps = vec![tip.get_timestamp()];
+        let mut prev_hash = tip.get_header().prev_hash_hex();
+        while timestamps.len() < MEDIAN_TIME_SPAN {
+            match self.store.get_header(&prev_hash)? {
+                Some(header) => {
+                    timestamps.push(header.timestamp);
+                    prev_hash = header.prev_hash_hex();
+                }
+                None => break,
+            }
+        }
+        timestamps.sort_unstable();
+        Ok(timestamps[timestamps.len() / 2])
+    }
+
+    /// any lookup failure (such as a missing previous transaction) makes the transaction invalid
+    fn verify_block_transaction(
+        &self,
+        tx: &Transaction,
+        fork_height: i32,
+        branch_txs: &HashMap<String, Transaction>,
+    ) -> bool {
+        if tx.is_coinbase() {
+            return tx.output_value().is_ok();
+        }
+        let mut prev_txs = HashMap::new();
+        for vin in &tx.input {
+            let prev_tx = match branch_txs.get(&vin.txid) {
+                Some(prev_tx) => prev_tx.clone(),
+                None => match self.find_transaction_before(&vin.txid, fork_height) {
+                    Ok(prev_tx) => prev_tx,
+                    Err(_) => return false,
+                },
+            };
+            if vin.vout < 0 || vin.vout as usize >= prev_tx.output.len() {
+                return false;
+            }
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+        }
+        tx.verify(prev_txs).unwrap_or(false)
+    }
+}
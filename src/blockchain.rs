@@ -1,79 +1,162 @@
-use bincode::{deserialize, serialize};
 use failure::format_err;
 use log::info;
 use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
 
+use crate::amount::Amount;
 use crate::block::Block;
-use crate::blockchain_itr::BlockchainIter;
+use crate::block_store::{BlockStore, MemoryBlockStore, SledBlockStore, StoreBatch, Tree};
+use crate::block_validation::check_duplicate_inputs;
+use crate::blockchain_itr::{BlockchainIter, HeightIter};
+use crate::config::ChainConfig;
+use crate::miner::Miner;
 use crate::transaction::Transaction;
+use crate::utils::now_ms;
+use crate::utxo_set::{BlockUndo, OutPoint, UtxoOverlay};
+use crate::utxo_view::UtxoView;
 
 const GENESIS_COINBASE_DATA: &str = "Some data for genesis block";
 
+/// ChainUpdate describes how the active chain moved after a block was added
+/// disconnected blocks are ordered from the old tip down to the fork point,
+/// connected blocks from the fork point up to the new tip
+#[derive(Debug, Default)]
+pub struct ChainUpdate {
+    pub disconnected: Vec<Block>,
+    pub connected: Vec<Block>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Blockchain {
     curr_hash: String,
-    pub(crate) db: sled::Db,
+    pub(crate) store: Arc<dyn BlockStore>,
+    config: ChainConfig,
 }
 
 impl Blockchain {
     /// the last block hash is stored in DB
     /// after this value is accessed
     /// we can traverse through all subsequent blocks via hash_prev_block
-    pub fn new() -> Result<Blockchain, failure::Error> {
+    pub fn new(config: &ChainConfig) -> Result<Blockchain, failure::Error> {
         info!("open blockchain");
+        let store = SledBlockStore::open(config.blocks_path())?;
+        Blockchain::from_store(Arc::new(store), config)
+    }
 
-        let db = sled::open("data/blocks")?;
-        let hash = db
-            .get("block_head_hash")?
+    /// opens a chain that was previously created in the given store
+    pub fn from_store(
+        store: Arc<dyn BlockStore>,
+        config: &ChainConfig,
+    ) -> Result<Blockchain, failure::Error> {
+        let last_block_hash = store
+            .get_head()?
             .expect("Create chain should have created one genesis block");
         info!("Found block database");
-        let last_block_hash = String::from_utf8(hash.to_vec())?;
         Ok(Blockchain {
-            curr_hash: last_block_hash.clone(),
-            db,
+            curr_hash: last_block_hash,
+            store,
+            config: config.clone(),
         })
     }
 
     /// creates the genesis block with coinbase transaction
     /// persists in database
-    pub fn create_blockchain(address: String) -> Result<Blockchain, failure::Error> {
-        if let Err(_e) = std::fs::remove_dir_all("data/blocks") {
+    pub fn create_blockchain(
+        address: String,
+        config: &ChainConfig,
+    ) -> Result<Blockchain, failure::Error> {
+        if let Err(_e) = std::fs::remove_dir_all(config.blocks_path()) {
             info!("bloks not exist to delete")
         }
+        let store = SledBlockStore::open(config.blocks_path())?;
+        Blockchain::create_with_store(address, Arc::new(store), config)
+    }
 
-        let db = sled::open("data/blocks")?;
+    /// same as `create_blockchain` but writes the genesis block into the given store
+    pub fn create_with_store(
+        address: String,
+        store: Arc<dyn BlockStore>,
+        config: &ChainConfig,
+    ) -> Result<Blockchain, failure::Error> {
+        let coinbase_transaction = Transaction::new_coinbase(
+            address,
+            String::from(GENESIS_COINBASE_DATA),
+            config.params().subsidy(0),
+            config,
+        )?;
+        let genesis =
+            Block::new_genesis_block(coinbase_transaction, config.params().pow_limit_bits)?;
+        let genesis = Miner::default()
+            .mine(genesis)?
+            .block
+            .ok_or_else(|| format_err!("mining genesis block was cancelled"))?;
 
-        let coinbase_transaction =
-            Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-        let genesis: Block = Block::new_genesis_block(coinbase_transaction);
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-        db.insert("block_head_hash", genesis.get_hash().as_bytes())?;
+        let mut batch = StoreBatch::new();
+        batch.put_block(&genesis)?;
+        batch.set_head(&genesis.get_hash());
+        batch.index_block(&genesis)?;
+        batch.set_chain_work(&genesis.get_hash(), genesis.work());
+        let mut utxos = UtxoOverlay::new(store.as_ref());
+        let undo = utxos.connect_block(&genesis, config.params())?;
+        batch.set_undo(&genesis.get_hash(), &undo)?;
+        utxos.write_to(&mut batch)?;
+        store.write(batch)?;
+        store.flush()?;
 
-        let bc = Blockchain {
+        Ok(Blockchain {
             curr_hash: genesis.get_hash(),
-            db,
-        };
-        bc.db.flush()?;
-        Ok(bc)
+            store,
+            config: config.clone(),
+        })
     }
 
-    /// serializes and inserts block into database
-    /// updates the head_hash to point ot this latest block
-    // pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<(Block), failure::Error> {
-    //     let new_block = Block::new(transactions, self.curr_hash.clone(), 0)?;
-    //     self.db.insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
-    //     self.db.insert("block_head_hash", new_block.get_hash().as_bytes())?;
-    //     self.curr_hash = new_block.get_hash();
-    //     Ok((new_block))
-    // }
+    pub fn config(&self) -> &ChainConfig {
+        &self.config
+    }
+
+    /// iterates from the tip back to genesis
+    pub fn iter(&self) -> BlockchainIter<'_> {
+        self.iter_from(&self.curr_hash)
+    }
 
-    pub fn iter(&self) -> BlockchainIter {
+    /// iterates from the block `hash` back to genesis
+    pub fn iter_from(&self, hash: &str) -> BlockchainIter<'_> {
         BlockchainIter {
-            current_hash: self.curr_hash.clone(),
-            bc: &self,
+            current_hash: hash.to_string(),
+            bc: self,
         }
     }
 
+    /// iterates the active chain from genesis up to the tip
+    pub fn iter_forward(&self) -> Result<HeightIter<'_>, failure::Error> {
+        self.iter_range(..)
+    }
+
+    /// iterates the blocks of the active chain whose height is in `range`,
+    /// heights above the tip are ignored
+    pub fn iter_range<R: RangeBounds<i32>>(
+        &self,
+        range: R,
+    ) -> Result<HeightIter<'_>, failure::Error> {
+        let low = match range.start_bound() {
+            Bound::Included(h) => *h,
+            Bound::Excluded(h) => h + 1,
+            Bound::Unbounded => 0,
+        };
+        let best = self.get_best_height()?;
+        let high = match range.end_bound() {
+            Bound::Included(h) => (*h).min(best),
+            Bound::Excluded(h) => (h - 1).min(best),
+            Bound::Unbounded => best,
+        };
+        Ok(HeightIter {
+            low: low.max(0),
+            high,
+            bc: self,
+        })
+    }
+
     /// invoked when sending transaction
     /// gets previous transactions that are present in input of a transaction
     /// suppose some person get a, b, c transaction accumulating 90 token
@@ -89,7 +172,18 @@ impl Blockchain {
         Ok(())
     }
 
-    /// verify_transaction verifies transaction input signatures
+    /// adds the signature of `private_key` to the multisig inputs of `tx`,
+    /// returns how many signatures the least signed of them has
+    pub fn sign_multisig_transaction(
+        &self,
+        tx: &mut Transaction,
+        private_key: &[u8],
+    ) -> Result<usize, failure::Error> {
+        let prev_txs = self.get_prev_txs(tx)?;
+        tx.sign_multisig(private_key, prev_txs)
+    }
+
+    /// verify_transaction verifies transaction input scripts
     pub fn verify_transaction(&self, tx: &mut Transaction) -> Result<bool, failure::Error> {
         let prev_txs = self.get_prev_txs(tx)?;
         tx.verify(prev_txs)
@@ -107,151 +201,1319 @@ impl Blockchain {
         Ok(prev_txs)
     }
 
-    /// traverses entire blockchain to get specific transaction
+    /// looks up a transaction of the active chain through the txid index
     pub fn find_transaction(&self, id: &str) -> Result<Transaction, failure::Error> {
-        for b in self.iter() {
-            for tx in b.get_transaction() {
-                if tx.id == id {
-                    return Ok(tx.clone());
-                }
-            }
-        }
-        // let tx = self.iter()
-        //     .flat_map(|b| b.get_transaction().into_iter())
-        //     .find(|tx| tx.id == id)
-        //     .cloned()
-        //     .ok_or_else(|| format_err!("Transaction not found"));
-
-        Err(format_err!("Transaction is not found"))
+        self.find_transaction_before(id, i32::MAX)
     }
 
     pub fn get_block(&self, block_hash: &str) -> Result<Block, failure::Error> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block = deserialize(&data.to_vec())?;
-        Ok(block)
+        self.store
+            .get_block(block_hash)?
+            .ok_or_else(|| format_err!("Block is not found"))
+    }
+
+    /// the last block of the active chain
+    pub fn get_tip(&self) -> Result<Block, failure::Error> {
+        self.get_block(&self.curr_hash)
     }
 
     pub fn get_best_height(&self) -> Result<i32, failure::Error> {
-        let lasthash = if let Some(h) = self.db.get("LAST")? {
+        let lasthash = if let Some(h) = self.store.get_head()? {
             h
         } else {
             return Ok(-1);
         };
-        let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = deserialize(&last_data.to_vec())?;
-        Ok(last_block.get_height())
+        Ok(self.get_block(&lasthash)?.get_height())
     }
 
-    pub fn get_block_hashs(&self) -> Vec<String> {
+    /// hashes of the active chain from the tip down to genesis, read from the height index
+    pub fn get_block_hashs(&self) -> Result<Vec<String>, failure::Error> {
         let mut list = Vec::new();
-        for b in self.iter() {
-            list.push(b.get_hash());
+        for height in (0..=self.get_best_height()?).rev() {
+            let hash = self
+                .store
+                .get_hash_at_height(height)?
+                .ok_or_else(|| format_err!("no block at height {} of the active chain", height))?;
+            list.push(hash);
         }
-        list
+        Ok(list)
     }
 
+    /// checks the scripts of `tx` and that it may go into the next block,
+    /// its inputs have to spend distinct outputs that are still unspent
     pub fn verify_transacton(&self, tx: &Transaction) -> Result<bool, failure::Error> {
-        if tx.is_coinbase() {
-            return Ok(true);
+        self.verify_transaction_in(tx, &UtxoView::new(self))
+    }
+
+    /// like `verify_transacton` but the outputs `tx` spends are looked up in `view`,
+    /// so they may belong to unconfirmed transactions
+    pub fn verify_transaction_in(
+        &self,
+        tx: &Transaction,
+        view: &UtxoView,
+    ) -> Result<bool, failure::Error> {
+        if !tx.is_coinbase() && !self.is_final_in(tx, view)? {
+            return Ok(false);
         }
-        let prev_TXs = self.get_prev_TXs(tx)?;
-        tx.verify(prev_TXs)
+        self.verify_inputs_in(tx, view)
     }
 
-    fn get_prev_TXs(
+    /// like `verify_transaction_in` without the lock time checks, for transactions
+    /// that are kept until they are final
+    pub fn verify_inputs_in(
         &self,
         tx: &Transaction,
-    ) -> Result<HashMap<String, Transaction>, failure::Error> {
-        let mut prev_TXs = HashMap::new();
+        view: &UtxoView,
+    ) -> Result<bool, failure::Error> {
+        if tx.is_coinbase() {
+            return Ok(true);
+        }
+        if check_duplicate_inputs(std::slice::from_ref(tx)).is_err() {
+            return Ok(false);
+        }
+        for outpoint in tx.spent_outpoints() {
+            if view.get_utxo(&outpoint)?.is_none() {
+                return Ok(false);
+            }
+        }
+        tx.verify(view.prev_txs(tx)?)
+    }
+
+    /// whether `tx` may go into the block on top of the tip, its lock time has to be
+    /// before the tip's median time past and the outputs it spends have to be as old
+    /// as its inputs ask
+    pub fn is_final_transaction(&self, tx: &Transaction) -> Result<bool, failure::Error> {
+        self.is_final_in(tx, &UtxoView::new(self))
+    }
+
+    /// like `is_final_transaction` with the spent outputs looked up in `view`,
+    /// outputs of unconfirmed transactions don't satisfy any relative lock
+    pub fn is_final_in(&self, tx: &Transaction, view: &UtxoView) -> Result<bool, failure::Error> {
+        let height = self.get_best_height()? + 1;
+        // like block validation, and the next block can't be older than this
+        // so relative locks that passed stay passed
+        let time = self.median_time_past(&self.get_tip()?)?;
+        if !tx.is_final(height, time) {
+            return Ok(false);
+        }
         for vin in &tx.input {
-            let prev_TX = self.find_transacton(&vin.txid)?;
-            prev_TXs.insert(prev_TX.id.clone(), prev_TX);
+            let lock = match vin.relative_lock() {
+                Some(lock) => lock,
+                None => continue,
+            };
+            match view.get_utxo(&OutPoint::new(&vin.txid, vin.vout as u32))? {
+                Some(entry) if entry.is_unlocked(lock, height, time) => {}
+                _ => return Ok(false),
+            }
         }
-        Ok(prev_TXs)
+        Ok(true)
     }
 
-    pub fn find_transacton(&self, id: &str) -> Result<Transaction, failure::Error> {
-        for b in self.iter() {
-            for tx in b.get_transaction() {
-                if tx.id == id {
-                    return Ok(tx.clone());
-                }
+    /// like `find_transaction` but ignores transactions above `height`,
+    /// which may be disconnected by a reorganization
+    pub fn find_transaction_before(
+        &self,
+        id: &str,
+        height: i32,
+    ) -> Result<Transaction, failure::Error> {
+        let (block_hash, index) = self
+            .store
+            .get_tx_location(id)?
+            .ok_or_else(|| format_err!("Transaction is not found"))?;
+        let block = self.get_block(&block_hash)?;
+        if block.get_height() > height {
+            return Err(format_err!("Transaction is not found"));
+        }
+        block
+            .get_transaction()
+            .get(index as usize)
+            .filter(|tx| tx.id == id)
+            .cloned()
+            .ok_or_else(|| format_err!("transaction index is corrupted for {}", id))
+    }
+
+    /// stores a block received from a peer after validating it
+    /// blocks that do not extend the chain with the most cumulative work are kept
+    /// as a side branch, once a branch has more work the chain is reorganized onto it
+    pub fn add_block(&mut self, block: Block) -> Result<ChainUpdate, failure::Error> {
+        if self.store.contains_block(&block.get_hash())? {
+            return Ok(ChainUpdate::default());
+        }
+        self.validate_block(&block)?;
+
+        let work = self.get_chain_work(&block.get_prev_block_hash())? + block.work();
+        if work <= self.get_chain_work(&self.curr_hash)? {
+            info!("store side branch block {}", block.get_hash());
+            let mut batch = StoreBatch::new();
+            batch.put_block(&block)?;
+            batch.set_chain_work(&block.get_hash(), work);
+            self.store.write(batch)?;
+            self.store.flush()?;
+            return Ok(ChainUpdate::default());
+        }
+
+        let update = self.reorganize(&block, work)?;
+        self.store.flush()?;
+        Ok(update)
+    }
+
+    /// makes `block` the new tip
+    /// walks its branch back to the fork point with the active chain,
+    /// verifies the branch transactions block by block and then switches
+    /// the head, the indexes and the UTXO set in one batch
+    fn reorganize(&mut self, block: &Block, work: u128) -> Result<ChainUpdate, failure::Error> {
+        let mut connected = vec![block.clone()];
+        let mut fork_hash = block.get_prev_block_hash();
+        loop {
+            let b = self.get_block(&fork_hash)?;
+            if self.store.get_hash_at_height(b.get_height())? == Some(b.get_hash()) {
+                break;
             }
+            fork_hash = b.get_prev_block_hash();
+            connected.push(b);
+        }
+        connected.reverse();
+
+        let mut disconnected = Vec::new();
+        let mut hash = self.curr_hash.clone();
+        while hash != fork_hash {
+            let b = self.get_block(&hash)?;
+            hash = b.get_prev_block_hash();
+            disconnected.push(b);
+        }
+
+        let old_height = self.get_block(&self.curr_hash)?.get_height();
+        let fork_height = self.get_block(&fork_hash)?.get_height();
+        let mut branch_txs = HashMap::new();
+        for b in &connected {
+            self.validate_block_transactions(b, fork_height, &mut branch_txs)?;
+        }
+
+        if !disconnected.is_empty() {
+            info!(
+                "reorganize chain: disconnect {} blocks, connect {} blocks",
+                disconnected.len(),
+                connected.len()
+            );
+        }
+        let mut batch = StoreBatch::new();
+        batch.put_block(block)?;
+        batch.set_chain_work(&block.get_hash(), work);
+        batch.set_head(&block.get_hash());
+        for b in &disconnected {
+            batch.unindex_transactions(b);
+        }
+        for b in &connected {
+            batch.index_block(b)?;
+        }
+        for height in block.get_height() + 1..=old_height {
+            batch.remove_hash_at_height(height);
+        }
+
+        let mut utxos = UtxoOverlay::new(self.store.as_ref());
+        for b in &disconnected {
+            let undo = self
+                .store
+                .get_undo(&b.get_hash())?
+                .ok_or_else(|| format_err!("undo data of block {} is missing", b.get_hash()))?;
+            utxos.disconnect_block(undo)?;
+            batch.remove_undo(&b.get_hash());
         }
-        Err(format_err!("Transaction is not found"))
+        for b in &connected {
+            let undo = utxos.connect_block(b, self.config.params())?;
+            batch.set_undo(&b.get_hash(), &undo)?;
+        }
+        utxos.write_to(&mut batch)?;
+        self.store.write(batch)?;
+        self.curr_hash = block.get_hash();
+
+        Ok(ChainUpdate {
+            disconnected,
+            connected,
+        })
     }
 
-    pub fn add_block(&mut self, block: Block) -> Result<(), failure::Error> {
-        let data = serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
-            return Ok(());
+    /// rebuilds the height and txid indexes by walking the chain from the head
+    /// used to repair the indexes or to add them to an older database
+    pub fn rebuild_indexes(&self) -> Result<usize, failure::Error> {
+        let mut batch = StoreBatch::new();
+        for tree in [Tree::Heights, Tree::Txs] {
+            batch.clear(self.store.as_ref(), tree)?;
         }
-        self.db.insert(block.get_hash(), data)?;
+        let mut count = 0;
+        for b in self.iter() {
+            batch.index_block(&b?)?;
+            count += 1;
+        }
+        self.store.write(batch)?;
+        self.store.flush()?;
+        Ok(count)
+    }
 
-        let lastheight = self.get_best_height()?;
-        if block.get_height() > lastheight {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.curr_hash = block.get_hash();
-            self.db.flush()?;
+    /// rebuilds the UTXO set and the undo data by replaying the active chain from genesis
+    pub fn rebuild_utxos(&self) -> Result<(), failure::Error> {
+        let mut batch = StoreBatch::new();
+        for tree in [Tree::Utxos, Tree::AddressUtxos, Tree::Undo] {
+            batch.clear(self.store.as_ref(), tree)?;
+        }
+        // the old set is cleared by the same batch, so the replay starts from nothing
+        let empty = MemoryBlockStore::new();
+        let mut utxos = UtxoOverlay::new(&empty);
+        for b in self.iter_forward()? {
+            let b = b?;
+            let undo = utxos.connect_block(&b, self.config.params())?;
+            batch.set_undo(&b.get_hash(), &undo)?;
         }
+        utxos.write_to(&mut batch)?;
+        self.store.write(batch)?;
+        self.store.flush()?;
         Ok(())
     }
 
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block, failure::Error> {
-        info!("mine a new block");
+    fn get_chain_work(&self, hash: &str) -> Result<u128, failure::Error> {
+        self.store
+            .get_chain_work(hash)?
+            .ok_or_else(|| format_err!("chain work of block {} is missing", hash))
+    }
 
+    /// value the coinbase of a block on top of the tip holding `transactions` may claim,
+    /// the subsidy of the next height plus the fees the transactions pay
+    pub fn block_reward(&self, transactions: &[Transaction]) -> Result<Amount, failure::Error> {
+        let height = self.get_best_height()? + 1;
+        let time = now_ms()?;
+        let params = self.config.params();
+        let mut utxos = UtxoOverlay::new(self.store.as_ref());
+        let mut undo = BlockUndo::default();
+        let mut reward = params.subsidy(height);
+        for tx in transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            let fee = utxos.connect_transaction(tx, height, time, params, &mut undo)?;
+            reward = reward
+                .checked_add(fee)
+                .ok_or_else(|| format_err!("block reward is above the money supply"))?;
+        }
+        Ok(reward)
+    }
+
+    /// verifies the transactions and builds an unmined block on top of the tip
+    pub fn prepare_block(&self, transactions: Vec<Transaction>) -> Result<Block, failure::Error> {
+        check_duplicate_inputs(&transactions)?;
+        // a transaction may spend outputs of the ones before it in the block
+        let mut view = UtxoView::new(self);
         for tx in &transactions {
-            if !self.verify_transacton(tx)? {
+            if !self.verify_transaction_in(tx, &view)? {
                 return Err(format_err!("ERROR: Invalid transaction"));
             }
+            view.add_pending(tx);
         }
 
-        let lasthash = self.db.get("LAST")?.unwrap();
-
-        let newblock = Block::new(
+        let tip = self.get_tip()?;
+        Block::new(
             transactions,
-            String::from_utf8(lasthash.to_vec())?,
-            self.get_best_height()? + 1,
-        )?;
-        self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
-        self.db.insert("LAST", newblock.get_hash().as_bytes())?;
-        self.db.flush()?;
+            tip.get_hash(),
+            tip.get_height() + 1,
+            self.next_bits(&tip)?,
+        )
+    }
 
-        self.curr_hash = newblock.get_hash();
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block, failure::Error> {
+        info!("mine a new block");
+
+        let newblock = self.prepare_block(transactions)?;
+        let newblock = Miner::default()
+            .mine(newblock)?
+            .block
+            .ok_or_else(|| format_err!("mining was cancelled"))?;
+        let work = self.get_chain_work(&self.curr_hash)? + newblock.work();
+        self.reorganize(&newblock, work)?;
+        self.store.flush()?;
         Ok(newblock)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::block_validation::BlockValidationError;
+    use crate::coin_selection::LargestFirst;
+    use crate::config::ChainParams;
+    use crate::mergetx::verify_merkle_proof;
+    use crate::pow::Target;
+    use crate::r#const::SEQUENCE_FINAL;
+    use crate::script::Script;
+    use crate::test_util::{
+        coinbase, coins, fork_from_genesis, spend_output, test_params, TestChain,
+    };
+    use crate::transaction::Fee;
+    use crate::transaction_builder::TransactionBuilder;
+    use crate::txs::{RelativeLock, TXOutput};
+    use crate::unspent_tx_util::UnspentTXUtil;
+    use crate::utxo_set::OutPoint;
+    use crate::wallet_chain::WalletChain;
+    use bitcoincash_addr::Address;
+
+    /// mines a block on the tip with the transactions as given, skipping the checks
+    /// of `prepare_block`, and returns why `add_block` rejects it
+    fn add_rejected(bc: &mut Blockchain, transactions: Vec<Transaction>) -> failure::Error {
+        let tip = bc.get_block(&bc.curr_hash).unwrap();
+        let bits = bc.next_bits(&tip).unwrap();
+        let block = Block::new(transactions, tip.get_hash(), tip.get_height() + 1, bits).unwrap();
+        let block = Miner::default().mine(block).unwrap().block.unwrap();
+        bc.add_block(block).unwrap_err()
+    }
 
     #[test]
     fn test_blockchain_in_memory() {
-        // let mut b = Blockchain::new().unwrap();
-        // b.add_block("block 0".to_string()).ok();
-        // b.add_block("block 1".to_string()).ok();
-        // b.add_block("block 2".to_string()).ok();
-        // dbg!(b);
+        let chain = TestChain::new(1);
+        let config = chain.config.clone();
+        let address = chain.wallets[0].clone();
+
+        let mut b = chain.bc.clone();
+        b.mine_block(vec![coinbase(&address, &config)]).unwrap();
+        b.mine_block(vec![coinbase(&address, &config)]).unwrap();
+
+        assert_eq!(b.get_best_height().unwrap(), 2);
+        assert_eq!(b.iter().count(), 3);
+        assert!(!config.blocks_path().exists());
     }
 
     #[test]
     fn test_blockchain_db() {
-        // let mut b = Blockchain::new().unwrap();
-        // b.add_block("data 1".to_string());
-        // b.add_block("data 2".to_string());
-        // b.add_block("data 3".to_string());
+        let chain = TestChain::new(1);
+        let config = chain.config.clone();
+        let address = chain.wallets[0].clone();
+
+        let hashes = {
+            let mut b = Blockchain::create_blockchain(address.clone(), &config).unwrap();
+            b.mine_block(vec![coinbase(&address, &config)]).unwrap();
+            b.mine_block(vec![coinbase(&address, &config)]).unwrap();
+            b.get_block_hashs().unwrap()
+        };
 
-        // for item in b.iter() {
-        //     println!("item {:?}",item)
-        // }
+        let b = Blockchain::new(&config).unwrap();
+        assert_eq!(b.get_block_hashs().unwrap(), hashes);
+        for (height, hash) in hashes.iter().rev().enumerate() {
+            let indexed = b.store.get_hash_at_height(height as i32).unwrap();
+            assert_eq!(indexed.as_ref(), Some(hash));
+        }
     }
 
     #[test]
     fn add_block() {
-        // this test case should fail as pub key hash address is hardcoded, opt for something from wallet in future
-        // let mut bc = Blockchain::new().unwrap();
-        // let tx = Transaction::new( "34KTu4aiqTaJ1vdYzHS3xGXL1eHkAuXred", "35gt2cJbbmLFLqWtEkCU5yMrECUoccNGy4",10, &bc).unwrap();
-        // bc.add_block(vec![tx]).unwrap();
-        // println!("success!");
+        let chain = TestChain::new(1);
+        let config = chain.config.clone();
+        let address = chain.wallets[0].clone();
+
+        let mut miner = chain.bc.clone();
+        let mut peer = fork_from_genesis(&miner, &config);
+        let block = miner.mine_block(vec![coinbase(&address, &config)]).unwrap();
+
+        assert_eq!(peer.get_best_height().unwrap(), 0);
+        peer.add_block(block.clone()).unwrap();
+        peer.add_block(block.clone()).unwrap();
+        assert_eq!(peer.get_best_height().unwrap(), 1);
+        assert_eq!(
+            peer.get_block_hashs().unwrap(),
+            miner.get_block_hashs().unwrap()
+        );
+    }
+
+    #[test]
+    fn add_block_rejects_unknown_parent() {
+        let chain = TestChain::new(1);
+        let config = chain.config.clone();
+        let address = chain.wallets[0].clone();
+
+        let mut other = chain.bc.clone();
+        let block = other.mine_block(vec![coinbase(&address, &config)]).unwrap();
+
+        let mut fresh = TestChain::new(1);
+        let bc = &mut fresh.bc;
+        let err = bc.add_block(block).unwrap_err();
+        match err.downcast_ref::<BlockValidationError>() {
+            Some(BlockValidationError::UnknownParent(_)) => {}
+            _ => panic!("unexpected error {}", err),
+        }
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+    }
+
+    #[test]
+    fn add_block_rejects_forged_transactions() {
+        let mut chain = TestChain::new(2);
+        let (alice, bob) = (chain.wallets[0].clone(), chain.wallets[1].clone());
+        let bc = &mut chain.bc;
+        let tip = bc.get_tip().unwrap();
+        let bits = bc.next_bits(&tip).unwrap();
+        let block = Block::new(
+            vec![coinbase(&alice, &chain.config)],
+            tip.get_hash(),
+            1,
+            bits,
+        )
+        .unwrap();
+        let block = Miner::default().mine(block).unwrap().block.unwrap();
+
+        // a relaying peer pays the reward to bob and keeps the id and the header
+        let mut body = block.body();
+        let value = body.transactions[0].output[0].value;
+        body.transactions[0].output[0] = TXOutput::new(value, bob).unwrap();
+        let forged = Block::from_parts(block.get_header().clone(), body);
+        let err = bc.add_block(forged).unwrap_err();
+        match err.downcast_ref::<BlockValidationError>() {
+            Some(BlockValidationError::BadTransactionId(txid, _))
+                if *txid == block.get_transaction()[0].id => {}
+            _ => panic!("unexpected error {}", err),
+        }
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+        bc.add_block(block).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+    }
+
+    #[test]
+    fn add_block_rejects_duplicate_outputs() {
+        let mut chain = TestChain::new(1);
+        let alice = chain.wallets[0].clone();
+        let reward =
+            Transaction::new_coinbase(alice, "same".to_string(), coins(100), &chain.config)
+                .unwrap();
+        let bc = &mut chain.bc;
+        bc.mine_block(vec![reward.clone()]).unwrap();
+
+        // the same coinbase again would overwrite the unspent output of the first
+        let err = add_rejected(bc, vec![reward.clone()]);
+        match err.downcast_ref::<BlockValidationError>() {
+            Some(BlockValidationError::DuplicateOutput(txid, 0)) if *txid == reward.id => {}
+            _ => panic!("unexpected error {}", err),
+        }
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+        let outpoint = OutPoint::new(&reward.id, 0);
+        assert_eq!(bc.store.get_utxo(&outpoint).unwrap().unwrap().height, 1);
+    }
+
+    #[test]
+    fn add_block_reorganizes_to_most_work() {
+        let chain = TestChain::new(1);
+        let config = chain.config.clone();
+        let address = chain.wallets[0].clone();
+
+        let mut bc = chain.bc.clone();
+        let mut rival = fork_from_genesis(&bc, &config);
+        let a1_coinbase =
+            Transaction::new_coinbase(address.clone(), "a1".to_string(), coins(100), &config)
+                .unwrap();
+        let a1 = bc.mine_block(vec![a1_coinbase]).unwrap();
+        let b1 = rival.mine_block(vec![coinbase(&address, &config)]).unwrap();
+        let b2 = rival.mine_block(vec![coinbase(&address, &config)]).unwrap();
+
+        // same amount of work, the first seen block stays the tip
+        let update = bc.add_block(b1.clone()).unwrap();
+        assert!(update.connected.is_empty() && update.disconnected.is_empty());
+        assert_eq!(bc.get_block_hashs().unwrap()[0], a1.get_hash());
+
+        let update = bc.add_block(b2.clone()).unwrap();
+        let hashes = |blocks: &Vec<Block>| blocks.iter().map(|b| b.get_hash()).collect::<Vec<_>>();
+        assert_eq!(hashes(&update.disconnected), vec![a1.get_hash()]);
+        assert_eq!(
+            hashes(&update.connected),
+            vec![b1.get_hash(), b2.get_hash()]
+        );
+        assert_eq!(
+            bc.get_block_hashs().unwrap(),
+            rival.get_block_hashs().unwrap()
+        );
+        assert_eq!(bc.store.get_hash_at_height(1).unwrap(), Some(b1.get_hash()));
+        assert!(bc.find_transaction(&a1.get_transaction()[0].id).is_err());
+        assert!(bc.find_transaction(&b1.get_transaction()[0].id).is_ok());
+    }
+
+    #[test]
+    fn rebuild_indexes() {
+        let chain = TestChain::new(1);
+        let config = chain.config.clone();
+        let address = chain.wallets[0].clone();
+
+        let store = chain.bc.store.clone();
+        let mut bc = chain.bc.clone();
+        let b1 = bc.mine_block(vec![coinbase(&address, &config)]).unwrap();
+        let b2 = bc.mine_block(vec![coinbase(&address, &config)]).unwrap();
+        let hashes = bc.get_block_hashs().unwrap();
+
+        // drop the indexes as an older database would not have them
+        let mut batch = StoreBatch::new();
+        for b in [&b1, &b2] {
+            batch.remove_hash_at_height(b.get_height());
+            batch.unindex_transactions(b);
+        }
+        store.write(batch).unwrap();
+        assert!(bc.find_transaction(&b2.get_transaction()[0].id).is_err());
+
+        assert_eq!(bc.rebuild_indexes().unwrap(), 3);
+        assert_eq!(bc.get_block_hashs().unwrap(), hashes);
+        let tx = bc.find_transaction(&b2.get_transaction()[0].id).unwrap();
+        assert_eq!(tx.id, b2.get_transaction()[0].id);
+    }
+
+    #[test]
+    fn retarget_difficulty() {
+        let chain = TestChain::with_params(
+            ChainParams {
+                retarget_interval: 2,
+                ..test_params()
+            },
+            1,
+        );
+        let config = chain.config.clone();
+        let address = chain.wallets[0].clone();
+
+        let mut bc = chain.bc.clone();
+        let mut peer = fork_from_genesis(&bc, &config);
+        let b1 = bc.mine_block(vec![coinbase(&address, &config)]).unwrap();
+        let b2 = bc.mine_block(vec![coinbase(&address, &config)]).unwrap();
+        assert_eq!(b1.get_bits(), 0x207fffff);
+
+        // blocks came in far faster than the target time, the target shrinks by the maximum factor
+        let limit = Target::from_compact(0x207fffff);
+        assert_eq!(b2.get_bits(), limit.scale(1, 4).to_compact());
+
+        peer.add_block(b1).unwrap();
+        peer.add_block(b2).unwrap();
+        assert_eq!(
+            peer.get_block_hashs().unwrap(),
+            bc.get_block_hashs().unwrap()
+        );
+    }
+
+    #[test]
+    fn merkle_proof() {
+        let chain = TestChain::new(1);
+        let config = chain.config.clone();
+        let address = chain.wallets[0].clone();
+        let txs: Vec<Transaction> = (0..5)
+            .map(|i| {
+                Transaction::new_coinbase(address.clone(), format!("tx {}", i), coins(100), &config)
+                    .unwrap()
+            })
+            .collect();
+        let block = Block::new(txs.clone(), String::new(), 1, 0x207fffff).unwrap();
+        let root = block.get_header().merkle_root;
+
+        for tx in &txs {
+            let proof = block.merkle_proof(&tx.id).unwrap();
+            assert!(verify_merkle_proof(&root, &tx.id, &proof));
+            assert!(!verify_merkle_proof(&root, &txs[0].id, &proof) || tx.id == txs[0].id);
+        }
+
+        let mut proof = block.merkle_proof(&txs[2].id).unwrap();
+        proof.lemmas[0][0] ^= 1;
+        assert!(!verify_merkle_proof(&root, &txs[2].id, &proof));
+        assert!(block.merkle_proof(&"00".repeat(32)).is_err());
+
+        // the root commits to the content, not to the ids carried along
+        let mut forged = txs.clone();
+        forged[1].id = "00".repeat(32);
+        let forged = Block::new(forged, String::new(), 1, 0x207fffff).unwrap();
+        assert_eq!(forged.get_header().merkle_root, root);
+        let mut changed = txs;
+        changed[1].output[0].value = coins(50);
+        let changed = Block::new(changed, String::new(), 1, 0x207fffff).unwrap();
+        assert_ne!(changed.get_header().merkle_root, root);
+    }
+
+    #[test]
+    fn iterate_chain() {
+        let chain = TestChain::new(1);
+        let config = chain.config.clone();
+        let address = chain.wallets[0].clone();
+
+        let store = chain.bc.store.clone();
+        let mut bc = chain.bc.clone();
+        for i in 0..4 {
+            let tx =
+                Transaction::new_coinbase(address.clone(), format!("{}", i), coins(100), &config);
+            bc.mine_block(vec![tx.unwrap()]).unwrap();
+        }
+        let heights = |blocks: Vec<Result<Block, failure::Error>>| {
+            blocks
+                .into_iter()
+                .map(|b| b.unwrap().get_height())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(heights(bc.iter().collect()), vec![4, 3, 2, 1, 0]);
+        assert_eq!(
+            heights(bc.iter_forward().unwrap().collect()),
+            vec![0, 1, 2, 3, 4]
+        );
+        assert_eq!(heights(bc.iter_range(1..3).unwrap().collect()), vec![1, 2]);
+        assert_eq!(
+            heights(bc.iter_range(2..).unwrap().rev().collect()),
+            vec![4, 3, 2]
+        );
+        assert_eq!(
+            heights(bc.iter_range(3..=10).unwrap().collect()),
+            vec![3, 4]
+        );
+        let b2 = bc.store.get_hash_at_height(2).unwrap().unwrap();
+        assert_eq!(heights(bc.iter_from(&b2).collect()), vec![2, 1, 0]);
+
+        // a missing block is reported instead of ending the iteration quietly
+        let mut batch = StoreBatch::new();
+        batch.remove(Tree::Blocks, b2.as_bytes());
+        store.write(batch).unwrap();
+        let blocks: Vec<_> = bc.iter().collect();
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks[2].is_err());
+        let blocks: Vec<_> = bc.iter_forward().unwrap().collect();
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks[2].is_err());
+        // so is a hole in the height index
+        assert_eq!(bc.get_block_hashs().unwrap().len(), 5);
+        let mut batch = StoreBatch::new();
+        batch.remove_hash_at_height(1);
+        store.write(batch).unwrap();
+        assert!(bc.get_block_hashs().is_err());
+    }
+
+    #[test]
+    fn utxo_set_follows_chain() {
+        let chain = TestChain::new(2);
+        let config = chain.config.clone();
+        let (alice, bob) = (chain.wallets[0].clone(), chain.wallets[1].clone());
+        let alice_hash = Address::decode(&alice).unwrap().body;
+
+        let store = chain.bc.store.clone();
+        let mut bc = chain.bc.clone();
+        let mut rival = fork_from_genesis(&bc, &config);
+        let genesis_coinbase = bc.iter().next().unwrap().unwrap().get_transaction()[0].clone();
+        let genesis_out = OutPoint::new(&genesis_coinbase.id, 0);
+        assert_eq!(store.get_utxo(&genesis_out).unwrap().unwrap().height, 0);
+
+        let utxo = UnspentTXUtil { chain: bc.clone() };
+        let pay = Transaction::new(
+            &alice,
+            &bob,
+            coins(30),
+            Fee::default(),
+            &LargestFirst,
+            &utxo,
+        )
+        .unwrap();
+        bc.mine_block(vec![coinbase(&alice, &config), pay.clone()])
+            .unwrap();
+        assert!(store.get_utxo(&genesis_out).unwrap().is_none());
+        assert_eq!(utxo.find_UTXO(&alice_hash).unwrap().outputs.len(), 2);
+
+        // spending the first output leaves the change at its own index
+        let utxo = UnspentTXUtil { chain: bc.clone() };
+        let refund = Transaction::new(
+            &bob,
+            &alice,
+            coins(10),
+            Fee::default(),
+            &LargestFirst,
+            &utxo,
+        )
+        .unwrap();
+        bc.mine_block(vec![coinbase(&bob, &config), refund])
+            .unwrap();
+        assert!(store
+            .get_utxo(&OutPoint::new(&pay.id, 0))
+            .unwrap()
+            .is_none());
+        let change = store.get_utxo(&OutPoint::new(&pay.id, 1)).unwrap().unwrap();
+        assert_eq!((change.value, change.height), (coins(70), 1));
+
+        // replaying the chain gives the same set as the incremental updates
+        let entries = |tree| store.scan_prefix(tree, &[]).unwrap();
+        let (utxos, addresses) = (entries(Tree::Utxos), entries(Tree::AddressUtxos));
+        bc.rebuild_utxos().unwrap();
+        assert_eq!(entries(Tree::Utxos), utxos);
+        assert_eq!(entries(Tree::AddressUtxos), addresses);
+
+        // a heavier branch without the payments brings back the spent output
+        for _ in 0..3 {
+            let b = rival.mine_block(vec![coinbase(&bob, &config)]).unwrap();
+            bc.add_block(b).unwrap();
+        }
+        assert!(store.get_utxo(&genesis_out).unwrap().is_some());
+        assert!(store
+            .get_utxo(&OutPoint::new(&pay.id, 1))
+            .unwrap()
+            .is_none());
+        assert_eq!(utxo.find_UTXO(&alice_hash).unwrap().outputs.len(), 1);
+    }
+
+    #[test]
+    fn coinbase_maturity_and_subsidy() {
+        let chain = TestChain::with_params(
+            ChainParams {
+                coinbase_maturity: 3,
+                halving_interval: 2,
+                ..test_params()
+            },
+            2,
+        );
+        let config = chain.config.clone();
+        let (alice, bob) = (chain.wallets[0].clone(), chain.wallets[1].clone());
+
+        let mut bc = chain.bc.clone();
+        let utxo = UnspentTXUtil { chain: bc.clone() };
+        assert!(Transaction::new(
+            &alice,
+            &bob,
+            coins(30),
+            Fee::default(),
+            &LargestFirst,
+            &utxo
+        )
+        .is_err());
+
+        // a block spending the genesis coinbase too early is rejected
+        let early_config = ChainConfig::new(chain.path()).with_params(test_params());
+        let early = UnspentTXUtil {
+            chain: fork_from_genesis(&bc, &early_config),
+        };
+        let pay = Transaction::new(
+            &alice,
+            &bob,
+            coins(30),
+            Fee::default(),
+            &LargestFirst,
+            &early,
+        )
+        .unwrap();
+        let block = bc
+            .prepare_block(vec![coinbase(&bob, &config), pay.clone()])
+            .unwrap();
+        let block = Miner::default().mine(block).unwrap().block.unwrap();
+        let err = bc.add_block(block).unwrap_err();
+        match err.downcast_ref::<BlockValidationError>() {
+            Some(BlockValidationError::ImmatureCoinbase(_, 0)) => {}
+            _ => panic!("unexpected error {}", err),
+        }
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+
+        // the subsidy halves every two blocks
+        assert_eq!(bc.block_reward(&[]).unwrap(), coins(100));
+        bc.mine_block(vec![coinbase(&bob, &config)]).unwrap();
+        assert_eq!(bc.block_reward(&[]).unwrap(), coins(50));
+        let err = bc.mine_block(vec![coinbase(&bob, &config)]).unwrap_err();
+        match err.downcast_ref::<BlockValidationError>() {
+            Some(BlockValidationError::BadCoinbaseValue(value, allowed))
+                if (*value, *allowed) == (coins(100), coins(50)) => {}
+            _ => panic!("unexpected error {}", err),
+        }
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+
+        // at height 3 the genesis coinbase is mature and the fee goes to the miner
+        let reward =
+            Transaction::new_coinbase(bob.clone(), String::new(), coins(50), &config).unwrap();
+        bc.mine_block(vec![reward]).unwrap();
+        let pay = Transaction::new(
+            &alice,
+            &bob,
+            coins(30),
+            Fee::default(),
+            &LargestFirst,
+            &utxo,
+        )
+        .unwrap();
+        assert_eq!(
+            bc.block_reward(std::slice::from_ref(&pay)).unwrap(),
+            coins(50)
+        );
+        let reward =
+            Transaction::new_coinbase(bob.clone(), String::new(), coins(50), &config).unwrap();
+        bc.mine_block(vec![reward, pay]).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 3);
+    }
+
+    #[test]
+    fn transaction_fees() {
+        let chain = TestChain::new(2);
+        let config = chain.config.clone();
+        let (alice, bob) = (chain.wallets[0].clone(), chain.wallets[1].clone());
+
+        let mut bc = chain.bc.clone();
+        let utxo = UnspentTXUtil { chain: bc.clone() };
+        let fixed = Transaction::new(
+            &alice,
+            &bob,
+            coins(30),
+            Fee::Absolute(coins(5)),
+            &LargestFirst,
+            &utxo,
+        )
+        .unwrap();
+        assert_eq!(fixed.fee(&utxo).unwrap(), coins(5));
+        assert_eq!(fixed.output[1].value, coins(65));
+        let per_byte = Transaction::new(
+            &alice,
+            &bob,
+            coins(30),
+            Fee::PerByte(Amount::from_units(0)),
+            &LargestFirst,
+            &utxo,
+        )
+        .unwrap();
+        assert_eq!(per_byte.fee(&utxo).unwrap(), Amount::ZERO);
+        assert!(Transaction::new(
+            &alice,
+            &bob,
+            coins(96),
+            Fee::Absolute(coins(5)),
+            &LargestFirst,
+            &utxo
+        )
+        .is_err());
+
+        // the fee goes to the miner on top of the subsidy
+        assert_eq!(
+            bc.block_reward(std::slice::from_ref(&fixed)).unwrap(),
+            coins(105)
+        );
+        let reward =
+            Transaction::new_coinbase(bob.clone(), String::new(), coins(105), &config).unwrap();
+        bc.mine_block(vec![reward, fixed]).unwrap();
+
+        // a per byte fee covers the size of the signed transaction
+        let utxo = UnspentTXUtil { chain: bc.clone() };
+        let tx = Transaction::new(
+            &bob,
+            &alice,
+            coins(10),
+            Fee::PerByte(Amount::from_units(1)),
+            &LargestFirst,
+            &utxo,
+        )
+        .unwrap();
+        let fee = tx.fee(&utxo).unwrap();
+        assert!(fee.units() >= tx.size().unwrap() as u64);
+
+        // outputs above the inputs are rejected even with a valid signature
+        let mut tx = Transaction::new(
+            &bob,
+            &alice,
+            coins(10),
+            Fee::default(),
+            &LargestFirst,
+            &utxo,
+        )
+        .unwrap();
+        let mut big = tx.clone();
+        tx.output[0].value = tx.output[0]
+            .value
+            .checked_add(Amount::from_units(1))
+            .unwrap();
+        tx.id = String::new();
+        tx.id = tx.hash().unwrap();
+        let ws = WalletChain::new(&config).unwrap();
+        bc.sign_transacton(&mut tx, &ws.get_wallet(&bob).unwrap().secret_key)
+            .unwrap();
+        let err = tx.fee(&utxo).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BlockValidationError>(),
+            Some(BlockValidationError::InputsBelowOutputs(_))
+        ));
+        let err = bc
+            .mine_block(vec![coinbase(&alice, &config), tx])
+            .unwrap_err();
+        match err.downcast_ref::<BlockValidationError>() {
+            Some(BlockValidationError::InputsBelowOutputs(_)) => {}
+            _ => panic!("unexpected error {}", err),
+        }
+
+        // outputs summing above the money supply fail verification
+        big.output[0].value = Amount::MAX;
+        big.output[1].value = Amount::MAX;
+        assert!(bc.verify_transacton(&big).is_err());
+    }
+
+    #[test]
+    fn batched_payments() {
+        let chain = TestChain::new(4);
+        let config = chain.config.clone();
+        let (alice, bob, carol, dave) = (
+            chain.wallets[0].clone(),
+            chain.wallets[1].clone(),
+            chain.wallets[2].clone(),
+            chain.wallets[3].clone(),
+        );
+        let hash = |address: &str| Address::decode(address).unwrap().body;
+
+        let mut bc = chain.bc.clone();
+        let utxo = UnspentTXUtil { chain: bc.clone() };
+        assert!(TransactionBuilder::new(&alice, &utxo)
+            .with_data(b"memo")
+            .unwrap()
+            .build()
+            .is_err());
+        let tx = TransactionBuilder::new(&alice, &utxo)
+            .pay_to(&bob, coins(20))
+            .unwrap()
+            .pay_to(&carol, coins(30))
+            .unwrap()
+            .with_data(b"payroll")
+            .unwrap()
+            .with_change_address(&dave)
+            .with_fee(Fee::Absolute(coins(5)))
+            .build()
+            .unwrap();
+        assert_eq!(tx.output.len(), 4);
+        assert!(tx.output[2].is_data());
+        let reward =
+            Transaction::new_coinbase(alice.clone(), String::new(), coins(105), &config).unwrap();
+        bc.mine_block(vec![reward, tx.clone()]).unwrap();
+
+        let balance = |address: &str| {
+            let outputs = utxo.find_UTXO(&hash(address)).unwrap().outputs;
+            Amount::checked_sum(outputs.iter().map(|out| out.value)).unwrap()
+        };
+        assert_eq!(
+            (balance(&bob), balance(&carol), balance(&dave)),
+            (coins(20), coins(30), coins(45))
+        );
+        assert!(bc
+            .store
+            .get_utxo(&OutPoint::new(&tx.id, 2))
+            .unwrap()
+            .is_none());
+
+        // explicit inputs have to be unspent outputs of the sender
+        let change = OutPoint::new(&tx.id, 3);
+        let pay_bob = |input: OutPoint| {
+            TransactionBuilder::new(&dave, &utxo)
+                .pay_to(&bob, coins(45))
+                .unwrap()
+                .with_input(input)
+        };
+        assert!(pay_bob(OutPoint::new(&tx.id, 0)).build().is_err());
+        assert!(pay_bob(change.clone())
+            .with_input(change.clone())
+            .build()
+            .is_err());
+        let exact = pay_bob(change).build().unwrap();
+        assert_eq!((exact.input.len(), exact.output.len()), (1, 1));
+    }
+
+    #[test]
+    fn input_scripts() {
+        let chain = TestChain::new(2);
+        let config = chain.config.clone();
+        let (alice, bob) = (chain.wallets[0].clone(), chain.wallets[1].clone());
+
+        let mut bc = chain.bc.clone();
+        bc.mine_block(vec![coinbase(&alice, &config)]).unwrap();
+        let utxo = UnspentTXUtil { chain: bc.clone() };
+        let tx = Transaction::new(
+            &alice,
+            &bob,
+            coins(150),
+            Fee::default(),
+            &LargestFirst,
+            &utxo,
+        )
+        .unwrap();
+        assert_eq!(tx.input.len(), 2);
+        let bob_hash = Address::decode(&bob).unwrap().body;
+        assert_eq!(tx.output[0].script_pubkey, Script::p2pkh(&bob_hash));
+        assert!(bc.verify_transacton(&tx).unwrap());
+
+        // every signature covers its own input and all the outputs
+        let mut swapped = tx.clone();
+        swapped.input.swap(0, 1);
+        assert!(!bc.verify_transacton(&swapped).unwrap());
+        let mut changed = tx.clone();
+        changed.output[0].script_pubkey = Script::p2pkh(&[0; 20]);
+        assert!(!bc.verify_transacton(&changed).unwrap());
+
+        // only the key behind the public key hash unlocks the outputs
+        let ws = WalletChain::new(&config).unwrap();
+        let mut stolen = tx.clone();
+        bc.sign_transacton(&mut stolen, &ws.get_wallet(&bob).unwrap().secret_key)
+            .unwrap();
+        assert!(!bc.verify_transacton(&stolen).unwrap());
+        assert!(bc
+            .mine_block(vec![coinbase(&bob, &config), stolen])
+            .is_err());
+
+        bc.mine_block(vec![coinbase(&bob, &config), tx]).unwrap();
+        let outputs = utxo.find_UTXO(&bob_hash).unwrap().outputs;
+        assert_eq!(
+            Amount::checked_sum(outputs.iter().map(|out| out.value)),
+            Some(coins(250))
+        );
+    }
+
+    #[test]
+    fn multisig_payments() {
+        let chain = TestChain::new(4);
+        let config = chain.config.clone();
+        let (alice, bob, carol, dave) = (
+            chain.wallets[0].clone(),
+            chain.wallets[1].clone(),
+            chain.wallets[2].clone(),
+            chain.wallets[3].clone(),
+        );
+        let ws = WalletChain::new(&config).unwrap();
+        let wallet = |address: &str| ws.get_wallet(address).unwrap().clone();
+
+        let mut bc = chain.bc.clone();
+        let utxo = UnspentTXUtil { chain: bc.clone() };
+        let public_keys: Vec<_> = [&alice, &bob, &carol]
+            .iter()
+            .map(|address| wallet(address).public_key)
+            .collect();
+        let redeem_script = Script::multisig(2, &public_keys).unwrap();
+        let treasury = Script::p2sh(&redeem_script.hash()).to_address().unwrap();
+        let fund = TransactionBuilder::new(&alice, &utxo)
+            .pay_to(&treasury, coins(60))
+            .unwrap()
+            .build()
+            .unwrap();
+        bc.mine_block(vec![coinbase(&alice, &config), fund])
+            .unwrap();
+
+        let spend = || {
+            TransactionBuilder::new(&treasury, &utxo)
+                .pay_to(&dave, coins(25))
+                .unwrap()
+                .with_fee(Fee::PerByte(Amount::from_units(1)))
+        };
+        assert!(spend().build().is_err());
+        let other = Script::multisig(1, &public_keys).unwrap();
+        assert!(spend().with_redeem_script(other).build().is_err());
+        let mut tx = spend()
+            .with_redeem_script(redeem_script.clone())
+            .build()
+            .unwrap();
+        assert!(!bc.verify_transacton(&tx).unwrap());
+
+        // co-signers add their signatures in any order
+        let sign = |tx: &mut Transaction, address: &str| {
+            utxo.chain
+                .sign_multisig_transaction(tx, &wallet(address).secret_key)
+        };
+        assert_eq!(sign(&mut tx, &carol).unwrap(), 1);
+        assert_eq!(sign(&mut tx, &carol).unwrap(), 1);
+        assert!(sign(&mut tx, &dave).is_err());
+        assert!(!bc.verify_transacton(&tx).unwrap());
+        assert!(bc
+            .mine_block(vec![coinbase(&bob, &config), tx.clone()])
+            .is_err());
+        assert_eq!(sign(&mut tx, &alice).unwrap(), 2);
+        assert!(bc.verify_transacton(&tx).unwrap());
+        assert!(tx.fee(&utxo).unwrap() >= Amount::from_units(tx.size().unwrap() as u64));
+
+        bc.mine_block(vec![coinbase(&bob, &config), tx]).unwrap();
+        let balance = |address: &str| {
+            let hash = Address::decode(address).unwrap().body;
+            let outputs = utxo.find_UTXO(&hash).unwrap().outputs;
+            Amount::checked_sum(outputs.iter().map(|out| out.value)).unwrap()
+        };
+        assert_eq!(balance(&dave), coins(25));
+        assert!(balance(&treasury) > coins(34) && balance(&treasury) < coins(35));
+    }
+
+    #[test]
+    fn timelocks() {
+        let chain = TestChain::new(3);
+        let config = chain.config.clone();
+        let (alice, bob, carol) = (
+            chain.wallets[0].clone(),
+            chain.wallets[1].clone(),
+            chain.wallets[2].clone(),
+        );
+
+        let mut bc = chain.bc.clone();
+        let utxo = UnspentTXUtil { chain: bc.clone() };
+
+        // locked until after height 2
+        let locked = TransactionBuilder::new(&alice, &utxo)
+            .pay_to(&bob, coins(10))
+            .unwrap()
+            .with_lock_time(2)
+            .build()
+            .unwrap();
+        assert!(!locked.is_final(2, 0) && locked.is_final(3, 0));
+        assert!(!bc.is_final_transaction(&locked).unwrap());
+        assert!(!bc.verify_transacton(&locked).unwrap());
+        let err = add_rejected(&mut bc, vec![coinbase(&bob, &config), locked.clone()]);
+        match err.downcast_ref::<BlockValidationError>() {
+            Some(BlockValidationError::NonFinalTransaction(txid)) if *txid == locked.id => {}
+            _ => panic!("unexpected error {}", err),
+        }
+        bc.mine_block(vec![coinbase(&bob, &config)]).unwrap();
+
+        // bob's coinbase from height 1 can be spent two blocks later
+        let relative = TransactionBuilder::new(&bob, &utxo)
+            .pay_to(&carol, coins(10))
+            .unwrap()
+            .with_relative_lock(RelativeLock::Blocks(2))
+            .build()
+            .unwrap();
+        assert_eq!(
+            relative.input[0].relative_lock(),
+            Some(RelativeLock::Blocks(2))
+        );
+        assert!(!bc.verify_transacton(&relative).unwrap());
+        let err = add_rejected(&mut bc, vec![coinbase(&carol, &config), relative.clone()]);
+        match err.downcast_ref::<BlockValidationError>() {
+            Some(BlockValidationError::RelativeLockNotMet(txid, 0))
+                if *txid == relative.input[0].txid => {}
+            _ => panic!("unexpected error {}", err),
+        }
+        assert!(bc
+            .mine_block(vec![coinbase(&carol, &config), locked.clone()])
+            .is_err());
+        bc.mine_block(vec![coinbase(&carol, &config)]).unwrap();
+
+        assert!(bc.verify_transacton(&locked).unwrap());
+        assert!(bc.verify_transacton(&relative).unwrap());
+        bc.mine_block(vec![coinbase(&carol, &config), locked, relative])
+            .unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 3);
+
+        // a lock time far in the future, every input final opts out of it
+        let hour_from_now = now_ms().unwrap() + 3_600_000;
+        let mut later = TransactionBuilder::new(&carol, &utxo)
+            .pay_to(&alice, coins(5))
+            .unwrap()
+            .with_lock_time(hour_from_now)
+            .build()
+            .unwrap();
+        assert!(!bc.is_final_transaction(&later).unwrap());
+        assert!(later.is_final(4, hour_from_now + 1));
+        later
+            .input
+            .iter_mut()
+            .for_each(|vin| vin.sequence = SEQUENCE_FINAL);
+        assert!(bc.is_final_transaction(&later).unwrap());
+        assert!(RelativeLock::Time(u64::MAX).to_sequence().is_err());
+
+        // time locks pass with the median time past, not with the clock or the
+        // timestamp of the block
+        let tip_time = bc.get_tip().unwrap().get_timestamp();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let past = TransactionBuilder::new(&carol, &utxo)
+            .pay_to(&alice, coins(5))
+            .unwrap()
+            .with_lock_time(tip_time)
+            .build()
+            .unwrap();
+        assert!(past.is_final(5, now_ms().unwrap()));
+        assert!(!bc.is_final_transaction(&past).unwrap());
+        let err = add_rejected(&mut bc, vec![coinbase(&carol, &config), past.clone()]);
+        match err.downcast_ref::<BlockValidationError>() {
+            Some(BlockValidationError::NonFinalTransaction(txid)) if *txid == past.id => {}
+            _ => panic!("unexpected error {}", err),
+        }
+        // once most of the recent blocks are newer than the lock time
+        for _ in 0..3 {
+            bc.mine_block(vec![coinbase(&carol, &config)]).unwrap();
+        }
+        assert!(!bc.is_final_transaction(&past).unwrap());
+        bc.mine_block(vec![coinbase(&carol, &config)]).unwrap();
+        assert!(bc.is_final_transaction(&past).unwrap());
+    }
+
+    #[test]
+    fn double_spends() {
+        let chain = TestChain::new(3);
+        let config = chain.config.clone();
+        let (alice, bob, carol) = (
+            chain.wallets[0].clone(),
+            chain.wallets[1].clone(),
+            chain.wallets[2].clone(),
+        );
+
+        let mut bc = chain.bc.clone();
+        let utxo = UnspentTXUtil { chain: bc.clone() };
+        // both spend the genesis coinbase, the only output alice has
+        let to_bob = TransactionBuilder::new(&alice, &utxo)
+            .pay_to(&bob, coins(10))
+            .unwrap()
+            .with_replaceable()
+            .build()
+            .unwrap();
+        let to_carol = TransactionBuilder::new(&alice, &utxo)
+            .pay_to(&carol, coins(10))
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(to_bob.is_replaceable() && !to_carol.is_replaceable());
+        assert!(bc.verify_transacton(&to_bob).unwrap());
+        assert!(bc.verify_transacton(&to_carol).unwrap());
+
+        let mut twice = to_carol.clone();
+        twice.input.push(twice.input[0].clone());
+        twice.id = twice.hash().unwrap();
+        assert!(!bc.verify_transacton(&twice).unwrap());
+
+        let outpoint = &to_bob.spent_outpoints()[0];
+        let is_duplicate = |err: &failure::Error| {
+            matches!(
+                err.downcast_ref::<BlockValidationError>(),
+                Some(BlockValidationError::DuplicateInput(txid, vout))
+                    if *txid == outpoint.txid && *vout == outpoint.vout as i32
+            )
+        };
+        let spend_both = vec![coinbase(&bob, &config), to_bob.clone(), to_carol.clone()];
+        assert!(is_duplicate(
+            &bc.mine_block(spend_both.clone()).unwrap_err()
+        ));
+        assert!(is_duplicate(&add_rejected(&mut bc, spend_both)));
+        assert!(is_duplicate(&add_rejected(
+            &mut bc,
+            vec![coinbase(&bob, &config), twice]
+        )));
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+
+        bc.mine_block(vec![coinbase(&bob, &config), to_bob])
+            .unwrap();
+        assert!(!bc.verify_transacton(&to_carol).unwrap());
+        assert!(bc
+            .mine_block(vec![coinbase(&bob, &config), to_carol])
+            .is_err());
+    }
+
+    #[test]
+    fn block_limits() {
+        let chain = TestChain::with_params(
+            ChainParams {
+                max_block_size: 1000,
+                max_block_txs: 2,
+                ..test_params()
+            },
+            2,
+        );
+        let (alice, bob) = (chain.wallets[0].clone(), chain.wallets[1].clone());
+        let (alice_key, bob_key) = (chain.secret_key(&alice), chain.secret_key(&bob));
+        let config = chain.config.clone();
+        let mut bc = chain.bc.clone();
+        let genesis_coinbase = chain.genesis_coinbase();
+        let to_bob = spend_output(&genesis_coinbase, 0, &alice_key, &bob, coins(1));
+        let to_alice = spend_output(&to_bob, 0, &bob_key, &alice, coins(1));
+
+        let err = add_rejected(
+            &mut bc,
+            vec![coinbase(&bob, &config), to_bob.clone(), to_alice],
+        );
+        assert!(matches!(
+            err.downcast_ref::<BlockValidationError>(),
+            Some(BlockValidationError::TooManyTransactions(_, 3, 2))
+        ));
+        let large_coinbase =
+            Transaction::new_coinbase(bob.clone(), "x".repeat(1000), coins(100), &config).unwrap();
+        let err = add_rejected(&mut bc, vec![large_coinbase, to_bob.clone()]);
+        assert!(matches!(
+            err.downcast_ref::<BlockValidationError>(),
+            Some(BlockValidationError::BlockTooLarge(_, size, 1000)) if *size > 1000
+        ));
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+
+        let block = bc
+            .mine_block(vec![coinbase(&bob, &config), to_bob])
+            .unwrap();
+        assert!(block.size().unwrap() <= 1000);
     }
 }
@@ -1,27 +1,92 @@
+use failure::format_err;
+
 use crate::block::Block;
 use crate::blockchain::Blockchain;
 
+/// BlockchainIter walks backwards from a block to genesis by following the
+/// previous hash, so it also works for blocks outside the active chain
+/// a block that cannot be read is returned as an error and ends the iteration
 pub struct BlockchainIter<'a> {
     pub(crate) current_hash: String,
     pub(crate) bc: &'a Blockchain,
 }
 
 impl<'a> Iterator for BlockchainIter<'a> {
-    type Item = Block;
+    type Item = Result<Block, failure::Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        // the genesis block has no parent
+        if self.current_hash.is_empty() {
+            return None;
+        }
+        let hash = std::mem::take(&mut self.current_hash);
+        match self.bc.store.get_block(&hash) {
+            Ok(Some(block)) => {
+                self.current_hash = block.get_prev_block_hash();
+                Some(Ok(block))
+            }
+            Ok(None) => Some(Err(format_err!("block {} is missing from the store", hash))),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// HeightIter walks the active chain over a range of heights using the height index
+/// it can be consumed from both ends, `rev()` goes from the highest block down
+pub struct HeightIter<'a> {
+    /// next height from the front, inclusive
+    pub(crate) low: i32,
+    /// next height from the back, inclusive
+    pub(crate) high: i32,
+    pub(crate) bc: &'a Blockchain,
+}
+
+impl<'a> HeightIter<'a> {
+    fn block_at(&self, height: i32) -> Result<Block, failure::Error> {
+        let hash = self
+            .bc
+            .store
+            .get_hash_at_height(height)?
+            .ok_or_else(|| format_err!("no block at height {} in the index", height))?;
+        self.bc.get_block(&hash)
+    }
+
+    /// stops the iteration after an error
+    fn fuse(
+        &mut self,
+        item: Result<Block, failure::Error>,
+    ) -> Option<Result<Block, failure::Error>> {
+        if item.is_err() {
+            self.low = 1;
+            self.high = 0;
+        }
+        Some(item)
+    }
+}
+
+impl<'a> Iterator for HeightIter<'a> {
+    type Item = Result<Block, failure::Error>;
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(encode_block) = self.bc.db.get(&self.current_hash) {
-            return match encode_block {
-                Some(b) => {
-                    if let Ok(block) = bincode::deserialize::<Block>(&b) {
-                        self.current_hash = block.get_prev_block_hash();
-                        Some(block)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
-            };
+        if self.low > self.high {
+            return None;
+        }
+        let item = self.block_at(self.low);
+        self.low += 1;
+        self.fuse(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.high - self.low + 1).max(0) as usize;
+        (0, Some(len))
+    }
+}
+
+impl<'a> DoubleEndedIterator for HeightIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.low > self.high {
+            return None;
         }
-        None
+        let item = self.block_at(self.high);
+        self.high -= 1;
+        self.fuse(item)
     }
 }
@@ -1,9 +1,23 @@
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::block_template::{BlockTemplate, BlockTemplateBuilder};
 use crate::blockchain::Blockchain;
-use crate::transaction::Transaction;
+use crate::coin_selection::{selector_by_name, CoinSelector};
+use crate::config::ChainConfig;
+use crate::mempool::Mempool;
+use crate::mergetx::{verify_merkle_proof, MerkleProof};
+use crate::script::Script;
+use crate::transaction::{Fee, Transaction};
+use crate::transaction_builder::TransactionBuilder;
 use crate::unspent_tx_util::UnspentTXUtil;
+use crate::utils::{hex_decode, hex_encode, now_ms};
 use crate::wallet_chain::WalletChain;
+use bincode::{deserialize, serialize};
 use bitcoincash_addr::Address;
-use clap::{arg, Command};
+use clap::{arg, Arg, ArgMatches, Command};
+use failure::format_err;
+use serde::Deserialize;
+use std::path::PathBuf;
 use std::process::exit;
 
 pub struct Cli {}
@@ -18,6 +32,11 @@ impl Cli {
             .version("0.1")
             .author("test")
             .about("test")
+            .arg(
+                arg!(--datadir <DIR> "'Directory holding blocks, utxo and wallets, defaults to $CHAIN_RS_DATADIR or ./data'")
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .global(true),
+            )
             .subcommand(Command::new("printchain").about("print all the chain blocks"))
             .subcommand(
                 Command::new("getbalance")
@@ -34,17 +53,117 @@ impl Cli {
                     .about("send  in the blockchain")
                     .arg(arg!(<FROM>" 'Source wallet address'"))
                     .arg(arg!(<TO>" 'Destination wallet address'"))
-                    .arg(arg!(<AMOUNT>" 'Destination wallet address'")),
+                    .arg(arg!(<AMOUNT>" 'Destination wallet address'"))
+                    .args(payment_args()),
+            )
+            .subcommand(
+                Command::new("sendmany")
+                    .about("pay many addresses in one transaction and mine it")
+                    .arg(arg!(<FROM>" 'Source wallet address'"))
+                    .arg(arg!(<FILE>" 'JSON or CSV file of address and amount pairs'"))
+                    .args(payment_args())
+                    .arg(arg!(--change <ADDRESS> "'Address receiving the change, defaults to FROM'"))
+                    .arg(arg!(--data <TEXT> "'Text stored in an unspendable data output'")),
+            )
+            .subcommand(
+                Command::new("createmultisig")
+                    .about("create a script address that needs REQUIRED of the KEYS to spend")
+                    .arg(arg!(<REQUIRED>"'Number of signatures a spend needs'"))
+                    .arg(
+                        Arg::new("KEYS")
+                            .help("Wallet addresses or hex encoded public keys")
+                            .num_args(1..)
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                Command::new("spendmultisig")
+                    .about("write an unsigned payment from a multisig address to a file")
+                    .arg(arg!(<REDEEM>"'Hex encoded redeem script printed by createmultisig'"))
+                    .arg(arg!(<TO>" 'Destination wallet address'"))
+                    .arg(arg!(<AMOUNT>" 'Amount in coins'"))
+                    .arg(arg!(<FILE>" 'File the transaction is written to'"))
+                    .args(payment_args()),
+            )
+            .subcommand(
+                Command::new("signmultisig")
+                    .about("add the signature of a wallet to a multisig payment")
+                    .arg(arg!(<FILE>" 'File written by spendmultisig'"))
+                    .arg(arg!(<ADDRESS>" 'Signing wallet address'"))
+                    .arg(
+                        arg!(--mine "'Mine the payment once it has enough signatures'")
+                            .action(clap::ArgAction::SetTrue),
+                    ),
             )
             .subcommand(Command::new("createwallet").about("create a wallet"))
             .subcommand(Command::new("listaddresses").about("list all addresses"))
             .subcommand(Command::new("reindex").about("reindex UTXO"))
+            .subcommand(
+                Command::new("reindexchain").about("rebuild the block height and txid indexes"),
+            )
+            .subcommand(
+                Command::new("exportsnapshot")
+                    .about("write the UTXO set at the chain tip to a file")
+                    .arg(arg!(<FILE>"'Snapshot file to create'")),
+            )
+            .subcommand(
+                Command::new("importsnapshot")
+                    .about("replace the UTXO set with a snapshot taken at the chain tip")
+                    .arg(arg!(<FILE>"'Snapshot file to read'"))
+                    .arg(arg!(--commitment <HASH> "'Trusted commitment the snapshot has to match'")),
+            )
+            .subcommand(
+                Command::new("utxocommitment").about("print the commitment hash of the UTXO set"),
+            )
+            .subcommand(
+                Command::new("merkleproof")
+                    .about("prove that a transaction is included in a block")
+                    .arg(arg!(<BLOCK>"'Hash of the block holding the transaction'"))
+                    .arg(arg!(<TXID>"'Id of the transaction to prove'")),
+            )
+            .subcommand(
+                Command::new("verifyproof")
+                    .about("verify a merkle proof against a block header")
+                    .arg(arg!(<BLOCK>"'Hash of the block the proof is for'"))
+                    .arg(arg!(<TXID>"'Id of the proven transaction'"))
+                    .arg(arg!(<PROOF>"'Hex encoded proof printed by merkleproof'")),
+            )
+            .subcommand(
+                Command::new("getblocktemplate")
+                    .about("print the next block as JSON for a miner, with the transactions of the saved mempool")
+                    .arg(
+                        arg!(--maxsize <BYTES> "'Largest block to build, at most the consensus limit'")
+                            .value_parser(clap::value_parser!(usize)),
+                    )
+                    .arg(
+                        arg!(--maxtxs <COUNT> "'Most transactions to include, coinbase included'")
+                            .value_parser(clap::value_parser!(usize)),
+                    )
+                    .arg(
+                        arg!(--coinbase <ADDRESS> "'Address the block reward is paid to'")
+                            .requires("block"),
+                    )
+                    .arg(
+                        arg!(--block <FILE> "'File the unmined block is written to'")
+                            .requires("coinbase"),
+                    ),
+            )
+            .subcommand(
+                Command::new("submitblock")
+                    .about("add a block mined from a template to the chain")
+                    .arg(arg!(<FILE>"'File holding the hex encoded block'")),
+            )
             .get_matches();
 
+        let config = match matches.get_one::<PathBuf>("datadir") {
+            Some(dir) => ChainConfig::new(dir),
+            None => ChainConfig::from_env(),
+        };
+
         if let Some(ref matches) = matches.subcommand_matches("create") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
                 let address = String::from(address);
-                Blockchain::create_blockchain(address.clone())?;
+                Blockchain::create_blockchain(address.clone(), &config)?;
                 println!("create blockchain");
             }
         }
@@ -52,13 +171,11 @@ impl Cli {
         if let Some(ref matches) = matches.subcommand_matches("getbalance") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
                 let pub_key_hash = Address::decode(address).unwrap().body;
-                let bc = Blockchain::new()?;
+                let bc = Blockchain::new(&config)?;
                 let utxo_util = UnspentTXUtil { chain: bc };
                 let utxos = utxo_util.find_UTXO(&pub_key_hash)?;
-                let mut balance = 0;
-                for out in utxos.outputs {
-                    balance += out.value;
-                }
+                let balance = Amount::checked_sum(utxos.outputs.iter().map(|out| out.value))
+                    .ok_or_else(|| format_err!("balance is above the money supply"))?;
                 println!("Balance of '{}'; {} ", address, balance)
             }
         }
@@ -78,39 +195,171 @@ impl Cli {
                 exit(1)
             };
 
-            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+            let amount: Amount = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
                 amount.parse()?
             } else {
                 println!("from not supply!: usage");
                 exit(1)
             };
 
-            let bc = Blockchain::new()?;
+            let (fee, selector) = payment_options(matches)?;
+
+            let bc = Blockchain::new(&config)?;
             let utxo_util = UnspentTXUtil { chain: bc };
-            let _cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
-            let _tx = Transaction::new(from, to, amount, &utxo_util)?;
+            let _cbtx = Transaction::new_coinbase(
+                from.to_string(),
+                String::from("reward!"),
+                utxo_util.chain.block_reward(&[])?,
+                &config,
+            )?;
+            let _tx = Transaction::new(from, to, amount, fee, selector.as_ref(), &utxo_util)?;
             // TODO update
             // let new_block = utxo_util.chain.add_block(vec![cbtx, tx])?;
             // utxo_util.update(&new_block)?;
             println!("success!");
         }
 
+        if let Some(matches) = matches.subcommand_matches("sendmany") {
+            let from = matches.get_one::<String>("FROM").unwrap();
+            let recipients = read_recipients(matches.get_one::<String>("FILE").unwrap())?;
+            let (fee, selector) = payment_options(matches)?;
+
+            let mut bc = Blockchain::new(&config)?;
+            let utxo_util = UnspentTXUtil { chain: bc.clone() };
+            let mut builder = TransactionBuilder::new(from, &utxo_util)
+                .with_fee(fee)
+                .with_coin_selector(selector.as_ref());
+            for (address, amount) in &recipients {
+                builder = builder.pay_to(address, *amount)?;
+            }
+            if let Some(change) = matches.get_one::<String>("change") {
+                builder = builder.with_change_address(change);
+            }
+            if let Some(data) = matches.get_one::<String>("data") {
+                builder = builder.with_data(data.as_bytes())?;
+            }
+            let tx = builder.build()?;
+            let paid_fee = tx.fee(&utxo_util)?;
+
+            let cbtx = Transaction::new_coinbase(
+                from.to_string(),
+                String::new(),
+                bc.block_reward(std::slice::from_ref(&tx))?,
+                &config,
+            )?;
+            let block = bc.mine_block(vec![cbtx, tx.clone()])?;
+            println!(
+                "success: paid {} recipients in {} with fee {}, block {}",
+                recipients.len(),
+                tx.id,
+                paid_fee,
+                block.get_hash()
+            );
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createmultisig") {
+            let required: usize = matches.get_one::<String>("REQUIRED").unwrap().parse()?;
+            let ws = WalletChain::new(&config)?;
+            let public_keys = matches
+                .get_many::<String>("KEYS")
+                .unwrap()
+                .map(|key| match ws.get_wallet(key) {
+                    Some(wallet) => Ok(wallet.public_key.clone()),
+                    None => hex_decode(key),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let redeem_script = Script::multisig(required, &public_keys)?;
+            let address = Script::p2sh(&redeem_script.hash())
+                .to_address()
+                .ok_or_else(|| format_err!("can't encode the script address"))?;
+            println!("address: {}", address);
+            println!("redeem script: {}", hex_encode(redeem_script.as_bytes()));
+        }
+
+        if let Some(matches) = matches.subcommand_matches("spendmultisig") {
+            let redeem_script =
+                Script::from_bytes(hex_decode(matches.get_one::<String>("REDEEM").unwrap())?);
+            let to = matches.get_one::<String>("TO").unwrap();
+            let amount: Amount = matches.get_one::<String>("AMOUNT").unwrap().parse()?;
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let (fee, selector) = payment_options(matches)?;
+            let from = Script::p2sh(&redeem_script.hash())
+                .to_address()
+                .ok_or_else(|| format_err!("can't encode the script address"))?;
+
+            let utxo_util = UnspentTXUtil {
+                chain: Blockchain::new(&config)?,
+            };
+            let tx = TransactionBuilder::new(&from, &utxo_util)
+                .pay_to(to, amount)?
+                .with_fee(fee)
+                .with_coin_selector(selector.as_ref())
+                .with_redeem_script(redeem_script)
+                .build()?;
+            std::fs::write(file, hex_encode(&serialize(&tx)?))?;
+            println!(
+                "wrote unsigned transaction {} spending {} outputs of {} to {}",
+                tx.id,
+                tx.input.len(),
+                from,
+                file
+            );
+        }
+
+        if let Some(matches) = matches.subcommand_matches("signmultisig") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let mut tx: Transaction =
+                deserialize(&hex_decode(std::fs::read_to_string(file)?.trim())?)?;
+            let ws = WalletChain::new(&config)?;
+            let wallet = ws
+                .get_wallet(address)
+                .ok_or_else(|| format_err!("wallet {} not found", address))?;
+
+            let mut bc = Blockchain::new(&config)?;
+            let signatures = bc.sign_multisig_transaction(&mut tx, &wallet.secret_key)?;
+            std::fs::write(file, hex_encode(&serialize(&tx)?))?;
+            let complete = bc.verify_transacton(&tx)?;
+            println!(
+                "{} has {} signatures, {}",
+                tx.id,
+                signatures,
+                if complete { "complete" } else { "incomplete" }
+            );
+            if complete && matches.get_flag("mine") {
+                let utxo_util = UnspentTXUtil { chain: bc.clone() };
+                let paid_fee = tx.fee(&utxo_util)?;
+                let cbtx = Transaction::new_coinbase(
+                    address.to_string(),
+                    String::new(),
+                    bc.block_reward(std::slice::from_ref(&tx))?,
+                    &config,
+                )?;
+                let block = bc.mine_block(vec![cbtx, tx])?;
+                println!(
+                    "success: mined with fee {}, block {}",
+                    paid_fee,
+                    block.get_hash()
+                );
+            }
+        }
+
         if let Some(_) = matches.subcommand_matches("printchain") {
-            let bc = Blockchain::new()?;
-            for b in &mut bc.iter() {
-                println!("block: {:#?}", b);
+            let bc = Blockchain::new(&config)?;
+            for b in bc.iter() {
+                println!("block: {:#?}", b?);
             }
         }
 
         if let Some(_) = matches.subcommand_matches("createwallet") {
-            let mut ws = WalletChain::new()?;
+            let mut ws = WalletChain::new(&config)?;
             let address = ws.create_wallet();
             ws.save_all()?;
             println!("success: address {}", address);
         }
 
         if let Some(_) = matches.subcommand_matches("listaddresses") {
-            let ws = WalletChain::new()?;
+            let ws = WalletChain::new(&config)?;
             let addresses = ws.get_all_address();
             println!("addresses: ");
             for ad in addresses {
@@ -119,13 +368,264 @@ impl Cli {
         }
 
         if let Some(_) = matches.subcommand_matches("reindex") {
-            let bc = Blockchain::new()?;
+            let bc = Blockchain::new(&config)?;
             let utxo_set = UnspentTXUtil { chain: bc };
             utxo_set.reindex()?;
             let count = utxo_set.count_transactions()?;
             println!("Done! There are {} transactions in the UTXO set.", count);
         }
 
+        if matches.subcommand_matches("reindexchain").is_some() {
+            let bc = Blockchain::new(&config)?;
+            let count = bc.rebuild_indexes()?;
+            println!("Done! Indexed {} blocks.", count);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("exportsnapshot") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let utxo_set = UnspentTXUtil {
+                chain: Blockchain::new(&config)?,
+            };
+            let info = utxo_set.export_snapshot(file)?;
+            println!(
+                "exported {} outputs at block {} height {}",
+                info.count, info.block_hash, info.height
+            );
+            println!("commitment: {}", info.commitment_hex());
+        }
+
+        if let Some(matches) = matches.subcommand_matches("importsnapshot") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let expected = matches.get_one::<String>("commitment");
+            let utxo_set = UnspentTXUtil {
+                chain: Blockchain::new(&config)?,
+            };
+            let info = utxo_set.import_snapshot(file, expected.map(|c| c.as_str()))?;
+            println!(
+                "imported {} outputs at block {} height {}",
+                info.count, info.block_hash, info.height
+            );
+            println!("commitment: {}", info.commitment_hex());
+        }
+
+        if matches.subcommand_matches("utxocommitment").is_some() {
+            let utxo_set = UnspentTXUtil {
+                chain: Blockchain::new(&config)?,
+            };
+            let info = utxo_set.commitment()?;
+            println!(
+                "{} outputs at block {} height {}",
+                info.count, info.block_hash, info.height
+            );
+            println!("commitment: {}", info.commitment_hex());
+        }
+
+        if let Some(matches) = matches.subcommand_matches("merkleproof") {
+            let block_hash = matches.get_one::<String>("BLOCK").unwrap();
+            let txid = matches.get_one::<String>("TXID").unwrap();
+            let bc = Blockchain::new(&config)?;
+            let block = bc.get_block(block_hash)?;
+            let proof = block.merkle_proof(txid)?;
+            println!(
+                "merkle root: {}",
+                hex_encode(&block.get_header().merkle_root)
+            );
+            println!("proof: {}", hex_encode(&serialize(&proof)?));
+        }
+
+        if let Some(matches) = matches.subcommand_matches("verifyproof") {
+            let block_hash = matches.get_one::<String>("BLOCK").unwrap();
+            let txid = matches.get_one::<String>("TXID").unwrap();
+            let proof: MerkleProof =
+                deserialize(&hex_decode(matches.get_one::<String>("PROOF").unwrap())?)?;
+            let bc = Blockchain::new(&config)?;
+            // only the header is read, the block body is not needed
+            let header = match bc.store.get_header(block_hash)? {
+                Some(header) => header,
+                None => {
+                    println!("block {} is not found", block_hash);
+                    exit(1)
+                }
+            };
+            if verify_merkle_proof(&header.merkle_root, txid, &proof) {
+                println!("valid: {} is in block {}", txid, block_hash);
+            } else {
+                println!("invalid proof");
+                exit(1)
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getblocktemplate") {
+            let template = block_template(
+                &config,
+                matches.get_one::<usize>("maxsize").copied(),
+                matches.get_one::<usize>("maxtxs").copied(),
+            )?;
+            println!("{}", serde_json::to_string_pretty(&template)?);
+            if let (Some(address), Some(file)) = (
+                matches.get_one::<String>("coinbase"),
+                matches.get_one::<String>("block"),
+            ) {
+                let cbtx = Transaction::new_coinbase(
+                    address.to_string(),
+                    String::new(),
+                    template.coinbase_value,
+                    &config,
+                )?;
+                let block = template.into_block(cbtx)?;
+                std::fs::write(file, hex_encode(&serialize(&block)?))?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("submitblock") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let mut block: Block =
+                deserialize(&hex_decode(std::fs::read_to_string(file)?.trim())?)?;
+            // miners only change the header, the hash is taken from it
+            block.set_hash(block.calculate_hash());
+            let mut bc = Blockchain::new(&config)?;
+            let update = bc.add_block(block.clone())?;
+            if update.connected.is_empty() {
+                println!("block {} is not on the best chain", block.get_hash());
+                return Ok(());
+            }
+            let now = now_ms()?;
+            let mut mempool = Mempool::new(config.mempool_policy().clone());
+            mempool.load(config.mempool_path(), &bc, now)?;
+            mempool.update(&update, &bc, now)?;
+            mempool.save(config.mempool_path())?;
+            println!(
+                "success: block {} at height {}",
+                block.get_hash(),
+                block.get_height()
+            );
+        }
+
         Ok(())
     }
 }
+
+/// the next block on top of the chain in `config` with the transactions of the
+/// mempool the node saved there, see `BlockTemplateBuilder`
+fn block_template(
+    config: &ChainConfig,
+    max_size: Option<usize>,
+    max_txs: Option<usize>,
+) -> Result<BlockTemplate, failure::Error> {
+    let bc = Blockchain::new(config)?;
+    let mut mempool = Mempool::new(config.mempool_policy().clone());
+    mempool.load(config.mempool_path(), &bc, now_ms()?)?;
+    let mut builder = BlockTemplateBuilder::new(&bc).with_mempool(&mempool);
+    if let Some(max_size) = max_size {
+        builder = builder.with_max_size(max_size);
+    }
+    if let Some(max_txs) = max_txs {
+        builder = builder.with_max_transactions(max_txs);
+    }
+    builder.build()
+}
+
+/// fee and coin selection options shared by `send` and `sendmany`
+fn payment_args() -> Vec<Arg> {
+    vec![
+        arg!(--fee <AMOUNT> "'Fee paid to the miner in coins'"),
+        arg!(--feerate <RATE> "'Fee paid to the miner in units for every byte'")
+            .conflicts_with("fee"),
+        arg!(--coins <STRATEGY> "'Coin selection: largest, smallest, exact or random'")
+            .default_value("exact"),
+    ]
+}
+
+fn payment_options(matches: &ArgMatches) -> Result<(Fee, Box<dyn CoinSelector>), failure::Error> {
+    let fee = if let Some(fee) = matches.get_one::<String>("fee") {
+        Fee::Absolute(fee.parse()?)
+    } else if let Some(rate) = matches.get_one::<String>("feerate") {
+        Fee::PerByte(Amount::from_units(rate.parse()?))
+    } else {
+        Fee::default()
+    };
+    // `--coins` defaults to exact
+    let selector = selector_by_name(matches.get_one::<String>("coins").unwrap())?;
+    Ok((fee, selector))
+}
+
+#[derive(Deserialize)]
+struct Recipient {
+    address: String,
+    amount: serde_json::Value,
+}
+
+/// reads `[{"address": .., "amount": ..}]` JSON or `address,amount` CSV lines,
+/// empty lines, `#` comments and an `address,amount` header are skipped in CSV
+fn read_recipients(path: &str) -> Result<Vec<(String, Amount)>, failure::Error> {
+    let content = std::fs::read_to_string(path)?;
+    if content.trim_start().starts_with('[') {
+        let recipients: Vec<Recipient> = serde_json::from_str(&content)?;
+        let mut parsed = Vec::new();
+        for recipient in recipients {
+            // amounts are coins, written as a number or a decimal string
+            let amount = match &recipient.amount {
+                serde_json::Value::String(amount) => amount.parse()?,
+                amount => amount.to_string().parse()?,
+            };
+            parsed.push((recipient.address, amount));
+        }
+        return Ok(parsed);
+    }
+
+    let mut recipients = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line == "address,amount" {
+            continue;
+        }
+        let (address, amount) = line
+            .split_once(',')
+            .ok_or_else(|| format_err!("line {} is not address,amount", number + 1))?;
+        let amount = amount
+            .parse::<Amount>()
+            .map_err(|e| format_err!("line {}: {}", number + 1, e))?;
+        recipients.push((address.trim().to_string(), amount));
+    }
+    Ok(recipients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{coins, TestChain};
+
+    #[test]
+    fn block_template_from_saved_mempool() {
+        let chain = TestChain::new(2);
+        let config = chain.config.clone();
+        let (alice, bob) = (chain.wallets[0].clone(), chain.wallets[1].clone());
+        // the command reads the chain from disk, not from the fixture's memory store
+        let bc = Blockchain::create_blockchain(alice.clone(), &config).unwrap();
+        let payment = TransactionBuilder::new(&alice, &UnspentTXUtil { chain: bc.clone() })
+            .pay_to(&bob, coins(10))
+            .unwrap()
+            .with_fee(Fee::PerByte(Amount::from_units(1)))
+            .build()
+            .unwrap();
+        // what a node writes after admitting the payment
+        let mut mempool = Mempool::new(config.mempool_policy().clone());
+        mempool
+            .add(payment.clone(), &bc, now_ms().unwrap())
+            .unwrap();
+        mempool.save(config.mempool_path()).unwrap();
+        drop(bc);
+
+        let template = block_template(&config, None, None).unwrap();
+        assert_eq!(template.height, 1);
+        assert_eq!(template.transactions.len(), 1);
+        assert_eq!(template.transactions[0].tx.id, payment.id);
+        let fee = template.transactions[0].fee;
+        assert_eq!(
+            template.coinbase_value,
+            config.params().subsidy(1).checked_add(fee).unwrap()
+        );
+        let template = block_template(&config, None, Some(1)).unwrap();
+        assert!(template.transactions.is_empty());
+    }
+}
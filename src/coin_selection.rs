@@ -0,0 +1,231 @@
+use failure::format_err;
+use rand::seq::SliceRandom;
+
+use crate::amount::Amount;
+use crate::utxo_set::OutPoint;
+
+/// Coin is an unspent output that can be used as an input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub outpoint: OutPoint,
+    pub value: Amount,
+}
+
+/// CoinSelector picks coins worth at least `target` out of `coins`
+/// it returns `None` when all of them together are not enough
+pub trait CoinSelector {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Vec<Coin>>;
+}
+
+/// selector for a name given on the command line
+pub fn selector_by_name(name: &str) -> Result<Box<dyn CoinSelector>, failure::Error> {
+    match name {
+        "largest" => Ok(Box::new(LargestFirst)),
+        "smallest" => Ok(Box::new(SmallestFirst)),
+        "exact" => Ok(Box::new(BranchAndBound::default())),
+        "random" => Ok(Box::new(RandomSelector)),
+        _ => Err(format_err!(
+            "unknown coin selection {}, use largest, smallest, exact or random",
+            name
+        )),
+    }
+}
+
+/// takes coins in the given order until `target` is reached
+fn accumulate<I: IntoIterator<Item = Coin>>(coins: I, target: Amount) -> Option<Vec<Coin>> {
+    let mut selected = Vec::new();
+    let mut total: u64 = 0;
+    for coin in coins {
+        if total >= target.units() {
+            break;
+        }
+        total += coin.value.units();
+        selected.push(coin);
+    }
+    if total >= target.units() {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
+/// LargestFirst spends the biggest coins first, using as few inputs as possible
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Vec<Coin>> {
+        let mut coins = coins.to_vec();
+        coins.sort_by_key(|coin| std::cmp::Reverse(coin.value));
+        accumulate(coins, target)
+    }
+}
+
+/// SmallestFirst spends the smallest coins first, consolidating dust
+pub struct SmallestFirst;
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Vec<Coin>> {
+        let mut coins = coins.to_vec();
+        coins.sort_by_key(|coin| coin.value);
+        accumulate(coins, target)
+    }
+}
+
+/// BranchAndBound searches for coins that add up to exactly `target`,
+/// so the transaction needs no change output
+/// it gives up after `max_tries` steps and falls back to `LargestFirst`
+pub struct BranchAndBound {
+    pub max_tries: usize,
+}
+
+impl Default for BranchAndBound {
+    fn default() -> Self {
+        BranchAndBound { max_tries: 100_000 }
+    }
+}
+
+impl BranchAndBound {
+    /// depth first over the coins sorted largest first, each coin is either
+    /// taken or skipped, branches that can no longer reach the target are cut
+    fn exact_match(
+        coins: &[Coin],
+        index: usize,
+        remaining: u64,
+        available: u64,
+        tries: &mut usize,
+        picked: &mut Vec<usize>,
+    ) -> bool {
+        if remaining == 0 {
+            return true;
+        }
+        if index == coins.len() || available < remaining || *tries == 0 {
+            return false;
+        }
+        *tries -= 1;
+        let value = coins[index].value.units();
+        let available = available - value;
+        if value <= remaining {
+            picked.push(index);
+            if Self::exact_match(
+                coins,
+                index + 1,
+                remaining - value,
+                available,
+                tries,
+                picked,
+            ) {
+                return true;
+            }
+            picked.pop();
+        }
+        Self::exact_match(coins, index + 1, remaining, available, tries, picked)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Vec<Coin>> {
+        let mut sorted: Vec<Coin> = coins
+            .iter()
+            .filter(|c| c.value > Amount::ZERO)
+            .cloned()
+            .collect();
+        sorted.sort_by_key(|coin| std::cmp::Reverse(coin.value));
+        let available = sorted.iter().map(|coin| coin.value.units()).sum();
+        let mut tries = self.max_tries;
+        let mut picked = Vec::new();
+        if Self::exact_match(
+            &sorted,
+            0,
+            target.units(),
+            available,
+            &mut tries,
+            &mut picked,
+        ) {
+            return Some(picked.into_iter().map(|i| sorted[i].clone()).collect());
+        }
+        LargestFirst.select(coins, target)
+    }
+}
+
+/// RandomSelector spends coins in random order, so the inputs of
+/// a transaction say less about the wallet they come from
+pub struct RandomSelector;
+
+impl CoinSelector for RandomSelector {
+    fn select(&self, coins: &[Coin], target: Amount) -> Option<Vec<Coin>> {
+        let mut coins = coins.to_vec();
+        coins.shuffle(&mut rand::thread_rng());
+        accumulate(coins, target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn units(units: u64) -> Amount {
+        Amount::from_units(units)
+    }
+
+    fn coins(values: &[u64]) -> Vec<Coin> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| Coin {
+                outpoint: OutPoint::new("tx", i as u32),
+                value: units(*value),
+            })
+            .collect()
+    }
+
+    fn values(selected: Option<Vec<Coin>>) -> Vec<u64> {
+        selected
+            .unwrap()
+            .iter()
+            .map(|coin| coin.value.units())
+            .collect()
+    }
+
+    #[test]
+    fn every_selector_meets_the_target() {
+        let coins = coins(&[7, 1, 20, 5, 2, 10]);
+        for name in ["largest", "smallest", "exact", "random"] {
+            let selector = selector_by_name(name).unwrap();
+            for target in [1, 3, 17, 30, 45] {
+                let total: u64 = values(selector.select(&coins, units(target))).iter().sum();
+                assert!(
+                    total >= target,
+                    "{} selected {} for {}",
+                    name,
+                    total,
+                    target
+                );
+            }
+            assert!(selector.select(&coins, units(46)).is_none());
+        }
+        assert!(selector_by_name("oldest").is_err());
+    }
+
+    #[test]
+    fn selection_order() {
+        let coins = coins(&[7, 1, 20, 5, 2, 10]);
+        assert_eq!(values(LargestFirst.select(&coins, units(21))), vec![20, 10]);
+        assert_eq!(
+            values(SmallestFirst.select(&coins, units(6))),
+            vec![1, 2, 5]
+        );
+    }
+
+    #[test]
+    fn branch_and_bound_finds_exact_match() {
+        let coins = coins(&[7, 1, 20, 5, 2, 10]);
+        let bnb = BranchAndBound::default();
+        assert_eq!(values(bnb.select(&coins, units(17))), vec![10, 7]);
+        assert_eq!(values(bnb.select(&coins, units(24))), vec![10, 7, 5, 2]);
+        assert_eq!(values(bnb.select(&coins, units(4))), vec![20]);
+
+        // without any tries it falls back to largest first
+        let bnb = BranchAndBound { max_tries: 0 };
+        assert_eq!(values(bnb.select(&coins, units(17))), vec![20]);
+    }
+}
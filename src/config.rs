@@ -0,0 +1,116 @@
+use std::path::PathBuf;
+
+use crate::amount::Amount;
+use crate::mempool::MempoolPolicy;
+use crate::r#const::{
+    COINBASE_MATURITY, DATADIR_ENV, DEFAULT_DATADIR, HALVING_INTERVAL, INITIAL_SUBSIDY,
+    MAX_BLOCK_SIZE, MAX_BLOCK_TXS, POW_LIMIT_BITS, RETARGET_INTERVAL, TARGET_BLOCK_TIME_MS,
+};
+
+/// ChainParams are the consensus rules every node of a network has to agree on
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    /// easiest allowed target in compact form, also used by the genesis block
+    pub pow_limit_bits: u32,
+    /// number of blocks whose timestamps are used to retarget the difficulty
+    pub retarget_interval: i32,
+    /// desired time between two blocks in milliseconds
+    pub target_block_time_ms: u64,
+    /// coinbase reward at height 0
+    pub initial_subsidy: Amount,
+    /// number of blocks after which the subsidy is halved
+    pub halving_interval: i32,
+    /// a coinbase output created at height h can be spent from height h + coinbase_maturity
+    pub coinbase_maturity: i32,
+    /// largest block in bytes, see `Block::size`
+    pub max_block_size: usize,
+    /// most transactions a block may hold, coinbase included
+    pub max_block_txs: usize,
+}
+
+impl ChainParams {
+    /// newly created coins a block at `height` may claim on top of its fees
+    pub fn subsidy(&self, height: i32) -> Amount {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return Amount::ZERO;
+        }
+        Amount::from_units(self.initial_subsidy.units() >> halvings)
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            pow_limit_bits: POW_LIMIT_BITS,
+            retarget_interval: RETARGET_INTERVAL,
+            target_block_time_ms: TARGET_BLOCK_TIME_MS,
+            initial_subsidy: INITIAL_SUBSIDY,
+            halving_interval: HALVING_INTERVAL,
+            coinbase_maturity: COINBASE_MATURITY,
+            max_block_size: MAX_BLOCK_SIZE,
+            max_block_txs: MAX_BLOCK_TXS,
+        }
+    }
+}
+
+/// ChainConfig holds the node local settings shared by every store
+/// each node, test or wallet should point at its own data directory
+/// so that several of them can run from a single checkout
+#[derive(Debug, Clone)]
+pub struct ChainConfig {
+    data_dir: PathBuf,
+    params: ChainParams,
+    mempool_policy: MempoolPolicy,
+}
+
+impl ChainConfig {
+    pub fn new<P: Into<PathBuf>>(data_dir: P) -> ChainConfig {
+        ChainConfig {
+            data_dir: data_dir.into(),
+            params: ChainParams::default(),
+            mempool_policy: MempoolPolicy::default(),
+        }
+    }
+
+    pub fn with_params(mut self, params: ChainParams) -> ChainConfig {
+        self.params = params;
+        self
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn mempool_policy(&self) -> &MempoolPolicy {
+        &self.mempool_policy
+    }
+
+    /// uses the directory from `CHAIN_RS_DATADIR` if set
+    /// otherwise falls back to `data` relative to the working directory
+    pub fn from_env() -> ChainConfig {
+        match std::env::var(DATADIR_ENV) {
+            Ok(dir) if !dir.is_empty() => ChainConfig::new(dir),
+            _ => ChainConfig::new(DEFAULT_DATADIR),
+        }
+    }
+
+    pub fn blocks_path(&self) -> PathBuf {
+        self.data_dir.join("blocks")
+    }
+
+    pub fn wallets_path(&self) -> PathBuf {
+        self.data_dir.join("wallets")
+    }
+
+    /// file the mempool is saved to on shutdown
+    pub fn mempool_path(&self) -> PathBuf {
+        self.data_dir.join("mempool.dat")
+    }
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig::from_env()
+    }
+}
@@ -1,5 +1,57 @@
+use crate::amount::Amount;
+
 pub const VERSION: i8 = 1;
-pub const DIFFICULTY: u32 = 4;
+/// version field written into new block headers
+pub const BLOCK_VERSION: u32 = VERSION as u32;
 pub const SIGNATURE_LEN: usize = 64;
 pub const ADDRESS: &str = "localhost:3000";
 pub const CMD_LEN: usize = 12;
+pub const DEFAULT_DATADIR: &str = "data";
+pub const DATADIR_ENV: &str = "CHAIN_RS_DATADIR";
+pub const MEDIAN_TIME_SPAN: usize = 11;
+pub const MAX_FUTURE_BLOCK_TIME_MS: u64 = 2 * 60 * 60 * 1000;
+/// easiest target, four leading hex zeros in the block hash
+pub const POW_LIMIT_BITS: u32 = 0x1f00ffff;
+pub const RETARGET_INTERVAL: i32 = 20;
+pub const TARGET_BLOCK_TIME_MS: u64 = 10 * 1000;
+/// units in one coin, amounts are stored in units
+pub const COIN: u64 = 100_000_000;
+/// no amount can be larger than all the coins that will ever exist
+pub const MAX_MONEY: u64 = 21_000_000 * COIN;
+/// coinbase reward of the first blocks, halved every `HALVING_INTERVAL` blocks
+pub const INITIAL_SUBSIDY: Amount = Amount::from_coins(100);
+pub const HALVING_INTERVAL: i32 = 1000;
+/// number of blocks a coinbase output has to wait before it can be spent
+pub const COINBASE_MATURITY: i32 = 10;
+/// largest block in bytes, header and coinbase included
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+/// most transactions a block may hold, coinbase included
+pub const MAX_BLOCK_TXS: usize = 10_000;
+/// room a block template leaves for the header and the coinbase
+pub const COINBASE_RESERVED_SIZE: usize = 1000;
+/// largest payload of a data output
+pub const MAX_DATA_OUTPUT_LEN: usize = 80;
+/// limits of the script interpreter
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+pub const MAX_PUSH_SIZE: usize = 520;
+pub const MAX_STACK_SIZE: usize = 1000;
+pub const MAX_MULTISIG_KEYS: usize = 20;
+/// lock times below this are block heights, from it on timestamps in milliseconds
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+/// an input with this sequence number opts out of the lock time of its transaction
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+/// a mempool transaction with an input up to this sequence number may be replaced
+/// by a conflicting one paying a higher fee
+pub const MAX_RBF_SEQUENCE: u32 = SEQUENCE_FINAL - 2;
+/// sequence numbers without the disable flag are relative locks, the low bits count
+/// blocks, or `SEQUENCE_TIME_UNIT_MS` periods when the type flag is set
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0xffff;
+pub const SEQUENCE_TIME_UNIT_MS: u64 = 512 * 1000;
+/// default mempool policy, see `MempoolPolicy`
+pub const MAX_MEMPOOL_SIZE: usize = 5_000_000;
+pub const MAX_STANDARD_TX_SIZE: usize = 100_000;
+pub const MIN_RELAY_FEE_RATE: Amount = Amount::from_units(1);
+pub const MAX_MEMPOOL_ANCESTORS: usize = 25;
+pub const MEMPOOL_EXPIRY_MS: u64 = 14 * 24 * 60 * 60 * 1000;
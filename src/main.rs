@@ -1,23 +1,39 @@
 use crate::cli::Cli;
 
+mod amount;
 mod block;
+mod block_header;
+mod block_store;
+mod block_template;
+mod block_validation;
 mod blockchain;
 mod blockchain_itr;
-mod blockchain_unspent_tx;
 mod cli;
+mod coin_selection;
+mod config;
 mod r#const;
 mod crypto;
+mod mempool;
 mod mergetx;
 mod message;
 mod message_parser;
 mod message_sender;
+mod miner;
 mod node;
 mod node_util;
 mod parser_util;
+mod pow;
+mod script;
+#[cfg(test)]
+mod test_util;
 mod transaction;
+mod transaction_builder;
 mod txs;
 mod unspent_tx_util;
 mod utils;
+mod utxo_set;
+mod utxo_snapshot;
+mod utxo_view;
 mod wallet;
 mod wallet_chain;
 
@@ -0,0 +1,1026 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use bincode::{deserialize_from, serialize_into};
+use failure::Fail;
+use log::debug;
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockchain::{Blockchain, ChainUpdate};
+use crate::r#const::{
+    MAX_MEMPOOL_ANCESTORS, MAX_MEMPOOL_SIZE, MAX_STANDARD_TX_SIZE, MEMPOOL_EXPIRY_MS,
+    MIN_RELAY_FEE_RATE,
+};
+use crate::transaction::Transaction;
+use crate::utxo_set::OutPoint;
+use crate::utxo_view::UtxoView;
+
+/// MempoolPolicy decides which unconfirmed transactions a node keeps and relays,
+/// unlike `ChainParams` every node may choose its own
+#[derive(Debug, Clone)]
+pub struct MempoolPolicy {
+    /// largest total size of the kept transactions in bytes
+    pub max_size: usize,
+    /// largest transaction accepted in bytes
+    pub max_tx_size: usize,
+    /// lowest fee for every byte a transaction has to pay
+    pub min_fee_rate: Amount,
+    /// longest chain of unconfirmed transactions, the new one included
+    pub max_ancestors: usize,
+    /// transactions not mined within this many milliseconds are dropped
+    pub expiry_ms: u64,
+}
+
+impl Default for MempoolPolicy {
+    fn default() -> Self {
+        MempoolPolicy {
+            max_size: MAX_MEMPOOL_SIZE,
+            max_tx_size: MAX_STANDARD_TX_SIZE,
+            min_fee_rate: MIN_RELAY_FEE_RATE,
+            max_ancestors: MAX_MEMPOOL_ANCESTORS,
+            expiry_ms: MEMPOOL_EXPIRY_MS,
+        }
+    }
+}
+
+/// reasons a transaction is not accepted into the mempool
+#[derive(Debug)]
+pub enum MempoolError {
+    AlreadyKnown(String),
+    NonStandard(String, &'static str),
+    UnspendableInput(String, u32),
+    InputsBelowOutputs(String),
+    InvalidScript(String),
+    FeeTooLow(String, Amount, Amount),
+    TooManyAncestors(String, usize),
+    Conflict(String, String),
+    ReplacementTooCheap(String, String),
+    MempoolFull(String),
+}
+
+impl Display for MempoolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown(txid) => write!(f, "tx {} is already in the mempool", txid),
+            MempoolError::NonStandard(txid, reason) => {
+                write!(f, "tx {} is not standard: {}", txid, reason)
+            }
+            MempoolError::UnspendableInput(txid, vout) => write!(
+                f,
+                "output {}:{} is unknown, spent or not mature yet",
+                txid, vout
+            ),
+            MempoolError::InputsBelowOutputs(txid) => {
+                write!(f, "tx {} spends more than its inputs are worth", txid)
+            }
+            MempoolError::InvalidScript(txid) => write!(
+                f,
+                "tx {} does not unlock the outputs it spends or is not final",
+                txid
+            ),
+            MempoolError::FeeTooLow(txid, fee, required) => write!(
+                f,
+                "tx {} pays a fee of {}, at least {} is required",
+                txid, fee, required
+            ),
+            MempoolError::TooManyAncestors(txid, count) => write!(
+                f,
+                "tx {} has {} unconfirmed ancestors, too many",
+                txid, count
+            ),
+            MempoolError::Conflict(txid, other) => write!(
+                f,
+                "tx {} conflicts with mempool tx {} that is not replaceable",
+                txid, other
+            ),
+            MempoolError::ReplacementTooCheap(txid, other) => write!(
+                f,
+                "tx {} does not pay enough to replace mempool tx {}",
+                txid, other
+            ),
+            MempoolError::MempoolFull(txid) => {
+                write!(f, "mempool is full, tx {} pays too little", txid)
+            }
+        }
+    }
+}
+
+impl Fail for MempoolError {}
+
+/// MempoolEntry is a transaction with what the mempool knows about it
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: Amount,
+    pub size: usize,
+    /// when the transaction entered the mempool, in milliseconds
+    pub time: u64,
+}
+
+/// compares `fee_a / size_a` with `fee_b / size_b` without rounding
+pub(crate) fn cmp_fee_rate(fee_a: Amount, size_a: usize, fee_b: Amount, size_b: usize) -> Ordering {
+    (fee_a.units() as u128 * size_b as u128).cmp(&(fee_b.units() as u128 * size_a as u128))
+}
+
+/// Mempool holds the valid transactions waiting to be mined
+/// a transaction may spend outputs of other mempool transactions, its ancestors,
+/// but every output is spent by at most one of them
+#[derive(Debug, Default)]
+pub struct Mempool {
+    policy: MempoolPolicy,
+    entries: HashMap<String, MempoolEntry>,
+    /// the mempool transaction spending each outpoint
+    spends: HashMap<OutPoint, String>,
+    size: usize,
+    /// valid transactions that can't be mined yet, kept until they are final
+    held: HashMap<String, MempoolEntry>,
+    held_size: usize,
+}
+
+impl Mempool {
+    pub fn new(policy: MempoolPolicy) -> Mempool {
+        Mempool {
+            policy,
+            ..Mempool::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// total size of the transactions in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    /// the held transaction `txid`, see `hold`
+    pub fn get_held(&self, txid: &str) -> Option<&MempoolEntry> {
+        self.held.get(txid)
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.entries.values().map(|entry| &entry.tx)
+    }
+
+    /// entries ordered by fee rate, highest first, ties go by txid to keep the order stable
+    pub fn by_fee_rate(&self) -> Vec<&MempoolEntry> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| {
+            cmp_fee_rate(b.fee, b.size, a.fee, a.size).then_with(|| a.tx.id.cmp(&b.tx.id))
+        });
+        entries
+    }
+
+    /// the mempool transactions `txid` spends outputs of, directly or not
+    pub fn ancestors(&self, txid: &str) -> HashSet<String> {
+        let mut found = HashSet::new();
+        let mut queue = vec![txid.to_string()];
+        while let Some(txid) = queue.pop() {
+            let entry = match self.entries.get(&txid) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            for vin in &entry.tx.input {
+                if self.entries.contains_key(&vin.txid) && found.insert(vin.txid.clone()) {
+                    queue.push(vin.txid.clone());
+                }
+            }
+        }
+        found
+    }
+
+    /// the mempool transactions spending outputs of `txid`, directly or not
+    pub fn descendants(&self, txid: &str) -> HashSet<String> {
+        let mut found = HashSet::new();
+        let mut queue = vec![txid.to_string()];
+        while let Some(txid) = queue.pop() {
+            let outputs = match self.entries.get(&txid) {
+                Some(entry) => entry.tx.output.len(),
+                None => continue,
+            };
+            for vout in 0..outputs {
+                if let Some(child) = self.spends.get(&OutPoint::new(&txid, vout as u32)) {
+                    if found.insert(child.clone()) {
+                        queue.push(child.clone());
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// inputs minus outputs of `tx`, the inputs are looked up in the mempool
+    /// and then in the UTXO set of `chain`
+    pub fn fee(&self, tx: &Transaction, chain: &Blockchain) -> Result<Amount, failure::Error> {
+        let height = chain.get_best_height()? + 1;
+        let mut input_value = Amount::ZERO;
+        for outpoint in tx.spent_outpoints() {
+            let unspendable =
+                || MempoolError::UnspendableInput(outpoint.txid.clone(), outpoint.vout);
+            let value = match self.entries.get(&outpoint.txid) {
+                Some(parent) => {
+                    parent
+                        .tx
+                        .output
+                        .get(outpoint.vout as usize)
+                        .filter(|out| !out.is_data())
+                        .ok_or_else(unspendable)?
+                        .value
+                }
+                None => {
+                    chain
+                        .store
+                        .get_utxo(&outpoint)?
+                        .filter(|entry| entry.is_spendable_at(height, chain.config().params()))
+                        .ok_or_else(unspendable)?
+                        .value
+                }
+            };
+            input_value = input_value
+                .checked_add(value)
+                .ok_or_else(|| MempoolError::InputsBelowOutputs(tx.id.clone()))?;
+        }
+        Ok(input_value
+            .checked_sub(tx.output_value()?)
+            .ok_or_else(|| MempoolError::InputsBelowOutputs(tx.id.clone()))?)
+    }
+
+    /// admits `tx`, which entered the mempool at `time`, if the policy allows it
+    /// conflicting transactions are replaced, with their descendants, when all of them
+    /// opt in to replacement and `tx` pays more in total and per byte than they do
+    /// returns the ids of the removed transactions
+    pub fn add(
+        &mut self,
+        tx: Transaction,
+        chain: &Blockchain,
+        time: u64,
+    ) -> Result<Vec<String>, failure::Error> {
+        if self.entries.contains_key(&tx.id) {
+            return Err(MempoolError::AlreadyKnown(tx.id.clone()).into());
+        }
+        let size = tx.size()?;
+        self.check_standard(&tx, size)?;
+
+        let mut conflicts: Vec<String> = Vec::new();
+        for outpoint in tx.spent_outpoints() {
+            if let Some(txid) = self.spends.get(&outpoint) {
+                if !conflicts.contains(txid) {
+                    conflicts.push(txid.clone());
+                }
+            }
+        }
+        let mut replaced: HashSet<String> = conflicts.iter().cloned().collect();
+        for txid in &conflicts {
+            replaced.extend(self.descendants(txid));
+        }
+        if let Some(vin) = tx.input.iter().find(|vin| replaced.contains(&vin.txid)) {
+            return Err(MempoolError::Conflict(tx.id.clone(), vin.txid.clone()).into());
+        }
+
+        let fee = self.check_fee(&tx, size, chain)?;
+        // signatures are checked before anything is replaced, the outputs the
+        // conflicting transactions spend count as unspent
+        if !chain.verify_transaction_in(&tx, &self.view(chain, &replaced))? {
+            return Err(MempoolError::InvalidScript(tx.id.clone()).into());
+        }
+
+        let mut ancestors = HashSet::new();
+        for vin in &tx.input {
+            if self.entries.contains_key(&vin.txid) {
+                ancestors.insert(vin.txid.clone());
+                ancestors.extend(self.ancestors(&vin.txid));
+            }
+        }
+        if ancestors.len() + 1 > self.policy.max_ancestors {
+            return Err(MempoolError::TooManyAncestors(tx.id.clone(), ancestors.len()).into());
+        }
+
+        let mut replaced_fee = Amount::ZERO;
+        for txid in &replaced {
+            replaced_fee = replaced_fee
+                .checked_add(self.entries[txid].fee)
+                .unwrap_or(Amount::MAX);
+        }
+        for txid in &conflicts {
+            let other = &self.entries[txid];
+            if !other.tx.is_replaceable() {
+                return Err(MempoolError::Conflict(tx.id.clone(), txid.clone()).into());
+            }
+            if fee <= replaced_fee
+                || cmp_fee_rate(fee, size, other.fee, other.size) != Ordering::Greater
+            {
+                return Err(MempoolError::ReplacementTooCheap(tx.id.clone(), txid.clone()).into());
+            }
+        }
+        // what is removed is kept until `tx` is sure to stay, a transaction that
+        // does not fit must not evict anything
+        let txid = tx.id.clone();
+        let mut removed: Vec<MempoolEntry> = replaced
+            .iter()
+            .filter_map(|txid| self.remove_entry(txid))
+            .collect();
+        self.insert_entry(MempoolEntry {
+            tx,
+            fee,
+            size,
+            time,
+        });
+        let held = self.remove_held(&txid);
+        removed.extend(self.trim());
+        if !self.entries.contains_key(&txid) {
+            for entry in removed.into_iter().filter(|entry| entry.tx.id != txid) {
+                self.insert_entry(entry);
+            }
+            if let Some(entry) = held {
+                self.held_size += entry.size;
+                self.held.insert(txid.clone(), entry);
+            }
+            return Err(MempoolError::MempoolFull(txid).into());
+        }
+        for other in &replaced {
+            debug!("tx {} replaces {}", txid, other);
+        }
+        Ok(removed.into_iter().map(|entry| entry.tx.id).collect())
+    }
+
+    /// keeps `tx`, which arrived at `time` but can't be mined yet, until `release`
+    /// finds it final, it is checked like in `add` except for its lock times and
+    /// held transactions only get the room the mempool leaves free
+    pub fn hold(
+        &mut self,
+        tx: Transaction,
+        chain: &Blockchain,
+        time: u64,
+    ) -> Result<(), failure::Error> {
+        if self.entries.contains_key(&tx.id) || self.held.contains_key(&tx.id) {
+            return Err(MempoolError::AlreadyKnown(tx.id.clone()).into());
+        }
+        let size = tx.size()?;
+        self.check_standard(&tx, size)?;
+        let fee = self.check_fee(&tx, size, chain)?;
+        if !chain.verify_inputs_in(&tx, &self.view(chain, &HashSet::new()))? {
+            return Err(MempoolError::InvalidScript(tx.id.clone()).into());
+        }
+        if self.size + self.held_size + size > self.policy.max_size {
+            return Err(MempoolError::MempoolFull(tx.id.clone()).into());
+        }
+        self.held_size += size;
+        self.held.insert(
+            tx.id.clone(),
+            MempoolEntry {
+                tx,
+                fee,
+                size,
+                time,
+            },
+        );
+        Ok(())
+    }
+
+    /// moves the held transactions that may go into the next block to the mempool,
+    /// oldest first, and returns their ids, those `add` refuses are dropped
+    pub fn release(&mut self, chain: &Blockchain) -> Result<Vec<String>, failure::Error> {
+        let mut ready = Vec::new();
+        for entry in self.held.values() {
+            if chain.is_final_transaction(&entry.tx)? {
+                ready.push((entry.time, entry.tx.id.clone()));
+            }
+        }
+        ready.sort();
+        let mut released = Vec::new();
+        for (_, txid) in ready {
+            let entry = match self.remove_held(&txid) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match self.add(entry.tx, chain, entry.time) {
+                Ok(_) => released.push(txid),
+                Err(e) => debug!("drop held tx {}: {}", txid, e),
+            }
+        }
+        Ok(released)
+    }
+
+    fn remove_held(&mut self, txid: &str) -> Option<MempoolEntry> {
+        let entry = self.held.remove(txid)?;
+        self.held_size -= entry.size;
+        Some(entry)
+    }
+
+    /// the fee of `tx` if it pays at least the minimum fee rate for its `size`
+    fn check_fee(
+        &self,
+        tx: &Transaction,
+        size: usize,
+        chain: &Blockchain,
+    ) -> Result<Amount, failure::Error> {
+        let fee = self.fee(tx, chain)?;
+        let required = self
+            .policy
+            .min_fee_rate
+            .checked_mul(size as u64)
+            .unwrap_or(Amount::MAX);
+        if fee < required {
+            return Err(MempoolError::FeeTooLow(tx.id.clone(), fee, required).into());
+        }
+        Ok(fee)
+    }
+
+    /// the chain with the mempool transactions on top, except the ones in `replaced`
+    fn view<'a>(&'a self, chain: &'a Blockchain, replaced: &HashSet<String>) -> UtxoView<'a> {
+        let mut view = UtxoView::new(chain);
+        for entry in self.entries.values() {
+            if !replaced.contains(&entry.tx.id) {
+                view.add_pending(&entry.tx);
+            }
+        }
+        view
+    }
+
+    /// standard transactions pay to public key hashes, script hashes or carry data,
+    /// and unlock their inputs with pushes only
+    fn check_standard(&self, tx: &Transaction, size: usize) -> Result<(), MempoolError> {
+        let non_standard = |reason| Err(MempoolError::NonStandard(tx.id.clone(), reason));
+        if tx.is_coinbase() {
+            return non_standard("coinbase");
+        }
+        if size > self.policy.max_tx_size {
+            return non_standard("too large");
+        }
+        if tx.input.iter().any(|vin| !vin.script_sig.is_push_only()) {
+            return non_standard("unlocking script does more than push data");
+        }
+        for out in &tx.output {
+            if out.is_data() {
+                continue;
+            }
+            if out.script_pubkey.address_hash().is_none() {
+                return non_standard("unknown output script");
+            }
+            if out.value == Amount::ZERO {
+                return non_standard("empty payment");
+            }
+        }
+        if tx.output.iter().filter(|out| out.is_data()).count() > 1 {
+            return non_standard("more than one data output");
+        }
+        Ok(())
+    }
+
+    /// evicts the transactions with the lowest fee rate, counting their descendants,
+    /// until the mempool fits into its size limit, returns the evicted entries
+    /// packages are ranked once, evicting one changes the packages of its ancestors
+    /// but not the order the rest are tried in
+    fn trim(&mut self) -> Vec<MempoolEntry> {
+        let mut evicted = Vec::new();
+        if self.size <= self.policy.max_size {
+            return evicted;
+        }
+        let mut packages: Vec<(Amount, usize, String)> = self
+            .entries
+            .keys()
+            .map(|txid| {
+                let (mut fee, mut size) = (Amount::ZERO, 0);
+                for member in self.descendants(txid).iter().chain(std::iter::once(txid)) {
+                    let entry = &self.entries[member];
+                    fee = fee.checked_add(entry.fee).unwrap_or(Amount::MAX);
+                    size += entry.size;
+                }
+                (fee, size, txid.clone())
+            })
+            .collect();
+        packages.sort_by(|(fee_a, size_a, a), (fee_b, size_b, b)| {
+            cmp_fee_rate(*fee_a, *size_a, *fee_b, *size_b).then_with(|| a.cmp(b))
+        });
+        for (_, _, txid) in packages {
+            if self.size <= self.policy.max_size {
+                break;
+            }
+            evicted.extend(self.remove_package(&txid));
+        }
+        evicted
+    }
+
+    fn insert_entry(&mut self, entry: MempoolEntry) {
+        for outpoint in entry.tx.spent_outpoints() {
+            self.spends.insert(outpoint, entry.tx.id.clone());
+        }
+        self.size += entry.size;
+        self.entries.insert(entry.tx.id.clone(), entry);
+    }
+
+    fn remove_entry(&mut self, txid: &str) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        for outpoint in entry.tx.spent_outpoints() {
+            self.spends.remove(&outpoint);
+        }
+        self.size -= entry.size;
+        Some(entry)
+    }
+
+    /// removes `txid` and every transaction spending its outputs, returns their entries
+    fn remove_package(&mut self, txid: &str) -> Vec<MempoolEntry> {
+        let mut members: Vec<String> = self.descendants(txid).into_iter().collect();
+        members.push(txid.to_string());
+        members
+            .iter()
+            .filter_map(|txid| self.remove_entry(txid))
+            .collect()
+    }
+
+    /// removes `txid` and every transaction spending its outputs, returns their ids
+    pub fn remove_with_descendants(&mut self, txid: &str) -> Vec<String> {
+        self.remove_package(txid)
+            .into_iter()
+            .map(|entry| entry.tx.id)
+            .collect()
+    }
+
+    /// drops the transactions mined in `block` and, with their descendants,
+    /// those spending the same outputs, children of mined transactions stay
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for tx in block.get_transaction() {
+            self.remove_entry(&tx.id);
+            for outpoint in tx.spent_outpoints() {
+                if let Some(txid) = self.spends.get(&outpoint).cloned() {
+                    debug!("drop tx {} conflicting with mined tx {}", txid, tx.id);
+                    self.remove_with_descendants(&txid);
+                }
+            }
+        }
+    }
+
+    /// drops the transactions that entered before `now - expiry_ms`, with their descendants,
+    /// and the held ones that arrived before then
+    pub fn expire(&mut self, now: u64) -> Vec<String> {
+        let deadline = now.saturating_sub(self.policy.expiry_ms);
+        let expired: Vec<String> = self
+            .entries
+            .values()
+            .filter(|entry| entry.time < deadline)
+            .map(|entry| entry.tx.id.clone())
+            .collect();
+        let mut removed: Vec<String> = expired
+            .iter()
+            .flat_map(|txid| self.remove_with_descendants(txid))
+            .collect();
+        let expired_held: Vec<String> = self
+            .held
+            .values()
+            .filter(|entry| entry.time < deadline)
+            .map(|entry| entry.tx.id.clone())
+            .collect();
+        for txid in expired_held {
+            self.remove_held(&txid);
+            removed.push(txid);
+        }
+        removed
+    }
+
+    /// follows the active chain after `update`: mined transactions leave the mempool
+    /// and those of disconnected blocks come back if they are valid on the new chain,
+    /// the ones that are not final yet are held
+    /// returns the ids of the held transactions of disconnected blocks
+    pub fn update(
+        &mut self,
+        update: &ChainUpdate,
+        chain: &Blockchain,
+        now: u64,
+    ) -> Result<Vec<String>, failure::Error> {
+        for block in &update.connected {
+            self.remove_for_block(block);
+            for tx in block.get_transaction() {
+                self.remove_held(&tx.id);
+            }
+        }
+        let mut held = Vec::new();
+        // from the fork point up, so parents come back before their children
+        for block in update.disconnected.iter().rev() {
+            for tx in block.get_transaction() {
+                if tx.is_coinbase() {
+                    continue;
+                }
+                if !chain.is_final_transaction(tx)? {
+                    match self.hold(tx.clone(), chain, now) {
+                        Ok(()) => held.push(tx.id.clone()),
+                        Err(e) => debug!("drop tx {} of a disconnected block: {}", tx.id, e),
+                    }
+                    continue;
+                }
+                if let Err(e) = self.add(tx.clone(), chain, now) {
+                    debug!("drop tx {} of a disconnected block: {}", tx.id, e);
+                }
+            }
+        }
+        Ok(held)
+    }
+
+    /// writes the transactions with the time they entered to `path`, parents first,
+    /// and then the held ones
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), failure::Error> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_cached_key(|entry| (self.ancestors(&entry.tx.id).len(), &entry.tx.id));
+        let mut held: Vec<&MempoolEntry> = self.held.values().collect();
+        held.sort_by_key(|entry| (entry.time, &entry.tx.id));
+        entries.extend(held);
+        let saved: Vec<(&Transaction, u64)> = entries
+            .into_iter()
+            .map(|entry| (&entry.tx, entry.time))
+            .collect();
+        let mut file = BufWriter::new(File::create(path)?);
+        serialize_into(&mut file, &saved)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// adds the transactions saved at `path` that are still valid on `chain`
+    /// and not expired, those that are not final yet are held again,
+    /// a missing file is an empty mempool
+    /// returns how many transactions were added
+    pub fn load<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        chain: &Blockchain,
+        now: u64,
+    ) -> Result<usize, failure::Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(0);
+        }
+        let saved: Vec<(Transaction, u64)> = deserialize_from(BufReader::new(File::open(path)?))?;
+        let mut added = Vec::new();
+        for (tx, time) in saved {
+            let txid = tx.id.clone();
+            let kept = if chain.is_final_transaction(&tx)? {
+                self.add(tx, chain, time).map(|_| added.push(txid.clone()))
+            } else {
+                self.hold(tx, chain, time)
+            };
+            if let Err(e) = kept {
+                debug!("drop saved tx {}: {}", txid, e);
+            }
+        }
+        self.expire(now);
+        Ok(added
+            .iter()
+            .filter(|txid| self.entries.contains_key(*txid))
+            .count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Script;
+    use crate::test_util::{coinbase, coins, fork_from_genesis, spend_output, TestChain};
+    use crate::transaction::Fee;
+    use crate::transaction_builder::TransactionBuilder;
+    use crate::utils::now_ms;
+
+    #[test]
+    fn mempool_admission() {
+        let chain = TestChain::new(3);
+        let (alice, bob, carol) = (&chain.wallets[0], &chain.wallets[1], &chain.wallets[2]);
+        let (bc, config) = (&chain.bc, &chain.config);
+        let bob_key = chain.secret_key(bob);
+        let utxo = chain.utxo();
+        let now = now_ms().unwrap();
+        let mut mempool = Mempool::new(config.mempool_policy().clone());
+        let rejected = |result: Result<Vec<String>, failure::Error>| match result {
+            Ok(_) => panic!("transaction was accepted"),
+            Err(err) => match err.downcast::<MempoolError>() {
+                Ok(reason) => reason,
+                Err(err) => panic!("unexpected error {}", err),
+            },
+        };
+        // every payment spends the genesis coinbase, so they all conflict
+        let payment = |to: &str, fee: Fee| {
+            TransactionBuilder::new(alice, &utxo)
+                .pay_to(to, coins(10))
+                .unwrap()
+                .with_fee(fee)
+                .with_replaceable()
+                .build()
+                .unwrap()
+        };
+
+        let free = payment(bob, Fee::default());
+        assert!(matches!(
+            rejected(mempool.add(free, bc, now)),
+            MempoolError::FeeTooLow(..)
+        ));
+        assert!(matches!(
+            rejected(mempool.add(chain.coinbase(bob), bc, now)),
+            MempoolError::NonStandard(_, "coinbase")
+        ));
+        let parent = payment(bob, Fee::PerByte(Amount::from_units(1)));
+        assert!(mempool.add(parent.clone(), bc, now).unwrap().is_empty());
+        assert!(matches!(
+            rejected(mempool.add(parent.clone(), bc, now)),
+            MempoolError::AlreadyKnown(_)
+        ));
+
+        // bob spends the unconfirmed output paid to him
+        let child = spend_output(&parent, 0, &bob_key, carol, coins(1));
+        assert_eq!(mempool.fee(&child, bc).unwrap(), coins(1));
+        mempool.add(child.clone(), bc, now).unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(
+            mempool.ancestors(&child.id),
+            HashSet::from([parent.id.clone()])
+        );
+        assert_eq!(
+            mempool.descendants(&parent.id),
+            HashSet::from([child.id.clone()])
+        );
+        assert_eq!(mempool.by_fee_rate()[0].tx.id, child.id);
+        assert_eq!(
+            mempool.size(),
+            parent.size().unwrap() + child.size().unwrap()
+        );
+
+        // limits on chains of unconfirmed transactions and on the mempool size
+        let small = |policy: MempoolPolicy| {
+            let mut mempool = Mempool::new(policy);
+            mempool.add(parent.clone(), bc, now).unwrap();
+            rejected(mempool.add(child.clone(), bc, now))
+        };
+        assert!(matches!(
+            small(MempoolPolicy {
+                max_ancestors: 1,
+                ..MempoolPolicy::default()
+            }),
+            MempoolError::TooManyAncestors(_, 1)
+        ));
+        // the child pays most but is evicted with its cheap parent
+        assert!(matches!(
+            small(MempoolPolicy {
+                max_size: parent.size().unwrap(),
+                ..MempoolPolicy::default()
+            }),
+            MempoolError::MempoolFull(_)
+        ));
+
+        // a replacement has to pay more than the parent and the child it evicts together
+        let cheap = payment(carol, Fee::Absolute(coins(1)));
+        assert!(matches!(
+            rejected(mempool.add(cheap, bc, now)),
+            MempoolError::ReplacementTooCheap(..)
+        ));
+        let bump = TransactionBuilder::new(alice, &utxo)
+            .pay_to(carol, coins(10))
+            .unwrap()
+            .with_fee(Fee::Absolute(coins(2)))
+            .build()
+            .unwrap();
+        let replaced: HashSet<String> = mempool
+            .add(bump.clone(), bc, now)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(replaced, HashSet::from([parent.id.clone(), child.id]));
+        assert_eq!(mempool.len(), 1);
+        assert!(matches!(
+            rejected(mempool.add(payment(bob, Fee::Absolute(coins(3))), bc, now)),
+            MempoolError::Conflict(..)
+        ));
+
+        // saved transactions come back unless they expired meanwhile
+        let path = chain.path().join("mempool.dat");
+        mempool.save(&path).unwrap();
+        let mut restored = Mempool::new(config.mempool_policy().clone());
+        assert_eq!(restored.load(&path, bc, now).unwrap(), 1);
+        assert!(restored.get(&bump.id).is_some());
+        let expiry = config.mempool_policy().expiry_ms;
+        let mut restored = Mempool::new(config.mempool_policy().clone());
+        assert_eq!(restored.load(&path, bc, now + expiry + 1).unwrap(), 0);
+        assert_eq!(mempool.expire(now + expiry + 1), vec![bump.id]);
+        assert_eq!(mempool.size(), 0);
+    }
+
+    #[test]
+    fn mempool_follows_chain() {
+        let mut chain = TestChain::new(3);
+        let (alice, bob, carol) = (&chain.wallets[0], &chain.wallets[1], &chain.wallets[2]);
+        let config = chain.config.clone();
+        let utxo = chain.utxo();
+        let bc = &mut chain.bc;
+        let mut rival = fork_from_genesis(bc, &config);
+        let now = now_ms().unwrap();
+        let mut mempool = Mempool::new(config.mempool_policy().clone());
+        let payment = |to: &str| {
+            TransactionBuilder::new(alice, &utxo)
+                .pay_to(to, coins(10))
+                .unwrap()
+                .with_fee(Fee::PerByte(Amount::from_units(1)))
+                .build()
+                .unwrap()
+        };
+        let to_bob = payment(bob);
+        let to_carol = payment(carol);
+        mempool.add(to_carol.clone(), bc, now).unwrap();
+
+        // mining a conflicting payment drops the one in the mempool
+        let a1 = bc
+            .mine_block(vec![coinbase(alice, &config), to_bob.clone()])
+            .unwrap();
+        let update = ChainUpdate {
+            disconnected: Vec::new(),
+            connected: vec![a1],
+        };
+        assert!(mempool.update(&update, bc, now).unwrap().is_empty());
+        assert_eq!(mempool.len(), 0);
+
+        // a longer rival branch without it brings the mined payment back
+        rival.mine_block(vec![coinbase(alice, &config)]).unwrap();
+        let b2 = rival.mine_block(vec![coinbase(alice, &config)]).unwrap();
+        let b1 = rival.get_block(&b2.get_prev_block_hash()).unwrap();
+        bc.add_block(b1).unwrap();
+        let update = bc.add_block(b2).unwrap();
+        assert_eq!(update.disconnected.len(), 1);
+        assert!(mempool.update(&update, bc, now).unwrap().is_empty());
+        assert!(mempool.get(&to_bob.id).is_some());
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn forged_replacement() {
+        let chain = TestChain::new(3);
+        let (alice, bob, carol) = (&chain.wallets[0], &chain.wallets[1], &chain.wallets[2]);
+        let carol_key = chain.secret_key(carol);
+        let now = now_ms().unwrap();
+        let mut mempool = Mempool::new(chain.config.mempool_policy().clone());
+        let genuine = TransactionBuilder::new(alice, &chain.utxo())
+            .pay_to(bob, coins(10))
+            .unwrap()
+            .with_fee(Fee::PerByte(Amount::from_units(1)))
+            .with_replaceable()
+            .build()
+            .unwrap();
+        mempool.add(genuine.clone(), &chain.bc, now).unwrap();
+
+        // carol outbids the replaceable payment with alice's output, signed by her
+        // own key or not signed at all
+        let forged = spend_output(&chain.genesis_coinbase(), 0, &carol_key, carol, coins(1));
+        let mut unsigned = forged.clone();
+        unsigned.input[0].script_sig = Script::new();
+        for tx in [forged, unsigned] {
+            let err = mempool.add(tx, &chain.bc, now).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<MempoolError>(),
+                Some(MempoolError::InvalidScript(_))
+            ));
+        }
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&genuine.id).is_some());
+    }
+
+    #[test]
+    fn double_spends_leave_mempool_unchanged() {
+        let chain = TestChain::new(3);
+        let (alice, bob, carol) = (&chain.wallets[0], &chain.wallets[1], &chain.wallets[2]);
+        let (alice_key, carol_key) = (chain.secret_key(alice), chain.secret_key(carol));
+        let now = now_ms().unwrap();
+        let mut mempool = Mempool::new(chain.config.mempool_policy().clone());
+        let genuine = spend_output(&chain.genesis_coinbase(), 0, &alice_key, bob, coins(1));
+        mempool.add(genuine.clone(), &chain.bc, now).unwrap();
+
+        // a forged double spend fails on its signature before the conflict is looked at,
+        // a signed one can't replace a payment that did not opt in
+        let forged = spend_output(&chain.genesis_coinbase(), 0, &carol_key, carol, coins(2));
+        let signed = spend_output(&chain.genesis_coinbase(), 0, &alice_key, carol, coins(2));
+        let reason = |tx: Transaction, mempool: &mut Mempool| {
+            let err = mempool.add(tx, &chain.bc, now).unwrap_err();
+            match err.downcast::<MempoolError>() {
+                Ok(reason) => reason,
+                Err(err) => panic!("unexpected error {}", err),
+            }
+        };
+        assert!(matches!(
+            reason(forged, &mut mempool),
+            MempoolError::InvalidScript(_)
+        ));
+        assert!(matches!(
+            reason(signed, &mut mempool),
+            MempoolError::Conflict(_, txid) if txid == genuine.id
+        ));
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&genuine.id).is_some());
+    }
+
+    #[test]
+    fn held_transactions() {
+        let mut chain = TestChain::new(3);
+        let (alice, bob, carol) = (&chain.wallets[0], &chain.wallets[1], &chain.wallets[2]);
+        let carol_key = chain.secret_key(carol);
+        let config = chain.config.clone();
+        let now = now_ms().unwrap();
+        let mut mempool = Mempool::new(config.mempool_policy().clone());
+        // can't go into the next block, at height 1
+        let locked = TransactionBuilder::new(alice, &chain.utxo())
+            .pay_to(bob, coins(10))
+            .unwrap()
+            .with_fee(Fee::PerByte(Amount::from_units(1)))
+            .with_lock_time(1)
+            .build()
+            .unwrap();
+        assert!(!chain.bc.is_final_transaction(&locked).unwrap());
+
+        // held transactions are verified like mempool ones
+        let forged = spend_output(&chain.genesis_coinbase(), 0, &carol_key, carol, coins(1));
+        let err = mempool.hold(forged, &chain.bc, now).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MempoolError>(),
+            Some(MempoolError::InvalidScript(_))
+        ));
+        let mut small = Mempool::new(MempoolPolicy {
+            max_size: locked.size().unwrap() - 1,
+            ..config.mempool_policy().clone()
+        });
+        let err = small.hold(locked.clone(), &chain.bc, now).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MempoolError>(),
+            Some(MempoolError::MempoolFull(_))
+        ));
+
+        mempool.hold(locked.clone(), &chain.bc, now).unwrap();
+        assert!(mempool.hold(locked.clone(), &chain.bc, now).is_err());
+        assert!(mempool.get_held(&locked.id).is_some());
+        assert_eq!(mempool.len(), 0);
+        assert!(mempool.release(&chain.bc).unwrap().is_empty());
+
+        // they are saved with the mempool and expire like its transactions
+        let path = chain.path().join("mempool.dat");
+        mempool.save(&path).unwrap();
+        let mut restored = Mempool::new(config.mempool_policy().clone());
+        assert_eq!(restored.load(&path, &chain.bc, now).unwrap(), 0);
+        assert!(restored.get_held(&locked.id).is_some());
+        let expiry = config.mempool_policy().expiry_ms;
+        assert_eq!(restored.expire(now + expiry + 1), vec![locked.id.clone()]);
+        assert!(restored.get_held(&locked.id).is_none());
+
+        // and enter the mempool once the chain is long enough
+        chain.bc.mine_block(vec![coinbase(alice, &config)]).unwrap();
+        assert_eq!(mempool.release(&chain.bc).unwrap(), vec![locked.id.clone()]);
+        assert!(mempool.get_held(&locked.id).is_none());
+        assert!(mempool.get(&locked.id).is_some());
+    }
+
+    #[test]
+    fn rejected_replacement_evicts_nothing() {
+        let chain = TestChain::new(3);
+        let (alice, bob, carol) = (&chain.wallets[0], &chain.wallets[1], &chain.wallets[2]);
+        let now = now_ms().unwrap();
+        let original = TransactionBuilder::new(alice, &chain.utxo())
+            .pay_to(bob, coins(10))
+            .unwrap()
+            .with_fee(Fee::PerByte(Amount::from_units(1)))
+            .with_replaceable()
+            .build()
+            .unwrap();
+        let mut mempool = Mempool::new(MempoolPolicy {
+            max_size: original.size().unwrap(),
+            ..chain.config.mempool_policy().clone()
+        });
+        mempool.add(original.clone(), &chain.bc, now).unwrap();
+
+        // outbids the original but is too large to fit once it is gone
+        let larger = TransactionBuilder::new(alice, &chain.utxo())
+            .pay_to(bob, coins(10))
+            .unwrap()
+            .pay_to(carol, coins(10))
+            .unwrap()
+            .with_fee(Fee::Absolute(coins(1)))
+            .build()
+            .unwrap();
+        let err = mempool.add(larger, &chain.bc, now).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MempoolError>(),
+            Some(MempoolError::MempoolFull(_))
+        ));
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&original.id).is_some());
+        assert_eq!(mempool.size(), original.size().unwrap());
+        let replacement = spend_output(
+            &chain.genesis_coinbase(),
+            0,
+            &chain.secret_key(alice),
+            carol,
+            coins(1),
+        );
+        assert_eq!(
+            mempool.add(replacement, &chain.bc, now).unwrap(),
+            vec![original.id]
+        );
+    }
+}
@@ -1,7 +1,10 @@
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use merkle_cbt::merkle_tree::MerkleProof as CbmtProof;
 use serde::{Deserialize, Serialize};
 
+use crate::utils::hex_decode;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MergeTX {}
 
@@ -17,3 +20,23 @@ impl merkle_cbt::merkle_tree::Merge for MergeTX {
         re.to_vec()
     }
 }
+
+/// MerkleProof shows that a transaction is committed to by a merkle root
+/// `index` is the position of the leaf in the tree's node array
+/// and `lemmas` are the sibling hashes on the way up to the root
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub index: u32,
+    pub lemmas: Vec<Vec<u8>>,
+}
+
+/// checks that `txid` is part of the block whose header carries `root`
+/// only the header is needed, not the block body
+pub fn verify_merkle_proof(root: &[u8; 32], txid: &str, proof: &MerkleProof) -> bool {
+    let leaf = match hex_decode(txid) {
+        Ok(leaf) => leaf,
+        Err(_) => return false,
+    };
+    let proof = CbmtProof::<Vec<u8>, MergeTX>::new(vec![proof.index], proof.lemmas.clone());
+    proof.verify(&root.to_vec(), &[leaf])
+}
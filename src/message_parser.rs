@@ -5,7 +5,7 @@ use crate::message::{
 use crate::node::Node;
 use crate::r#const::ADDRESS;
 use crate::transaction::Transaction;
-use log::{debug, info};
+use log::info;
 
 impl Node {
     pub(crate) fn handle_version(&self, msg: VersionMessage) -> Result<(), failure::Error> {
@@ -35,7 +35,13 @@ impl Node {
 
     pub(crate) fn handle_block(&self, msg: BlockMessage) -> Result<(), failure::Error> {
         info!("receive block msg: {}, {}", msg.from, msg.block.get_hash());
-        self.add_block(msg.block)?;
+        let update = self.add_block(msg.block)?;
+        if !update.connected.is_empty() {
+            // a block we are mining would no longer extend the tip
+            self.miner.cancel();
+            self.update_mempool(&update)?;
+            self.release_final_txs()?;
+        }
 
         let mut in_transit = self.get_in_transit();
         if in_transit.len() > 0 {
@@ -43,8 +49,6 @@ impl Node {
             self.send_get_data(&msg.from, DataRequestType::Block, block_hash)?;
             in_transit.remove(0);
             self.replace_in_transit(in_transit);
-        } else {
-            self.utxo_reindex()?;
         }
 
         Ok(())
@@ -79,7 +83,7 @@ impl Node {
 
     pub(crate) fn handle_get_blocks(&self, msg: GetBlockMessage) -> Result<(), failure::Error> {
         info!("receive get blocks msg: {:#?}", msg);
-        let block_hashs = self.get_block_hashs();
+        let block_hashs = self.get_block_hashs()?;
         self.send_inv(&msg.from, "block", block_hashs)?;
         Ok(())
     }
@@ -100,9 +104,34 @@ impl Node {
         Ok(())
     }
 
+    /// moves held transactions that became final to the mempool,
+    /// the central node announces them like newly received ones
+    fn release_final_txs(&self) -> Result<(), failure::Error> {
+        let released = self.release_final()?;
+        if released.is_empty() || self.address != ADDRESS {
+            return Ok(());
+        }
+        for node in self.get_known_nodes() {
+            if node != self.address {
+                self.send_inv(&node, "tx", released.clone())?;
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn handle_tx(&self, msg: TxMessage) -> Result<(), failure::Error> {
         info!("receive tx msg: {} {}", msg.from, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
+        self.check_tx_fee(&msg.transaction)?;
+        // time locks pass with the clock, not only with new blocks
+        self.release_final_txs()?;
+        if !self.is_final_tx(&msg.transaction)? {
+            info!("hold tx {} until it is final", &msg.transaction.id);
+            self.hold_transaction(msg.transaction)?;
+            return Ok(());
+        }
+        for txid in self.insert_mempool(msg.transaction.clone())? {
+            info!("tx {} left the mempool for {}", txid, &msg.transaction.id);
+        }
         let known_nodes = self.get_known_nodes();
         if self.address == ADDRESS {
             for node in known_nodes {
@@ -110,38 +139,41 @@ impl Node {
                     self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
                 }
             }
-        } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.miner_address.is_empty() {
-                loop {
-                    let mut txs = Vec::new();
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
-                    if txs.is_empty() {
+        } else if !self.miner_address.is_empty() {
+            // mines until no mempool transaction is left that may go into the next block,
+            // mined ones leave the mempool when the block is added
+            loop {
+                // a block arriving from here on cancels the job
+                let job = self.miner.new_job();
+                let template = self.block_template()?;
+                if template.transactions.is_empty() {
+                    break;
+                }
+                let cbtx = Transaction::new_coinbase(
+                    self.miner_address.clone(),
+                    String::new(),
+                    template.coinbase_value,
+                    &self.get_config(),
+                )?;
+                // mine without holding the metadata lock so a competing block can cancel us
+                let new_block = match self
+                    .miner
+                    .mine_with_token(template.into_block(cbtx)?, &job)?
+                    .block
+                {
+                    Some(block) => block,
+                    None => {
+                        info!("mining cancelled, chain tip changed");
                         return Ok(());
                     }
-                    let cbtx =
-                        Transaction::new_coinbase(self.miner_address.clone(), String::new())?;
-                    txs.push(cbtx);
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
-                    for node in self.get_known_nodes() {
-                        if node != self.address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
-                    }
-                    if mempool.len() == 0 {
-                        break;
+                };
+                let update = self.add_block(new_block.clone())?;
+                self.update_mempool(&update)?;
+                for node in self.get_known_nodes() {
+                    if node != self.address {
+                        self.send_inv(&node, "block", vec![new_block.get_hash()])?;
                     }
                 }
-                self.clear_mempool();
             }
         }
         Ok(())
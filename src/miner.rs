@@ -0,0 +1,233 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::block::Block;
+use crate::pow::hash_meets_target;
+
+/// how many hashes a worker computes between two checks of the stop flags
+const CHECK_INTERVAL: u64 = 1024;
+
+/// CancelToken aborts a running mining job,
+/// clones share the same flag
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// MiningOutcome is the result of one mining job
+/// `block` is `None` when the job was cancelled
+#[derive(Debug)]
+pub struct MiningOutcome {
+    pub block: Option<Block>,
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningOutcome {
+    /// hashes per second over the whole job
+    pub fn hashrate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return self.hashes as f64;
+        }
+        self.hashes as f64 / secs
+    }
+}
+
+/// Miner searches the nonce space of a block with several worker threads
+/// each worker owns a slice of the u64 nonce space, when its slice is exhausted
+/// it bumps the coinbase extra nonce, which changes the merkle root in the header
+#[derive(Debug)]
+pub struct Miner {
+    threads: usize,
+    job: Mutex<CancelToken>,
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        let threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Miner::new(threads)
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            threads: threads.max(1),
+            job: Mutex::new(CancelToken::new()),
+        }
+    }
+
+    /// starts a job, `cancel` aborts mining with the returned token from now on
+    /// call it before the block is built so a new tip arriving meanwhile is not missed
+    pub fn new_job(&self) -> CancelToken {
+        let token = CancelToken::new();
+        *self.job.lock().unwrap() = token.clone();
+        token
+    }
+
+    /// aborts the job started last, if any
+    /// called when the chain tip changes under the miner
+    pub fn cancel(&self) {
+        self.job.lock().unwrap().cancel();
+    }
+
+    /// mines `block` until a valid nonce is found, see `new_job` to make it cancellable
+    pub fn mine(&self, block: Block) -> Result<MiningOutcome, failure::Error> {
+        self.mine_with_token(block, &CancelToken::new())
+    }
+
+    pub fn mine_with_token(
+        &self,
+        block: Block,
+        cancel: &CancelToken,
+    ) -> Result<MiningOutcome, failure::Error> {
+        let start = Instant::now();
+        let found = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+        let result: Mutex<Option<Block>> = Mutex::new(None);
+        let chunk = u64::MAX / self.threads as u64;
+
+        thread::scope(|scope| -> Result<(), failure::Error> {
+            let mut workers = Vec::new();
+            for worker in 0..self.threads as u64 {
+                let first = worker * chunk;
+                let last = if worker + 1 == self.threads as u64 {
+                    u64::MAX
+                } else {
+                    first + chunk - 1
+                };
+                let mut candidate = block.clone();
+                let (found, hashes, result) = (&found, &hashes, &result);
+                workers.push(scope.spawn(move || -> Result<(), failure::Error> {
+                    let mut extra_nonce = 0;
+                    loop {
+                        let mut nonce = first;
+                        loop {
+                            candidate.set_nonce(nonce);
+                            let hash = candidate.calculate_hash();
+                            if hash_meets_target(&hash, candidate.get_bits()) {
+                                if !found.swap(true, Ordering::SeqCst) {
+                                    candidate.set_hash(hash);
+                                    *result.lock().unwrap() = Some(candidate);
+                                }
+                                hashes.fetch_add(nonce - first + 1, Ordering::Relaxed);
+                                return Ok(());
+                            }
+                            if (nonce - first + 1).is_multiple_of(CHECK_INTERVAL)
+                                && (found.load(Ordering::Relaxed) || cancel.is_cancelled())
+                            {
+                                hashes.fetch_add(nonce - first + 1, Ordering::Relaxed);
+                                return Ok(());
+                            }
+                            if nonce == last {
+                                break;
+                            }
+                            nonce += 1;
+                        }
+                        hashes.fetch_add((last - first).saturating_add(1), Ordering::Relaxed);
+                        extra_nonce += 1;
+                        candidate.set_extra_nonce(extra_nonce)?;
+                    }
+                }));
+            }
+            for worker in workers {
+                worker.join().unwrap()?;
+            }
+            Ok(())
+        })?;
+
+        let outcome = MiningOutcome {
+            block: result.into_inner().unwrap(),
+            hashes: hashes.into_inner(),
+            elapsed: start.elapsed(),
+        };
+        match &outcome.block {
+            Some(b) => info!(
+                "mined block {} with {} threads, {} hashes at {:.0} H/s",
+                b.get_hash(),
+                self.threads,
+                outcome.hashes,
+                outcome.hashrate()
+            ),
+            None => info!(
+                "mining cancelled after {} hashes at {:.0} H/s",
+                outcome.hashes,
+                outcome.hashrate()
+            ),
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mine_easy_block() {
+        let block = Block::new(vec![], String::new(), 1, 0x207fffff).unwrap();
+        let outcome = Miner::new(4).mine(block).unwrap();
+        let mined = outcome.block.unwrap();
+        assert!(outcome.hashes >= 1);
+        assert_eq!(mined.calculate_hash(), mined.get_hash());
+        assert!(hash_meets_target(&mined.get_hash(), mined.get_bits()));
+    }
+
+    #[test]
+    fn cancel_mining() {
+        // a target no hash will ever meet
+        let block = Block::new(vec![], String::new(), 1, 0x01000001).unwrap();
+        let token = CancelToken::new();
+        let handle = {
+            let token = token.clone();
+            thread::spawn(move || Miner::new(2).mine_with_token(block, &token).unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        token.cancel();
+        let outcome = handle.join().unwrap();
+        assert!(outcome.block.is_none());
+        assert!(outcome.hashes > 0);
+    }
+
+    #[test]
+    fn cancel_job() {
+        let block = Block::new(vec![], String::new(), 1, 0x01000001).unwrap();
+        let miner = Arc::new(Miner::new(2));
+
+        // a tip arriving while the block is still being built
+        let token = miner.new_job();
+        miner.cancel();
+        let outcome = miner.mine_with_token(block.clone(), &token).unwrap();
+        assert!(outcome.block.is_none());
+
+        let token = miner.new_job();
+        let handle = {
+            let miner = miner.clone();
+            thread::spawn(move || miner.mine_with_token(block, &token).unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+        miner.cancel();
+        let outcome = handle.join().unwrap();
+        assert!(outcome.block.is_none());
+        assert!(outcome.hashes > 0);
+    }
+}
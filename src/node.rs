@@ -1,33 +1,48 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
 
 use log::info;
 
+use crate::mempool::Mempool;
 use crate::message::Message;
+use crate::miner::Miner;
 use crate::parser_util::bytes_to_cmd;
 use crate::r#const::ADDRESS;
-use crate::transaction::Transaction;
 use crate::unspent_tx_util::UnspentTXUtil;
+use crate::utils::now_ms;
 
 pub struct NetworkMetadata {
     pub(crate) known_nodes: HashSet<String>,
     pub(crate) unspent_tx: UnspentTXUtil,
     pub(crate) blocks_in_transit: Vec<String>,
-    pub(crate) mem_pool: HashMap<String, Transaction>,
+    pub(crate) mempool: Mempool,
+}
+
+impl NetworkMetadata {
+    /// saves the mempool to the data directory, `Node::new` loads it again
+    pub(crate) fn save_mempool(&self) -> Result<(), failure::Error> {
+        self.mempool
+            .save(self.unspent_tx.chain.config().mempool_path())
+    }
 }
 
 pub struct Node {
     pub(crate) address: String,
     pub(crate) miner_address: String,
     pub(crate) metadata: Arc<Mutex<NetworkMetadata>>,
+    pub(crate) miner: Miner,
 }
 
 impl Node {
     fn new(_port: &str, _miner_address: &str, utxo: UnspentTXUtil) -> Result<Node, failure::Error> {
         let mut node_set = HashSet::new();
         node_set.insert(String::from(ADDRESS));
+        let config = utxo.chain.config().clone();
+        let mut mempool = Mempool::new(config.mempool_policy().clone());
+        let loaded = mempool.load(config.mempool_path(), &utxo.chain, now_ms()?)?;
+        info!("loaded {} mempool transactions", loaded);
         let server = Node {
             address: "".to_string(),
             miner_address: "".to_string(),
@@ -35,8 +50,9 @@ impl Node {
                 known_nodes: node_set,
                 unspent_tx: utxo,
                 blocks_in_transit: vec![],
-                mem_pool: Default::default(),
+                mempool,
             })),
+            miner: Miner::default(),
         };
         Ok(server)
     }
@@ -75,3 +91,41 @@ impl Node {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::test_util::{coins, TestChain};
+    use crate::transaction::Fee;
+    use crate::transaction_builder::TransactionBuilder;
+
+    #[test]
+    fn mempool_survives_restart() {
+        let chain = TestChain::new(2);
+        let (alice, bob) = (&chain.wallets[0], &chain.wallets[1]);
+        let node = Node::new("", "", chain.utxo()).unwrap();
+        let utxo = chain.utxo();
+        let payment = |lock_time: u64, mempool: &Mempool| {
+            TransactionBuilder::new(alice, &utxo)
+                .pay_to(bob, coins(10))
+                .unwrap()
+                .with_fee(Fee::PerByte(Amount::from_units(1)))
+                .with_lock_time(lock_time)
+                .with_mempool(mempool)
+                .build()
+                .unwrap()
+        };
+        let paid = payment(0, &Mempool::default());
+        node.insert_mempool(paid.clone()).unwrap();
+        // spends the change of the payment, it can't be mined before height 1
+        let locked = payment(1, &node.metadata.lock().unwrap().mempool);
+        node.hold_transaction(locked.clone()).unwrap();
+        assert!(chain.config.mempool_path().exists());
+
+        let restarted = Node::new("", "", chain.utxo()).unwrap();
+        assert!(restarted.get_mempool_tx(&paid.id).is_some());
+        let metadata = restarted.metadata.lock().unwrap();
+        assert!(metadata.mempool.get_held(&locked.id).is_some());
+    }
+}
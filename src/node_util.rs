@@ -1,7 +1,12 @@
 use crate::block::Block;
+use crate::block_template::{BlockTemplate, BlockTemplateBuilder};
+use crate::blockchain::ChainUpdate;
+use crate::config::ChainConfig;
 use crate::node::Node;
 use crate::transaction::Transaction;
-use std::collections::{HashMap, HashSet};
+use crate::utils::now_ms;
+use log::debug;
+use std::collections::HashSet;
 
 // Improvements
 // Remove pub(crate), dont know yet how to properly structure
@@ -41,88 +46,136 @@ impl Node {
         self.metadata.lock().unwrap().blocks_in_transit.clone()
     }
 
-    pub(crate) fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.metadata.lock().unwrap().mem_pool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
-        }
-    }
-
-    pub(crate) fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.metadata.lock().unwrap().mem_pool.clone()
-    }
-
-    pub(crate) fn insert_mempool(&self, tx: Transaction) {
+    pub(crate) fn get_mempool_tx(&self, txid: &str) -> Option<Transaction> {
         self.metadata
             .lock()
             .unwrap()
-            .mem_pool
-            .insert(tx.id.clone(), tx);
-    }
-
-    pub(crate) fn clear_mempool(&self) {
-        self.metadata.lock().unwrap().mem_pool.clear()
+            .mempool
+            .get(txid)
+            .map(|entry| entry.tx.clone())
+    }
+
+    /// the next block on top of the mempool, see `BlockTemplateBuilder`
+    pub(crate) fn block_template(&self) -> Result<BlockTemplate, failure::Error> {
+        let metadata = self.metadata.lock().unwrap();
+        BlockTemplateBuilder::new(&metadata.unspent_tx.chain)
+            .with_mempool(&metadata.mempool)
+            .build()
+    }
+
+    /// admits `tx` to the mempool, see `Mempool::add`, and saves it
+    /// returns the ids of the transactions it replaced or evicted
+    pub(crate) fn insert_mempool(&self, tx: Transaction) -> Result<Vec<String>, failure::Error> {
+        let mut metadata = self.metadata.lock().unwrap();
+        let metadata = &mut *metadata;
+        let removed = metadata
+            .mempool
+            .add(tx, &metadata.unspent_tx.chain, now_ms()?)?;
+        metadata.save_mempool()?;
+        Ok(removed)
+    }
+
+    /// moves the mempool along with the chain after a block was added,
+    /// transactions of disconnected blocks that are not final yet are held,
+    /// drops expired transactions and saves what is left
+    pub(crate) fn update_mempool(&self, update: &ChainUpdate) -> Result<(), failure::Error> {
+        let mut metadata = self.metadata.lock().unwrap();
+        let metadata = &mut *metadata;
+        let now = now_ms()?;
+        for txid in metadata
+            .mempool
+            .update(update, &metadata.unspent_tx.chain, now)?
+        {
+            debug!("hold tx {} until it is final", txid);
+        }
+        for txid in metadata.mempool.expire(now) {
+            debug!("tx {} expired", txid);
+        }
+        metadata.save_mempool()
+    }
+
+    /// keeps a transaction that can't be mined yet out of the mempool, see `Mempool::hold`
+    pub(crate) fn hold_transaction(&self, tx: Transaction) -> Result<(), failure::Error> {
+        let mut metadata = self.metadata.lock().unwrap();
+        let metadata = &mut *metadata;
+        metadata
+            .mempool
+            .hold(tx, &metadata.unspent_tx.chain, now_ms()?)?;
+        metadata.save_mempool()
+    }
+
+    /// moves the held transactions that may go into the next block to the mempool
+    /// and returns their ids
+    pub(crate) fn release_final(&self) -> Result<Vec<String>, failure::Error> {
+        let mut metadata = self.metadata.lock().unwrap();
+        let metadata = &mut *metadata;
+        let released = metadata.mempool.release(&metadata.unspent_tx.chain)?;
+        if !released.is_empty() {
+            metadata.save_mempool()?;
+        }
+        Ok(released)
     }
 
-    pub(crate) fn get_best_height(&self) -> Result<i32, failure::Error> {
+    pub(crate) fn is_final_tx(&self, tx: &Transaction) -> Result<bool, failure::Error> {
         self.metadata
             .lock()
             .unwrap()
             .unspent_tx
             .chain
-            .get_best_height()
+            .is_final_transaction(tx)
+    }
+
+    /// rejects transactions that spend more than their inputs,
+    /// which may be outputs of mempool transactions
+    pub(crate) fn check_tx_fee(&self, tx: &Transaction) -> Result<(), failure::Error> {
+        let metadata = self.metadata.lock().unwrap();
+        metadata.mempool.fee(tx, &metadata.unspent_tx.chain)?;
+        Ok(())
     }
 
-    pub(crate) fn get_block_hashs(&self) -> Vec<String> {
+    pub(crate) fn get_config(&self) -> ChainConfig {
         self.metadata
             .lock()
             .unwrap()
             .unspent_tx
             .chain
-            .get_block_hashs()
+            .config()
+            .clone()
     }
 
-    pub(crate) fn get_block(&self, block_hash: &str) -> Result<Block, failure::Error> {
+    pub(crate) fn get_best_height(&self) -> Result<i32, failure::Error> {
         self.metadata
             .lock()
             .unwrap()
             .unspent_tx
             .chain
-            .get_block(block_hash)
+            .get_best_height()
     }
 
-    pub(crate) fn verify_tx(&self, tx: &Transaction) -> Result<bool, failure::Error> {
+    pub(crate) fn get_block_hashs(&self) -> Result<Vec<String>, failure::Error> {
         self.metadata
             .lock()
             .unwrap()
             .unspent_tx
             .chain
-            .verify_transacton(tx)
+            .get_block_hashs()
     }
 
-    pub(crate) fn add_block(&self, block: Block) -> Result<(), failure::Error> {
+    pub(crate) fn get_block(&self, block_hash: &str) -> Result<Block, failure::Error> {
         self.metadata
             .lock()
             .unwrap()
             .unspent_tx
             .chain
-            .add_block(block)
+            .get_block(block_hash)
     }
 
-    pub(crate) fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block, failure::Error> {
+    pub(crate) fn add_block(&self, block: Block) -> Result<ChainUpdate, failure::Error> {
         self.metadata
             .lock()
             .unwrap()
             .unspent_tx
             .chain
-            .mine_block(txs)
-    }
-
-    pub(crate) fn unspent_tx_reindex(&self) -> Result<(), failure::Error> {
-        self.metadata.lock().unwrap().unspent_tx.reindex()
-    }
-
-    pub(crate) fn utxo_reindex(&self) -> Result<(), failure::Error> {
-        self.metadata.lock().unwrap().unspent_tx.reindex()
+            .add_block(block)
     }
 }
@@ -0,0 +1,134 @@
+use crate::utils::hex_decode;
+
+/// Target is a 256 bit unsigned number stored big endian
+/// a block hash, read as a number, has to be lower or equal to the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target([u8; 32]);
+
+impl Target {
+    pub const MAX: Target = Target([0xff; 32]);
+
+    /// decodes the compact "bits" representation used in block headers
+    /// the highest byte is the size of the number in bytes,
+    /// the lower three bytes are its most significant digits
+    pub fn from_compact(bits: u32) -> Target {
+        let size = (bits >> 24) as usize;
+        let mut word = bits & 0x007f_ffff;
+        let mut target = [0u8; 32];
+        if size <= 3 {
+            word >>= 8 * (3 - size);
+            target[29..].copy_from_slice(&word.to_be_bytes()[1..]);
+        } else if size > 32 {
+            return Target::MAX;
+        } else {
+            let start = 32 - size;
+            target[start..start + 3].copy_from_slice(&word.to_be_bytes()[1..]);
+        }
+        Target(target)
+    }
+
+    pub fn to_compact(self) -> u32 {
+        let mut size = match self.0.iter().position(|b| *b != 0) {
+            Some(first) => 32 - first,
+            None => return 0,
+        };
+        let mut word = if size <= 3 {
+            let mut bytes = [0u8; 4];
+            bytes[1..].copy_from_slice(&self.0[29..]);
+            u32::from_be_bytes(bytes) << (8 * (3 - size))
+        } else {
+            let start = 32 - size;
+            let mut bytes = [0u8; 4];
+            bytes[1..].copy_from_slice(&self.0[start..start + 3]);
+            u32::from_be_bytes(bytes)
+        };
+        // the top bit of the mantissa is a sign bit, keep it clear
+        if word & 0x0080_0000 != 0 {
+            word >>= 8;
+            size += 1;
+        }
+        word | (size as u32) << 24
+    }
+
+    /// reads a hex encoded block hash as a number
+    pub fn from_hash(hash: &str) -> Result<Target, failure::Error> {
+        let bytes = hex_decode(hash)?;
+        if bytes.len() != 32 {
+            return Err(failure::format_err!("hash {} is not 32 bytes long", hash));
+        }
+        let mut target = [0u8; 32];
+        target.copy_from_slice(&bytes);
+        Ok(Target(target))
+    }
+
+    /// multiplies by `num / den`, saturating at `Target::MAX`
+    /// the product is kept in 40 bytes so easy targets do not overflow before the division
+    pub fn scale(&self, num: u64, den: u64) -> Target {
+        let mut product = [0u8; 40];
+        let mut carry: u128 = 0;
+        for i in (0..40).rev() {
+            let digit = if i >= 8 { self.0[i - 8] as u128 } else { 0 };
+            let v = digit * num as u128 + carry;
+            product[i] = v as u8;
+            carry = v >> 8;
+        }
+
+        let mut quotient = [0u8; 40];
+        let mut rem: u128 = 0;
+        for i in 0..40 {
+            rem = (rem << 8) | product[i] as u128;
+            quotient[i] = (rem / den as u128) as u8;
+            rem %= den as u128;
+        }
+        if quotient[..8].iter().any(|b| *b != 0) {
+            return Target::MAX;
+        }
+        let mut target = [0u8; 32];
+        target.copy_from_slice(&quotient[8..]);
+        Target(target)
+    }
+
+    /// expected number of hashes needed to find a hash below the target
+    /// computed from the upper 128 bits, which is exact enough for chain work
+    pub fn work(&self) -> u128 {
+        let mut high = [0u8; 16];
+        high.copy_from_slice(&self.0[..16]);
+        let high = u128::from_be_bytes(high);
+        u128::MAX / high.saturating_add(1)
+    }
+}
+
+/// checks a hex encoded hash against the target encoded in `bits`
+pub fn hash_meets_target(hash: &str, bits: u32) -> bool {
+    match Target::from_hash(hash) {
+        Ok(value) => value <= Target::from_compact(bits),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_round_trip() {
+        for bits in [0x1f00ffff, 0x1d00ffff, 0x207fffff, 0x1b0404cb, 0x03123456] {
+            assert_eq!(Target::from_compact(bits).to_compact(), bits);
+        }
+        // four leading hex zeros, the old fixed difficulty
+        let target = Target::from_compact(0x1f00ffff);
+        assert!(target >= Target::from_hash(&format!("0000ffff{}", "0".repeat(56))).unwrap());
+        assert!(target < Target::from_hash(&format!("00010000{}", "0".repeat(56))).unwrap());
+    }
+
+    #[test]
+    fn scale_target() {
+        let target = Target::from_compact(0x1d00ffff);
+        assert_eq!(target.scale(4, 1).scale(1, 4), target);
+        assert_eq!(target.scale(1, 256).to_compact(), 0x1c00ffff);
+        assert_eq!(Target::MAX.scale(2, 1), Target::MAX);
+        let easy = Target::from_compact(0x207fffff);
+        assert_eq!(easy.scale(3, 4).to_compact(), 0x205fffff);
+        assert!(Target::from_compact(0x1c00ffff).work() > target.work());
+    }
+}
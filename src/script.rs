@@ -0,0 +1,958 @@
+use std::fmt;
+
+use bitcoincash_addr::{Address, HashType, Scheme};
+use crypto::ed25519;
+use failure::{format_err, Fail};
+use serde::{Deserialize, Serialize};
+
+use crate::r#const::{
+    LOCKTIME_THRESHOLD, MAX_MULTISIG_KEYS, MAX_PUSH_SIZE, MAX_SCRIPT_SIZE, MAX_STACK_SIZE,
+    SEQUENCE_FINAL, SIGNATURE_LEN,
+};
+use crate::transaction::Transaction;
+use crate::utils::{hash_pub_key, hex_encode};
+
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+
+const PUBLIC_KEY_LEN: usize = 32;
+/// length of public key and script hashes
+const HASH_LEN: usize = 20;
+/// lock times are numbers of at most six bytes, timestamps are in milliseconds
+const LOCK_TIME_LEN: usize = 6;
+const COUNT_LEN: usize = 4;
+
+/// Script is a program for a small stack machine, the opcodes follow bitcoin
+/// outputs carry a locking script and inputs the unlocking script that satisfies it
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Script(Vec<u8>);
+
+/// a single step of a script
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction<'a> {
+    Push(&'a [u8]),
+    Op(u8),
+}
+
+/// reasons a script fails to run
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScriptError {
+    ScriptTooLarge(usize),
+    BadPush,
+    PushTooLarge(usize),
+    StackUnderflow(u8),
+    StackOverflow,
+    NotPushOnly,
+    BadOpcode(u8),
+    OpReturn,
+    VerifyFailed(u8),
+    BadNumber,
+    BadKeyCount(i64),
+    BadSignatureCount(i64),
+    NegativeLockTime,
+    LockTimeNotReached(i64, u64),
+    FinalSequence,
+    EvalFalse,
+    SignatureHash(String),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::ScriptTooLarge(len) => write!(f, "script of {} bytes is too large", len),
+            ScriptError::BadPush => write!(f, "push runs past the end of the script"),
+            ScriptError::PushTooLarge(len) => write!(f, "push of {} bytes is too large", len),
+            ScriptError::StackUnderflow(op) => {
+                write!(f, "{} needs more items on the stack", opcode_name(*op))
+            }
+            ScriptError::StackOverflow => write!(f, "stack holds too many items"),
+            ScriptError::NotPushOnly => write!(f, "unlocking script may only push data"),
+            ScriptError::BadOpcode(op) => write!(f, "{} is not allowed", opcode_name(*op)),
+            ScriptError::OpReturn => write!(f, "OP_RETURN output can't be spent"),
+            ScriptError::VerifyFailed(op) => write!(f, "{} failed", opcode_name(*op)),
+            ScriptError::BadNumber => write!(f, "number is too long"),
+            ScriptError::BadKeyCount(n) => write!(f, "multisig with {} keys", n),
+            ScriptError::BadSignatureCount(m) => write!(f, "multisig with {} signatures", m),
+            ScriptError::NegativeLockTime => write!(f, "lock time is negative"),
+            ScriptError::LockTimeNotReached(lock_time, tx_lock_time) => write!(
+                f,
+                "lock time {} is not reached by transaction lock time {}",
+                lock_time, tx_lock_time
+            ),
+            ScriptError::FinalSequence => write!(f, "input opts out of the lock time"),
+            ScriptError::EvalFalse => write!(f, "script ended without a true value"),
+            ScriptError::SignatureHash(e) => write!(f, "signature hash failed: {}", e),
+        }
+    }
+}
+
+impl Fail for ScriptError {}
+
+/// what a script is checked against: the spending transaction and the input being unlocked
+pub struct ScriptContext<'a> {
+    pub tx: &'a Transaction,
+    pub input: usize,
+}
+
+impl Script {
+    pub fn new() -> Script {
+        Script(Vec::new())
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Script {
+        Script(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn push_opcode(mut self, op: u8) -> Script {
+        self.0.push(op);
+        self
+    }
+
+    /// pushes `data` with the shortest push opcode
+    pub fn push_data(mut self, data: &[u8]) -> Script {
+        let len = data.len();
+        if len < OP_PUSHDATA1 as usize {
+            self.0.push(len as u8);
+        } else if len <= u8::MAX as usize {
+            self.0.extend_from_slice(&[OP_PUSHDATA1, len as u8]);
+        } else if len <= u16::MAX as usize {
+            self.0.push(OP_PUSHDATA2);
+            self.0.extend_from_slice(&(len as u16).to_le_bytes());
+        } else {
+            self.0.push(OP_PUSHDATA4);
+            self.0.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    /// pushes `n`, small numbers have their own opcodes
+    pub fn push_int(self, n: i64) -> Script {
+        match n {
+            0 => self.push_opcode(OP_0),
+            -1 => self.push_opcode(OP_1NEGATE),
+            1..=16 => self.push_opcode(OP_1 + (n - 1) as u8),
+            _ => self.push_data(&encode_num(n)),
+        }
+    }
+
+    /// pays to the owner of `pub_key_hash`:
+    /// `OP_DUP OP_HASH160 <pub_key_hash> OP_EQUALVERIFY OP_CHECKSIG`
+    pub fn p2pkh(pub_key_hash: &[u8]) -> Script {
+        Script::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_data(pub_key_hash)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    /// unlocks a `p2pkh` output: `<signature> <public_key>`
+    pub fn p2pkh_unlock(signature: &[u8], public_key: &[u8]) -> Script {
+        Script::new().push_data(signature).push_data(public_key)
+    }
+
+    /// an unspendable output carrying `data`: `OP_RETURN <data>`
+    pub fn data(data: &[u8]) -> Script {
+        Script::new().push_opcode(OP_RETURN).push_data(data)
+    }
+
+    /// requires `required` signatures of `public_keys`:
+    /// `<required> <key 1> .. <key n> <n> OP_CHECKMULTISIG`
+    /// it is used as the redeem script of a `p2sh` output, so it has to fit in a push
+    pub fn multisig(required: usize, public_keys: &[Vec<u8>]) -> Result<Script, ScriptError> {
+        let n = public_keys.len();
+        if n == 0 || n > MAX_MULTISIG_KEYS {
+            return Err(ScriptError::BadKeyCount(n as i64));
+        }
+        if required == 0 || required > n {
+            return Err(ScriptError::BadSignatureCount(required as i64));
+        }
+        let script = public_keys
+            .iter()
+            .fold(Script::new().push_int(required as i64), |script, key| {
+                script.push_data(key)
+            })
+            .push_int(n as i64)
+            .push_opcode(OP_CHECKMULTISIG);
+        if script.0.len() > MAX_PUSH_SIZE {
+            return Err(ScriptError::PushTooLarge(script.0.len()));
+        }
+        Ok(script)
+    }
+
+    /// the required signature count and the public keys of a `multisig` script
+    pub fn multisig_keys(&self) -> Option<(usize, Vec<&[u8]>)> {
+        let instructions = self.instructions().ok()?;
+        let (last, rest) = instructions.split_last()?;
+        if *last != Instruction::Op(OP_CHECKMULTISIG) || rest.len() < 2 {
+            return None;
+        }
+        let required = small_int(rest[0])?;
+        let n = small_int(rest[rest.len() - 1])?;
+        let public_keys: Vec<&[u8]> = rest[1..rest.len() - 1]
+            .iter()
+            .map(|instruction| match instruction {
+                Instruction::Push(key) => Some(*key),
+                Instruction::Op(_) => None,
+            })
+            .collect::<Option<_>>()?;
+        if public_keys.len() != n || required == 0 || required > n {
+            return None;
+        }
+        Some((required, public_keys))
+    }
+
+    /// pays to whoever reveals a script hashing to `script_hash` and satisfies it:
+    /// `OP_HASH160 <script_hash> OP_EQUAL`
+    pub fn p2sh(script_hash: &[u8]) -> Script {
+        Script::new()
+            .push_opcode(OP_HASH160)
+            .push_data(script_hash)
+            .push_opcode(OP_EQUAL)
+    }
+
+    /// unlocks a `p2sh` output of a `multisig` redeem script,
+    /// `signatures` are in the order of the keys they belong to
+    pub fn multisig_unlock(signatures: &[Vec<u8>], redeem_script: &Script) -> Script {
+        signatures
+            .iter()
+            .fold(Script::new(), |script, signature| {
+                script.push_data(signature)
+            })
+            .push_data(&redeem_script.0)
+    }
+
+    /// splits the unlocking script of a `p2sh` output into
+    /// the pushes before the redeem script and the redeem script
+    pub fn split_redeem_script(&self) -> Option<(Vec<Vec<u8>>, Script)> {
+        let mut pushes = self
+            .instructions()
+            .ok()?
+            .into_iter()
+            .map(|instruction| match instruction {
+                Instruction::Push(data) => Some(data.to_vec()),
+                Instruction::Op(_) => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let redeem_script = Script(pushes.pop()?);
+        Some((pushes, redeem_script))
+    }
+
+    /// hash of the script, which `p2sh` outputs are locked to
+    pub fn hash(&self) -> Vec<u8> {
+        let mut hash = self.0.clone();
+        hash_pub_key(&mut hash);
+        hash
+    }
+
+    pub fn p2pkh_hash(&self) -> Option<&[u8]> {
+        match self.instructions().ok()?.as_slice() {
+            [Instruction::Op(OP_DUP), Instruction::Op(OP_HASH160), Instruction::Push(hash), Instruction::Op(OP_EQUALVERIFY), Instruction::Op(OP_CHECKSIG)]
+                if hash.len() == HASH_LEN =>
+            {
+                Some(*hash)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn p2sh_hash(&self) -> Option<&[u8]> {
+        match self.instructions().ok()?.as_slice() {
+            [Instruction::Op(OP_HASH160), Instruction::Push(hash), Instruction::Op(OP_EQUAL)]
+                if hash.len() == HASH_LEN =>
+            {
+                Some(*hash)
+            }
+            _ => None,
+        }
+    }
+
+    /// the hash an address of this script is made of,
+    /// the public key hash of a `p2pkh` script or the script hash of a `p2sh` one
+    pub fn address_hash(&self) -> Option<&[u8]> {
+        self.p2pkh_hash().or_else(|| self.p2sh_hash())
+    }
+
+    /// key addresses are locked with `p2pkh` and script addresses with `p2sh`
+    pub fn from_address(address: &str) -> Result<Script, failure::Error> {
+        let decoded = Address::decode(address)
+            .map_err(|e| format_err!("invalid address {}: {:?}", address, e))?;
+        if decoded.body.len() != HASH_LEN {
+            return Err(format_err!("invalid address {}", address));
+        }
+        Ok(match decoded.hash_type {
+            HashType::Key => Script::p2pkh(&decoded.body),
+            HashType::Script => Script::p2sh(&decoded.body),
+        })
+    }
+
+    /// the address `from_address` turns back into this script
+    pub fn to_address(&self) -> Option<String> {
+        let (hash_type, hash) = match (self.p2pkh_hash(), self.p2sh_hash()) {
+            (Some(hash), _) => (HashType::Key, hash),
+            (None, Some(hash)) => (HashType::Script, hash),
+            (None, None) => return None,
+        };
+        Address {
+            body: hash.to_vec(),
+            scheme: Scheme::Base58,
+            hash_type,
+            ..Default::default()
+        }
+        .encode()
+        .ok()
+    }
+
+    /// data outputs start with `OP_RETURN` and can never be spent
+    pub fn is_data(&self) -> bool {
+        self.0.first() == Some(&OP_RETURN)
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        match self.instructions() {
+            Ok(instructions) => instructions.iter().all(|instruction| match instruction {
+                Instruction::Push(_) => true,
+                Instruction::Op(op) => is_small_int(*op),
+            }),
+            Err(_) => false,
+        }
+    }
+
+    /// splits the script into pushes and opcodes
+    pub fn instructions(&self) -> Result<Vec<Instruction<'_>>, ScriptError> {
+        let mut instructions = Vec::new();
+        let mut rest = &self.0[..];
+        while let Some((&op, tail)) = rest.split_first() {
+            let (len, tail) = match op {
+                OP_0 => (0, tail),
+                0x01..=0x4b => (op as usize, tail),
+                OP_PUSHDATA1 => read_len(tail, 1)?,
+                OP_PUSHDATA2 => read_len(tail, 2)?,
+                OP_PUSHDATA4 => read_len(tail, 4)?,
+                _ => {
+                    instructions.push(Instruction::Op(op));
+                    rest = tail;
+                    continue;
+                }
+            };
+            if tail.len() < len {
+                return Err(ScriptError::BadPush);
+            }
+            let (data, tail) = tail.split_at(len);
+            instructions.push(Instruction::Push(data));
+            rest = tail;
+        }
+        Ok(instructions)
+    }
+}
+
+/// the script in assembly, pushes are shown in hex
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let instructions = match self.instructions() {
+            Ok(instructions) => instructions,
+            Err(_) => return write!(f, "<invalid {}>", hex_encode(&self.0)),
+        };
+        let asm: Vec<String> = instructions
+            .iter()
+            .map(|instruction| match instruction {
+                Instruction::Push([]) => "0".to_string(),
+                Instruction::Push(data) => hex_encode(data),
+                Instruction::Op(op) => opcode_name(*op),
+            })
+            .collect();
+        write!(f, "{}", asm.join(" "))
+    }
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Script({})", self)
+    }
+}
+
+fn read_len(bytes: &[u8], width: usize) -> Result<(usize, &[u8]), ScriptError> {
+    if bytes.len() < width {
+        return Err(ScriptError::BadPush);
+    }
+    let (len, rest) = bytes.split_at(width);
+    let len = len.iter().rev().fold(0, |n, b| n << 8 | *b as usize);
+    Ok((len, rest))
+}
+
+fn opcode_name(op: u8) -> String {
+    let name = match op {
+        OP_0 => "OP_0",
+        OP_1NEGATE => "OP_1NEGATE",
+        OP_1..=OP_16 => return format!("OP_{}", op - OP_1 + 1),
+        OP_VERIFY => "OP_VERIFY",
+        OP_RETURN => "OP_RETURN",
+        OP_DROP => "OP_DROP",
+        OP_DUP => "OP_DUP",
+        OP_EQUAL => "OP_EQUAL",
+        OP_EQUALVERIFY => "OP_EQUALVERIFY",
+        OP_HASH160 => "OP_HASH160",
+        OP_CHECKSIG => "OP_CHECKSIG",
+        OP_CHECKMULTISIG => "OP_CHECKMULTISIG",
+        OP_CHECKLOCKTIMEVERIFY => "OP_CHECKLOCKTIMEVERIFY",
+        _ => return format!("OP_UNKNOWN({:#04x})", op),
+    };
+    name.to_string()
+}
+
+fn is_small_int(op: u8) -> bool {
+    op == OP_1NEGATE || (OP_1..=OP_16).contains(&op)
+}
+
+/// the number pushed by `OP_1` to `OP_16`
+fn small_int(instruction: Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Op(op) if (OP_1..=OP_16).contains(&op) => Some((op - OP_1 + 1) as usize),
+        _ => None,
+    }
+}
+
+/// numbers are little endian with the sign in the top bit of the last byte, zero is empty
+fn encode_num(n: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        bytes.push(abs as u8);
+        abs >>= 8;
+    }
+    match bytes.last_mut() {
+        Some(last) if *last & 0x80 != 0 => bytes.push(if n < 0 { 0x80 } else { 0 }),
+        Some(last) if n < 0 => *last |= 0x80,
+        _ => {}
+    }
+    bytes
+}
+
+fn decode_num(bytes: &[u8], max_len: usize) -> Result<i64, ScriptError> {
+    if bytes.len() > max_len {
+        return Err(ScriptError::BadNumber);
+    }
+    let last = match bytes.last() {
+        Some(last) => *last,
+        None => return Ok(0),
+    };
+    let n = bytes
+        .iter()
+        .enumerate()
+        .fold(0i64, |n, (i, b)| n | (*b as i64) << (8 * i));
+    if last & 0x80 != 0 {
+        Ok(-(n & !(0x80 << (8 * (bytes.len() - 1)))))
+    } else {
+        Ok(n)
+    }
+}
+
+/// anything but zero or negative zero is true
+fn cast_to_bool(data: &[u8]) -> bool {
+    match data.split_last() {
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || *last & 0x7f != 0,
+        None => false,
+    }
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        Vec::new()
+    }
+}
+
+type Stack = Vec<Vec<u8>>;
+
+fn pop(stack: &mut Stack, op: u8) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::StackUnderflow(op))
+}
+
+/// the top `n` items in the order they were pushed
+fn pop_n(stack: &mut Stack, n: usize, op: u8) -> Result<Stack, ScriptError> {
+    if stack.len() < n {
+        return Err(ScriptError::StackUnderflow(op));
+    }
+    Ok(stack.split_off(stack.len() - n))
+}
+
+fn verify(stack: &mut Stack, op: u8) -> Result<(), ScriptError> {
+    if cast_to_bool(&pop(stack, op)?) {
+        Ok(())
+    } else {
+        Err(ScriptError::VerifyFailed(op))
+    }
+}
+
+/// runs the unlocking script, then the locking script on the stack it left
+/// the input is unlocked if a true value is on top of the stack at the end,
+/// for a `p2sh` output the redeem script pushed last has to succeed as well
+/// on the rest of the stack the unlocking script left
+pub fn verify_script(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    context: &ScriptContext,
+) -> Result<(), ScriptError> {
+    if !script_sig.is_push_only() {
+        return Err(ScriptError::NotPushOnly);
+    }
+    let mut stack = Stack::new();
+    eval(script_sig, &mut stack, context)?;
+    let mut redeem_stack = stack.clone();
+    eval(script_pubkey, &mut stack, context)?;
+    check_true(&stack)?;
+
+    if script_pubkey.p2sh_hash().is_some() {
+        let redeem_script = Script(pop(&mut redeem_stack, OP_HASH160)?);
+        eval(&redeem_script, &mut redeem_stack, context)?;
+        check_true(&redeem_stack)?;
+    }
+    Ok(())
+}
+
+fn check_true(stack: &Stack) -> Result<(), ScriptError> {
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _ => Err(ScriptError::EvalFalse),
+    }
+}
+
+fn eval(script: &Script, stack: &mut Stack, context: &ScriptContext) -> Result<(), ScriptError> {
+    if script.0.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptTooLarge(script.0.len()));
+    }
+    for instruction in script.instructions()? {
+        let op = match instruction {
+            Instruction::Push(data) => {
+                if data.len() > MAX_PUSH_SIZE {
+                    return Err(ScriptError::PushTooLarge(data.len()));
+                }
+                stack.push(data.to_vec());
+                continue;
+            }
+            Instruction::Op(op) => op,
+        };
+        match op {
+            OP_1NEGATE => stack.push(encode_num(-1)),
+            OP_1..=OP_16 => stack.push(encode_num((op - OP_1 + 1) as i64)),
+            OP_VERIFY => verify(stack, op)?,
+            OP_RETURN => return Err(ScriptError::OpReturn),
+            OP_DROP => {
+                pop(stack, op)?;
+            }
+            OP_DUP => {
+                let top = stack.last().ok_or(ScriptError::StackUnderflow(op))?.clone();
+                stack.push(top);
+            }
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let a = pop(stack, op)?;
+                let b = pop(stack, op)?;
+                stack.push(encode_bool(a == b));
+                if op == OP_EQUALVERIFY {
+                    verify(stack, op)?;
+                }
+            }
+            OP_HASH160 => {
+                let mut data = pop(stack, op)?;
+                hash_pub_key(&mut data);
+                stack.push(data);
+            }
+            OP_CHECKSIG => {
+                let public_key = pop(stack, op)?;
+                let signature = pop(stack, op)?;
+                let hash = signature_hash(script, context)?;
+                stack.push(encode_bool(check_signature(&hash, &signature, &public_key)));
+            }
+            OP_CHECKMULTISIG => {
+                let valid = check_multisig(stack, script, context)?;
+                stack.push(encode_bool(valid));
+            }
+            OP_CHECKLOCKTIMEVERIFY => {
+                let top = stack.last().ok_or(ScriptError::StackUnderflow(op))?;
+                let lock_time = decode_num(top, LOCK_TIME_LEN)?;
+                if lock_time < 0 {
+                    return Err(ScriptError::NegativeLockTime);
+                }
+                // the script only compares against the lock time of the transaction,
+                // which block validation enforces, so both have to be heights or times
+                let tx_lock_time = context.tx.lock_time;
+                let lock_time_kind = (lock_time as u64) < LOCKTIME_THRESHOLD;
+                if lock_time_kind != (tx_lock_time < LOCKTIME_THRESHOLD)
+                    || lock_time as u64 > tx_lock_time
+                {
+                    return Err(ScriptError::LockTimeNotReached(lock_time, tx_lock_time));
+                }
+                if context.tx.input[context.input].sequence == SEQUENCE_FINAL {
+                    return Err(ScriptError::FinalSequence);
+                }
+            }
+            _ => return Err(ScriptError::BadOpcode(op)),
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackOverflow);
+        }
+    }
+    Ok(())
+}
+
+/// `<sig 1> .. <sig m> m <key 1> .. <key n> n OP_CHECKMULTISIG`
+/// the signatures have to be in the same order as their keys
+fn check_multisig(
+    stack: &mut Stack,
+    script: &Script,
+    context: &ScriptContext,
+) -> Result<bool, ScriptError> {
+    let op = OP_CHECKMULTISIG;
+    let n = decode_num(&pop(stack, op)?, COUNT_LEN)?;
+    if n < 0 || n as usize > MAX_MULTISIG_KEYS {
+        return Err(ScriptError::BadKeyCount(n));
+    }
+    let public_keys = pop_n(stack, n as usize, op)?;
+    let m = decode_num(&pop(stack, op)?, COUNT_LEN)?;
+    if m < 0 || m > n {
+        return Err(ScriptError::BadSignatureCount(m));
+    }
+    let signatures = pop_n(stack, m as usize, op)?;
+    let hash = signature_hash(script, context)?;
+    let mut public_keys = public_keys.iter();
+    Ok(signatures.iter().all(|signature| {
+        public_keys.any(|public_key| check_signature(&hash, signature, public_key))
+    }))
+}
+
+fn signature_hash(script: &Script, context: &ScriptContext) -> Result<Vec<u8>, ScriptError> {
+    context
+        .tx
+        .signature_hash(context.input, script)
+        .map_err(|e| ScriptError::SignatureHash(e.to_string()))
+}
+
+/// malformed keys and signatures don't verify instead of failing the script
+pub fn check_signature(hash: &[u8], signature: &[u8], public_key: &[u8]) -> bool {
+    signature.len() == SIGNATURE_LEN
+        && public_key.len() == PUBLIC_KEY_LEN
+        && ed25519::verify(hash, public_key, signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::txs::{TXInput, TXOutput};
+
+    fn keypair(seed: u8) -> ([u8; 64], [u8; 32]) {
+        ed25519::keypair(&[seed; 32])
+    }
+
+    fn spending_tx() -> Transaction {
+        Transaction {
+            id: String::new(),
+            input: vec![TXInput {
+                txid: "prev".to_string(),
+                vout: 0,
+                script_sig: Script::new(),
+                sequence: SEQUENCE_FINAL,
+            }],
+            output: vec![TXOutput {
+                value: Amount::from_coins(1),
+                script_pubkey: Script::data(b"out"),
+            }],
+            lock_time: 0,
+        }
+    }
+
+    fn sign(tx: &Transaction, script_pubkey: &Script, secret_key: &[u8]) -> Vec<u8> {
+        let hash = tx.signature_hash(0, script_pubkey).unwrap();
+        ed25519::signature(&hash, secret_key).to_vec()
+    }
+
+    fn run(
+        tx: &Transaction,
+        script_sig: &Script,
+        script_pubkey: &Script,
+    ) -> Result<(), ScriptError> {
+        let context = ScriptContext { tx, input: 0 };
+        verify_script(script_sig, script_pubkey, &context)
+    }
+
+    #[test]
+    fn parse_and_numbers() {
+        for n in [
+            0,
+            1,
+            -1,
+            16,
+            17,
+            127,
+            128,
+            -128,
+            255,
+            256,
+            1 << 31,
+            -(1 << 31),
+        ] {
+            assert_eq!(decode_num(&encode_num(n), LOCK_TIME_LEN), Ok(n), "{}", n);
+        }
+        assert_eq!(encode_num(128), vec![0x80, 0]);
+        assert_eq!(encode_num(-1), vec![0x81]);
+        assert!(!cast_to_bool(&[0, 0x80]));
+        assert!(cast_to_bool(&[0, 1]));
+
+        let hash = [7u8; HASH_LEN];
+        let script = Script::p2pkh(&hash);
+        assert_eq!(script.address_hash(), Some(&hash[..]));
+        assert_eq!(
+            script.to_string(),
+            format!(
+                "OP_DUP OP_HASH160 {} OP_EQUALVERIFY OP_CHECKSIG",
+                hex_encode(&hash)
+            )
+        );
+        assert!(!script.is_push_only());
+        assert_eq!(Script::p2pkh(&[7u8; 4]).address_hash(), None);
+
+        let big = Script::new()
+            .push_data(&[1; 300])
+            .push_int(1000)
+            .push_int(3);
+        let instructions = big.instructions().unwrap();
+        assert_eq!(instructions[0], Instruction::Push(&[1; 300]));
+        assert_eq!(instructions[2], Instruction::Op(OP_1 + 2));
+        assert!(big.is_push_only());
+
+        assert!(Script::data(b"hello").is_data());
+        let truncated = Script::from_bytes(vec![5, 1, 2]);
+        assert_eq!(truncated.instructions(), Err(ScriptError::BadPush));
+        assert!(!truncated.is_push_only());
+    }
+
+    #[test]
+    fn pay_to_public_key_hash() {
+        let (secret_key, public_key) = keypair(1);
+        let (other_secret, other_public) = keypair(2);
+        let mut pub_key_hash = public_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+        let script_pubkey = Script::p2pkh(&pub_key_hash);
+        let tx = spending_tx();
+
+        let signature = sign(&tx, &script_pubkey, &secret_key);
+        let unlock = Script::p2pkh_unlock(&signature, &public_key);
+        assert_eq!(run(&tx, &unlock, &script_pubkey), Ok(()));
+
+        // the signature covers the outputs
+        let mut changed = tx.clone();
+        changed.output[0].value = Amount::from_coins(2);
+        assert_eq!(
+            run(&changed, &unlock, &script_pubkey),
+            Err(ScriptError::EvalFalse)
+        );
+
+        let wrong_key = Script::p2pkh_unlock(&signature, &other_public);
+        assert_eq!(
+            run(&tx, &wrong_key, &script_pubkey),
+            Err(ScriptError::VerifyFailed(OP_EQUALVERIFY))
+        );
+        let wrong_signature =
+            Script::p2pkh_unlock(&sign(&tx, &script_pubkey, &other_secret), &public_key);
+        assert_eq!(
+            run(&tx, &wrong_signature, &script_pubkey),
+            Err(ScriptError::EvalFalse)
+        );
+        let not_push = Script::new()
+            .push_data(&signature)
+            .push_data(&public_key)
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_DROP);
+        assert_eq!(
+            run(&tx, &not_push, &script_pubkey),
+            Err(ScriptError::NotPushOnly)
+        );
+        assert_eq!(
+            run(&tx, &Script::new(), &Script::data(b"x")),
+            Err(ScriptError::OpReturn)
+        );
+    }
+
+    #[test]
+    fn check_multisig_in_key_order() {
+        let keys: Vec<_> = (1..=3).map(keypair).collect();
+        let mut script_pubkey = Script::new().push_int(2);
+        for (_, public_key) in &keys {
+            script_pubkey = script_pubkey.push_data(public_key);
+        }
+        let script_pubkey = script_pubkey.push_int(3).push_opcode(OP_CHECKMULTISIG);
+        let tx = spending_tx();
+        let signatures: Vec<_> = keys
+            .iter()
+            .map(|(secret_key, _)| sign(&tx, &script_pubkey, secret_key))
+            .collect();
+        let unlock = |sigs: &[&Vec<u8>]| {
+            sigs.iter()
+                .fold(Script::new(), |script, sig| script.push_data(sig))
+        };
+
+        for sigs in [
+            [&signatures[0], &signatures[1]],
+            [&signatures[0], &signatures[2]],
+            [&signatures[1], &signatures[2]],
+        ] {
+            assert_eq!(run(&tx, &unlock(&sigs), &script_pubkey), Ok(()));
+        }
+        assert_eq!(
+            run(
+                &tx,
+                &unlock(&[&signatures[2], &signatures[0]]),
+                &script_pubkey
+            ),
+            Err(ScriptError::EvalFalse)
+        );
+        assert_eq!(
+            run(
+                &tx,
+                &unlock(&[&signatures[0], &signatures[0]]),
+                &script_pubkey
+            ),
+            Err(ScriptError::EvalFalse)
+        );
+        assert_eq!(
+            run(&tx, &unlock(&[&signatures[0]]), &script_pubkey),
+            Err(ScriptError::StackUnderflow(OP_CHECKMULTISIG))
+        );
+    }
+
+    #[test]
+    fn pay_to_script_hash() {
+        let keys: Vec<_> = (1..=3).map(keypair).collect();
+        let public_keys: Vec<Vec<u8>> = keys.iter().map(|(_, key)| key.to_vec()).collect();
+        let redeem_script = Script::multisig(2, &public_keys).unwrap();
+        let (required, found) = redeem_script.multisig_keys().unwrap();
+        assert_eq!((required, found.len()), (2, 3));
+        assert_eq!(found[1], &public_keys[1][..]);
+        assert!(Script::multisig(4, &public_keys).is_err());
+        assert!(Script::multisig(1, &vec![vec![0; 32]; 16]).is_err());
+
+        let script_pubkey = Script::p2sh(&redeem_script.hash());
+        let address = script_pubkey.to_address().unwrap();
+        assert_eq!(Script::from_address(&address).unwrap(), script_pubkey);
+        let key_script = Script::p2pkh(&[3; HASH_LEN]);
+        let key_address = key_script.to_address().unwrap();
+        assert_ne!(
+            key_address,
+            Script::p2sh(&[3; HASH_LEN]).to_address().unwrap()
+        );
+        assert_eq!(Script::from_address(&key_address).unwrap(), key_script);
+
+        let tx = spending_tx();
+        let signatures: Vec<_> = keys
+            .iter()
+            .map(|(secret_key, _)| sign(&tx, &redeem_script, secret_key))
+            .collect();
+        let unlock = |sigs: &[Vec<u8>]| Script::multisig_unlock(sigs, &redeem_script);
+        assert_eq!(
+            run(
+                &tx,
+                &unlock(&[signatures[0].clone(), signatures[2].clone()]),
+                &script_pubkey
+            ),
+            Ok(())
+        );
+        let (pushes, redeem) = unlock(&signatures[..2]).split_redeem_script().unwrap();
+        assert_eq!(
+            (pushes, redeem),
+            (signatures[..2].to_vec(), redeem_script.clone())
+        );
+        assert_eq!(
+            run(&tx, &unlock(&signatures[..1]), &script_pubkey),
+            Err(ScriptError::StackUnderflow(OP_CHECKMULTISIG))
+        );
+        assert_eq!(
+            run(
+                &tx,
+                &unlock(&[signatures[1].clone(), signatures[0].clone()]),
+                &script_pubkey
+            ),
+            Err(ScriptError::EvalFalse)
+        );
+        let other = Script::multisig(1, &public_keys).unwrap();
+        assert_eq!(
+            run(
+                &tx,
+                &Script::multisig_unlock(&signatures[..1], &other),
+                &script_pubkey
+            ),
+            Err(ScriptError::EvalFalse)
+        );
+    }
+
+    #[test]
+    fn check_lock_time_verify() {
+        let script_pubkey = Script::new()
+            .push_int(100)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_DROP)
+            .push_int(1);
+        let locked = |lock_time: u64, sequence: u32| {
+            let mut tx = spending_tx();
+            tx.lock_time = lock_time;
+            tx.input[0].sequence = sequence;
+            tx
+        };
+        let open = SEQUENCE_FINAL - 1;
+        assert_eq!(
+            run(&locked(99, open), &Script::new(), &script_pubkey),
+            Err(ScriptError::LockTimeNotReached(100, 99))
+        );
+        assert_eq!(
+            run(&locked(100, open), &Script::new(), &script_pubkey),
+            Ok(())
+        );
+        assert_eq!(
+            run(&locked(100, SEQUENCE_FINAL), &Script::new(), &script_pubkey),
+            Err(ScriptError::FinalSequence)
+        );
+        // a height lock can't be met by a time lock
+        let time = LOCKTIME_THRESHOLD + 100;
+        assert_eq!(
+            run(&locked(time, open), &Script::new(), &script_pubkey),
+            Err(ScriptError::LockTimeNotReached(100, time))
+        );
+        let time_lock = Script::new()
+            .push_int(time as i64)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY);
+        assert_eq!(run(&locked(time, open), &Script::new(), &time_lock), Ok(()));
+
+        let negative = Script::new()
+            .push_int(-5)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY);
+        assert_eq!(
+            run(&locked(100, open), &Script::new(), &negative),
+            Err(ScriptError::NegativeLockTime)
+        );
+        let empty = Script::new().push_opcode(OP_CHECKLOCKTIMEVERIFY);
+        assert_eq!(
+            run(&locked(100, open), &Script::new(), &empty),
+            Err(ScriptError::StackUnderflow(OP_CHECKLOCKTIMEVERIFY))
+        );
+    }
+}
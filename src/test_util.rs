@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::amount::Amount;
+use crate::block_store::{BlockStore, MemoryBlockStore, StoreBatch};
+use crate::blockchain::Blockchain;
+use crate::config::{ChainConfig, ChainParams};
+use crate::r#const::SEQUENCE_FINAL;
+use crate::script::Script;
+use crate::transaction::Transaction;
+use crate::txs::{TXInput, TXOutput};
+use crate::unspent_tx_util::UnspentTXUtil;
+use crate::utxo_set::UtxoOverlay;
+use crate::wallet_chain::WalletChain;
+
+/// chain params with a target so easy that mining is almost instant
+/// and coinbase outputs that can be spent in the next block
+pub fn test_params() -> ChainParams {
+    ChainParams {
+        pow_limit_bits: 0x207fffff,
+        coinbase_maturity: 1,
+        ..ChainParams::default()
+    }
+}
+
+pub fn coins(coins: u64) -> Amount {
+    Amount::from_coins(coins)
+}
+
+fn create_wallet(config: &ChainConfig) -> String {
+    let mut ws = WalletChain::new(config).unwrap();
+    let address = ws.create_wallet();
+    ws.save_all().unwrap();
+    address
+}
+
+pub fn coinbase(address: &str, config: &ChainConfig) -> Transaction {
+    Transaction::new_coinbase(address.to_string(), String::new(), coins(100), config).unwrap()
+}
+
+/// a signed transaction paying output `vout` of `parent` less `fee` to `to`
+pub fn spend_output(
+    parent: &Transaction,
+    vout: usize,
+    secret_key: &[u8],
+    to: &str,
+    fee: Amount,
+) -> Transaction {
+    let value = parent.output[vout].value.checked_sub(fee).unwrap();
+    let mut tx = Transaction {
+        id: String::new(),
+        input: vec![TXInput {
+            txid: parent.id.clone(),
+            vout: vout as i32,
+            script_sig: Script::new(),
+            sequence: SEQUENCE_FINAL,
+        }],
+        output: vec![TXOutput::new(value, to.to_string()).unwrap()],
+        lock_time: 0,
+    };
+    tx.id = tx.hash().unwrap();
+    let prev_txs = HashMap::from([(parent.id.clone(), parent.clone())]);
+    tx.sign(secret_key, prev_txs).unwrap();
+    tx
+}
+
+/// a chain in a fresh in-memory store sharing only the genesis block with `bc`
+pub fn fork_from_genesis(bc: &Blockchain, config: &ChainConfig) -> Blockchain {
+    let genesis = bc
+        .get_block(&bc.get_block_hashs().unwrap().pop().unwrap())
+        .unwrap();
+    let store = Arc::new(MemoryBlockStore::new());
+    let mut batch = StoreBatch::new();
+    batch.put_block(&genesis).unwrap();
+    batch.set_head(&genesis.get_hash());
+    batch.index_block(&genesis).unwrap();
+    batch.set_chain_work(&genesis.get_hash(), genesis.work());
+    let mut utxos = UtxoOverlay::new(store.as_ref());
+    let undo = utxos.connect_block(&genesis, config.params()).unwrap();
+    batch.set_undo(&genesis.get_hash(), &undo).unwrap();
+    utxos.write_to(&mut batch).unwrap();
+    store.write(batch).unwrap();
+    Blockchain::from_store(store, config).unwrap()
+}
+
+/// TestChain is a chain in memory with wallets in a temporary data directory,
+/// the first wallet owns the genesis coinbase
+pub struct TestChain {
+    pub config: ChainConfig,
+    pub bc: Blockchain,
+    pub wallets: Vec<String>,
+    dir: tempfile::TempDir,
+}
+
+impl TestChain {
+    pub fn new(wallets: usize) -> TestChain {
+        TestChain::with_params(test_params(), wallets)
+    }
+
+    pub fn with_params(params: ChainParams, wallets: usize) -> TestChain {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ChainConfig::new(dir.path()).with_params(params);
+        let wallets: Vec<String> = (0..wallets.max(1))
+            .map(|_| create_wallet(&config))
+            .collect();
+        let bc = Blockchain::create_with_store(
+            wallets[0].clone(),
+            Arc::new(MemoryBlockStore::new()),
+            &config,
+        )
+        .unwrap();
+        TestChain {
+            config,
+            bc,
+            wallets,
+            dir,
+        }
+    }
+
+    /// the data directory, removed when the chain is dropped
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn utxo(&self) -> UnspentTXUtil {
+        UnspentTXUtil {
+            chain: self.bc.clone(),
+        }
+    }
+
+    pub fn secret_key(&self, address: &str) -> Vec<u8> {
+        let ws = WalletChain::new(&self.config).unwrap();
+        ws.get_wallet(address).unwrap().secret_key.clone()
+    }
+
+    pub fn coinbase(&self, address: &str) -> Transaction {
+        coinbase(address, &self.config)
+    }
+
+    /// the coinbase of the genesis block, paying the first wallet
+    pub fn genesis_coinbase(&self) -> Transaction {
+        let genesis = self
+            .bc
+            .get_block(&self.bc.get_block_hashs().unwrap().pop().unwrap());
+        genesis.unwrap().get_transaction()[0].clone()
+    }
+}
@@ -1,21 +1,49 @@
 use std::collections::HashMap;
 
+use crate::amount::Amount;
+use crate::block_validation::BlockValidationError;
+use crate::coin_selection::CoinSelector;
+use crate::config::ChainConfig;
+use crate::r#const::{LOCKTIME_THRESHOLD, MAX_RBF_SEQUENCE, SEQUENCE_FINAL};
+use crate::script::{check_signature, verify_script, Instruction, Script, ScriptContext};
+use crate::transaction_builder::TransactionBuilder;
 use crate::txs::{TXInput, TXOutput};
 use crate::unspent_tx_util::UnspentTXUtil;
-use crate::utils::hash_pub_key;
+use crate::utils::hex_encode;
+use crate::utxo_set::OutPoint;
 use crate::wallet_chain::WalletChain;
 use crypto::digest::Digest;
 use crypto::ed25519;
 use crypto::sha2::Sha256;
 use failure::format_err;
-use log::error;
+use log::debug;
+use rand::rngs::OsRng;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 
+/// `lock_time` is the height, or from `LOCKTIME_THRESHOLD` on the timestamp in milliseconds,
+/// the transaction can't be mined at or before, zero for no lock
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub id: String,
     pub input: Vec<TXInput>,
     pub output: Vec<TXOutput>,
+    pub lock_time: u64,
+}
+
+/// Fee a new transaction pays to the miner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fee {
+    /// a fixed amount
+    Absolute(Amount),
+    /// an amount for every byte of the serialized transaction
+    PerByte(Amount),
+}
+
+impl Default for Fee {
+    fn default() -> Self {
+        Fee::Absolute(Amount::ZERO)
+    }
 }
 
 impl Transaction {
@@ -23,67 +51,78 @@ impl Transaction {
     /// first spendable output from an address is taken
     /// then the input to new transaction will constitute of outputs from previous transactions
     /// if access amount found the remaining goes back to sender's address
+    /// the fee is left out of the outputs and `selector` decides which outputs are spent,
+    /// see `TransactionBuilder` for payments to several outputs
     pub fn new(
         from: &str,
         to: &str,
-        amount: i32,
+        amount: Amount,
+        fee: Fee,
+        selector: &dyn CoinSelector,
         utxo: &UnspentTXUtil,
     ) -> Result<Transaction, failure::Error> {
-        let wallets = WalletChain::new()?;
-        let wallet = match wallets.get_wallet(from) {
-            Some(w) => w,
-            None => return Err(format_err!("from wallet not found")),
-        };
+        let wallets = WalletChain::new(utxo.chain.config())?;
         if let None = wallets.get_wallet(&to) {
             return Err(format_err!("to wallet not found"));
         };
 
-        let mut pub_key_hash = wallet.public_key.clone();
-        hash_pub_key(&mut pub_key_hash);
-
-        let mut input = Vec::new();
-        let acc_v = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
-        if acc_v.0 < amount {
-            error!("Not Enough balance");
-            return Err(format_err!(
-                "Not Enough balance: current balance {}",
-                acc_v.0
-            ));
-        }
-
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let tx_in = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(), // to be filled in sign phase
-                    pub_key: wallet.public_key.clone(),
-                };
-                input.push(tx_in);
-            }
-        }
+        TransactionBuilder::new(from, utxo)
+            .pay_to(to, amount)?
+            .with_fee(fee)
+            .with_coin_selector(selector)
+            .build()
+    }
 
-        let mut output = vec![TXOutput::new(amount, to.to_string())?];
-        if acc_v.0 > amount {
-            output.push(TXOutput::new(acc_v.0 - amount, from.to_string())?)
+    /// inputs minus outputs, the inputs are looked up in the UTXO set
+    /// fails with `InputsBelowOutputs` if the transaction spends more than it has
+    pub fn fee(&self, utxo: &UnspentTXUtil) -> Result<Amount, failure::Error> {
+        let output_value = self.output_value()?;
+        if self.is_coinbase() {
+            return Ok(Amount::ZERO);
+        }
+        let mut input_value = Amount::ZERO;
+        for vin in &self.input {
+            let entry = utxo
+                .chain
+                .store
+                .get_utxo(&OutPoint::new(&vin.txid, vin.vout as u32))?
+                .ok_or_else(|| format_err!("input {}:{} is not unspent", vin.txid, vin.vout))?;
+            input_value = input_value
+                .checked_add(entry.value)
+                .ok_or_else(|| BlockValidationError::BadAmount(self.id.clone()))?;
         }
+        Ok(input_value
+            .checked_sub(output_value)
+            .ok_or_else(|| BlockValidationError::InputsBelowOutputs(self.id.clone()))?)
+    }
 
-        let mut tx = Transaction {
-            id: String::new(),
-            input,
-            output,
-        };
-        tx.id = tx.hash()?;
-        utxo.chain.sign_transacton(&mut tx, &wallet.secret_key)?;
-        Ok(tx)
+    /// total of the outputs, fails with `BadAmount` above `MAX_MONEY`
+    pub fn output_value(&self) -> Result<Amount, failure::Error> {
+        Ok(Amount::checked_sum(self.output.iter().map(|out| out.value))
+            .ok_or_else(|| BlockValidationError::BadAmount(self.id.clone()))?)
     }
 
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction, failure::Error> {
+    /// size of the serialized transaction in bytes
+    pub fn size(&self) -> Result<usize, failure::Error> {
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
+    /// `reward` is the block subsidy plus the fees of the other transactions in the block
+    /// without `data` a random tag keeps coinbases paying the same reward apart,
+    /// a second one with the same id can't be connected while the first is unspent
+    pub fn new_coinbase(
+        to: String,
+        mut data: String,
+        reward: Amount,
+        config: &ChainConfig,
+    ) -> Result<Transaction, failure::Error> {
         if data == String::from("") {
-            data += &format!("Reward to '{}'", to);
+            let mut tag = [0u8; 8];
+            OsRng.fill_bytes(&mut tag);
+            data += &format!("Reward to '{}' {}", to, hex_encode(&tag));
         }
 
-        let wallets = WalletChain::new()?;
+        let wallets = WalletChain::new(config)?;
         if let None = wallets.get_wallet(&to) {
             return Err(format_err!("coinbase wallet not found"));
         }
@@ -93,10 +132,11 @@ impl Transaction {
             input: vec![TXInput {
                 txid: String::new(),
                 vout: -1,
-                signature: vec![],
-                pub_key: Vec::from(data.as_bytes()),
+                script_sig: Script::new().push_data(data.as_bytes()),
+                sequence: SEQUENCE_FINAL,
             }],
-            output: vec![TXOutput::new(100, to)?],
+            output: vec![TXOutput::new(reward, to)?],
+            lock_time: 0,
         };
         tx.id = tx.hash()?;
         Ok(tx)
@@ -106,6 +146,58 @@ impl Transaction {
         self.input.len() == 1 && self.input[0].txid.is_empty() && self.input[0].vout == -1
     }
 
+    /// whether the transaction may go into a block at `height` with timestamp `time`,
+    /// that is its lock time has passed or every input opts out with `SEQUENCE_FINAL`
+    pub fn is_final(&self, height: i32, time: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        let passed = if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time < height as u64
+        } else {
+            self.lock_time < time
+        };
+        passed || self.input.iter().all(|vin| vin.sequence == SEQUENCE_FINAL)
+    }
+
+    /// whether a conflicting transaction paying more may replace this one in the mempool
+    pub fn is_replaceable(&self) -> bool {
+        self.input
+            .iter()
+            .any(|vin| vin.sequence <= MAX_RBF_SEQUENCE)
+    }
+
+    /// the outputs the inputs spend, none for a coinbase
+    pub fn spent_outpoints(&self) -> Vec<OutPoint> {
+        if self.is_coinbase() {
+            return Vec::new();
+        }
+        self.input
+            .iter()
+            .map(|vin| OutPoint::new(&vin.txid, vin.vout as u32))
+            .collect()
+    }
+
+    /// coinbase inputs are never unlocked, so after the coinbase data their script
+    /// carries the extra nonce the miner bumps once the block nonce space is exhausted
+    pub(crate) fn set_extra_nonce(&mut self, extra_nonce: u64) -> Result<(), failure::Error> {
+        if !self.is_coinbase() {
+            return Err(format_err!("extra nonce is only allowed in a coinbase"));
+        }
+        let data = match self.input[0].script_sig.instructions()?.first() {
+            Some(Instruction::Push(data)) => data.to_vec(),
+            _ => Vec::new(),
+        };
+        self.input[0].script_sig = Script::new()
+            .push_data(&data)
+            .push_data(&extra_nonce.to_be_bytes());
+        self.id = String::new();
+        self.id = self.hash()?;
+        Ok(())
+    }
+
+    /// unlocks every input with a signature of `private_key`,
+    /// the spent outputs have to pay to the public key hash of that key
     pub fn sign(
         &mut self,
         private_key: &[u8],
@@ -114,88 +206,153 @@ impl Transaction {
         if self.is_coinbase() {
             return Ok(());
         }
-
-        for vin in &self.input {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
+        if private_key.len() != 64 {
+            return Err(format_err!("private key of {} bytes", private_key.len()));
         }
+        // the second half of an ed25519 secret key is its public key
+        let public_key = &private_key[32..];
 
-        let mut tx_copy = self.deep_copy();
-        for in_id in 0..tx_copy.input.len() {
-            let prev_Tx = prev_TXs.get(&tx_copy.input[in_id].txid).unwrap();
-            tx_copy.input[in_id].signature.clear();
-            tx_copy.input[in_id].pub_key = prev_Tx.output[tx_copy.input[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            // tx_copy.input[in_id].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            self.input[in_id].signature = signature.to_vec();
+        for index in 0..self.input.len() {
+            let script_pubkey = self.prev_output(index, &prev_TXs)?.script_pubkey.clone();
+            if script_pubkey.address_hash().is_none() {
+                return Err(format_err!(
+                    "input {} of {} does not pay to a public key hash",
+                    index,
+                    self.id
+                ));
+            }
+            let hash = self.signature_hash(index, &script_pubkey)?;
+            let signature = ed25519::signature(&hash, private_key);
+            self.input[index].script_sig = Script::p2pkh_unlock(&signature, public_key);
         }
         Ok(())
     }
 
-    fn deep_copy(&self) -> Transaction {
-        let mut ins = Vec::new();
-        let mut outs = Vec::new();
-
-        for v in &self.input {
-            ins.push(TXInput {
-                txid: v.txid.clone(),
-                vout: v.vout.clone(),
-                signature: Vec::new(),
-                pub_key: Vec::new(),
-            });
+    /// adds a signature of `private_key` to every input spending a multisig `p2sh` output
+    /// the unlocking script of such an input holds the signatures collected so far in
+    /// key order followed by the redeem script, so signers can pass the transaction on
+    /// returns the fewest signatures any of these inputs has now
+    pub fn sign_multisig(
+        &mut self,
+        private_key: &[u8],
+        prev_txs: HashMap<String, Transaction>,
+    ) -> Result<usize, failure::Error> {
+        if private_key.len() != 64 {
+            return Err(format_err!("private key of {} bytes", private_key.len()));
         }
+        let public_key = &private_key[32..];
+
+        let mut fewest: Option<usize> = None;
+        for index in 0..self.input.len() {
+            let script_pubkey = self.prev_output(index, &prev_txs)?.script_pubkey.clone();
+            let script_hash = match script_pubkey.p2sh_hash() {
+                Some(script_hash) => script_hash,
+                None => continue,
+            };
+            let (collected, redeem_script) = self.input[index]
+                .script_sig
+                .split_redeem_script()
+                .filter(|(_, redeem_script)| redeem_script.hash() == script_hash)
+                .ok_or_else(|| format_err!("input {} has no matching redeem script", index))?;
+            let (required, public_keys) = redeem_script
+                .multisig_keys()
+                .ok_or_else(|| format_err!("redeem script of input {} is not a multisig", index))?;
+            let position = public_keys
+                .iter()
+                .position(|key| *key == public_key)
+                .ok_or_else(|| format_err!("key is not part of the multisig of input {}", index))?;
 
-        for v in &self.output {
-            outs.push(TXOutput {
-                value: v.value,
-                pub_key_hash: v.pub_key_hash.clone(),
-            });
+            let hash = self.signature_hash(index, &redeem_script)?;
+            let mut signatures = vec![None; public_keys.len()];
+            for signature in collected {
+                if let Some(i) = public_keys
+                    .iter()
+                    .position(|key| check_signature(&hash, &signature, key))
+                {
+                    signatures[i] = Some(signature);
+                }
+            }
+            signatures[position] = Some(ed25519::signature(&hash, private_key).to_vec());
+            let signatures: Vec<Vec<u8>> =
+                signatures.into_iter().flatten().take(required).collect();
+
+            fewest = Some(fewest.map_or(signatures.len(), |n| n.min(signatures.len())));
+            self.input[index].script_sig = Script::multisig_unlock(&signatures, &redeem_script);
         }
+        fewest.ok_or_else(|| format_err!("{} spends no multisig output", self.id))
+    }
 
-        Transaction {
-            id: self.id.clone(),
-            input: ins,
-            output: outs,
+    /// the hash the signatures of input `index` are made over, the transaction with
+    /// every unlocking script cleared and `script_code` in place of the one of `index`
+    pub fn signature_hash(
+        &self,
+        index: usize,
+        script_code: &Script,
+    ) -> Result<Vec<u8>, failure::Error> {
+        let mut tx_copy = self.clone();
+        tx_copy.id = String::new();
+        for vin in &mut tx_copy.input {
+            vin.script_sig = Script::new();
         }
+        tx_copy
+            .input
+            .get_mut(index)
+            .ok_or_else(|| format_err!("input {} of {} does not exist", index, self.id))?
+            .script_sig = script_code.clone();
+
+        let data = bincode::serialize(&tx_copy)?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        let mut hash = vec![0; hasher.output_bytes()];
+        hasher.result(&mut hash);
+        Ok(hash)
+    }
+
+    /// the output input `index` spends, taken from `prev_txs`
+    fn prev_output<'a>(
+        &self,
+        index: usize,
+        prev_txs: &'a HashMap<String, Transaction>,
+    ) -> Result<&'a TXOutput, failure::Error> {
+        let vin = &self.input[index];
+        prev_txs
+            .get(&vin.txid)
+            .filter(|prev_tx| !prev_tx.id.is_empty())
+            .and_then(|prev_tx| prev_tx.output.get(usize::try_from(vin.vout).ok()?))
+            .ok_or_else(|| format_err!("ERROR: Previous transaction is not correct"))
     }
 
+    /// the id of the transaction, a hash of it with an empty id and, unless it is
+    /// a coinbase, without the signatures that are added once the id is known
     pub(crate) fn hash(&self) -> Result<String, failure::Error> {
-        let data = bincode::serialize(self)?;
+        let mut tx_copy = self.clone();
+        tx_copy.id = String::new();
+        if !tx_copy.is_coinbase() {
+            for vin in &mut tx_copy.input {
+                vin.script_sig = Script::new();
+            }
+        }
+        let data = bincode::serialize(&tx_copy)?;
         let mut hasher = Sha256::new();
         hasher.input(&data[..]);
         Ok(hasher.result_str())
     }
 
+    /// checks the output amounts and runs the scripts of every input
     pub fn verify(&self, prev_TXs: HashMap<String, Transaction>) -> Result<bool, failure::Error> {
+        self.output_value()?;
         if self.is_coinbase() {
             return Ok(true);
         }
 
-        for vin in &self.input {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
-        }
-
-        let mut tx_copy = self.deep_copy();
-        for in_id in 0..self.input.len() {
-            let prev_Tx = prev_TXs.get(&self.input[in_id].txid).unwrap();
-            tx_copy.input[in_id].signature.clear();
-            tx_copy.input[in_id].pub_key = prev_Tx.output[self.input[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.input[in_id].pub_key = Vec::new();
-
-            if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
-                &self.input[in_id].pub_key,
-                &self.input[in_id].signature,
-            ) {
+        for (index, vin) in self.input.iter().enumerate() {
+            let script_pubkey = &self.prev_output(index, &prev_TXs)?.script_pubkey;
+            let context = ScriptContext {
+                tx: self,
+                input: index,
+            };
+            if let Err(e) = verify_script(&vin.script_sig, script_pubkey, &context) {
+                debug!("input {} of {} is not unlocked: {}", index, self.id, e);
                 return Ok(false);
             }
         }
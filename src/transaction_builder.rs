@@ -0,0 +1,326 @@
+use std::collections::HashSet;
+
+use failure::format_err;
+use log::error;
+
+use crate::amount::Amount;
+use crate::coin_selection::{BranchAndBound, CoinSelector};
+use crate::mempool::Mempool;
+use crate::r#const::{MAX_RBF_SEQUENCE, SEQUENCE_FINAL, SIGNATURE_LEN};
+use crate::script::Script;
+use crate::transaction::{Fee, Transaction};
+use crate::txs::{RelativeLock, TXInput, TXOutput};
+use crate::unspent_tx_util::UnspentTXUtil;
+use crate::utxo_set::OutPoint;
+use crate::utxo_view::UtxoView;
+use crate::wallet::Wallet;
+use crate::wallet_chain::WalletChain;
+
+/// TransactionBuilder assembles a payment from one wallet to any number of outputs
+/// inputs are picked by a coin selector unless given explicitly, whatever is left
+/// after the outputs and the fee goes to the change address, the sender by default
+/// a multisig sender is a script address, its transaction is left for the co-signers
+pub struct TransactionBuilder<'a> {
+    utxo: &'a UnspentTXUtil,
+    from: String,
+    outputs: Vec<TXOutput>,
+    inputs: Vec<OutPoint>,
+    change_address: Option<String>,
+    fee: Fee,
+    selector: Option<&'a dyn CoinSelector>,
+    redeem_script: Option<Script>,
+    lock_time: u64,
+    relative_lock: Option<RelativeLock>,
+    replaceable: bool,
+    mempool: Option<&'a Mempool>,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    pub fn new(from: &str, utxo: &'a UnspentTXUtil) -> TransactionBuilder<'a> {
+        TransactionBuilder {
+            utxo,
+            from: from.to_string(),
+            outputs: Vec::new(),
+            inputs: Vec::new(),
+            change_address: None,
+            fee: Fee::default(),
+            selector: None,
+            redeem_script: None,
+            lock_time: 0,
+            relative_lock: None,
+            replaceable: false,
+            mempool: None,
+        }
+    }
+
+    /// adds an output paying `amount` to `address`
+    pub fn pay_to(mut self, address: &str, amount: Amount) -> Result<Self, failure::Error> {
+        if amount == Amount::ZERO || !amount.is_valid() {
+            return Err(format_err!(
+                "payment of {} to {} is out of range",
+                amount,
+                address
+            ));
+        }
+        self.outputs
+            .push(TXOutput::new(amount, address.to_string())?);
+        Ok(self)
+    }
+
+    /// adds an unspendable output carrying `data`
+    pub fn with_data(mut self, data: &[u8]) -> Result<Self, failure::Error> {
+        self.outputs.push(TXOutput::new_data(data)?);
+        Ok(self)
+    }
+
+    /// spends `outpoint`, once any input is given only the given inputs are spent
+    pub fn with_input(mut self, outpoint: OutPoint) -> Self {
+        self.inputs.push(outpoint);
+        self
+    }
+
+    pub fn with_change_address(mut self, address: &str) -> Self {
+        self.change_address = Some(address.to_string());
+        self
+    }
+
+    pub fn with_fee(mut self, fee: Fee) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    /// defaults to `BranchAndBound`, which avoids change when it can
+    pub fn with_coin_selector(mut self, selector: &'a dyn CoinSelector) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+
+    /// spends the outputs of the script address `from` with the multisig `redeem_script`
+    /// the built transaction carries no signatures, see `Transaction::sign_multisig`
+    pub fn with_redeem_script(mut self, redeem_script: Script) -> Self {
+        self.redeem_script = Some(redeem_script);
+        self
+    }
+
+    /// the transaction can't be mined at or before `lock_time`,
+    /// a height or from `LOCKTIME_THRESHOLD` on a timestamp in milliseconds
+    pub fn with_lock_time(mut self, lock_time: u64) -> Self {
+        self.lock_time = lock_time;
+        self
+    }
+
+    /// every input waits until the output it spends is as old as `lock`
+    pub fn with_relative_lock(mut self, lock: RelativeLock) -> Self {
+        self.relative_lock = Some(lock);
+        self
+    }
+
+    /// lets a conflicting transaction paying a higher fee replace this one in the mempool
+    pub fn with_replaceable(mut self) -> Self {
+        self.replaceable = true;
+        self
+    }
+
+    /// spends outputs of the unconfirmed transactions in `mempool` as well,
+    /// and none of the outputs they spend
+    pub fn with_mempool(mut self, mempool: &'a Mempool) -> Self {
+        self.mempool = Some(mempool);
+        self
+    }
+
+    /// the outputs the transaction may spend
+    fn view(&self) -> UtxoView<'a> {
+        let view = UtxoView::new(&self.utxo.chain);
+        match self.mempool {
+            Some(mempool) => view.with_mempool(mempool),
+            None => view,
+        }
+    }
+
+    /// selects the inputs, adds the change and signs the transaction
+    /// for a per byte fee the transaction is rebuilt until the fee covers its final size
+    pub fn build(&self) -> Result<Transaction, failure::Error> {
+        if self.outputs.iter().all(|out| out.is_data()) {
+            return Err(format_err!("transaction has no payment"));
+        }
+        let wallets = WalletChain::new(self.utxo.chain.config())?;
+        let wallet = match &self.redeem_script {
+            Some(redeem_script) => {
+                let from = Script::from_address(&self.from)?;
+                if from.p2sh_hash() != Some(&redeem_script.hash()[..]) {
+                    return Err(format_err!("redeem script does not match {}", self.from));
+                }
+                if redeem_script.multisig_keys().is_none() {
+                    return Err(format_err!("redeem script is not a multisig"));
+                }
+                None
+            }
+            None => Some(
+                wallets
+                    .get_wallet(&self.from)
+                    .ok_or_else(|| format_err!("from wallet not found"))?,
+            ),
+        };
+
+        let mut fee = match self.fee {
+            Fee::Absolute(value) => value,
+            Fee::PerByte(_) => Amount::ZERO,
+        };
+        loop {
+            let tx = self.build_with_fee(wallet, fee)?;
+            let rate = match self.fee {
+                Fee::Absolute(_) => return Ok(tx),
+                Fee::PerByte(rate) => rate,
+            };
+            let needed = rate
+                .checked_mul(self.signed_size(&tx)? as u64)
+                .ok_or_else(|| format_err!("fee rate {} is out of range", rate))?;
+            if needed <= fee {
+                return Ok(tx);
+            }
+            fee = needed;
+        }
+    }
+
+    /// size of `tx` once every multisig input has its signatures
+    fn signed_size(&self, tx: &Transaction) -> Result<usize, failure::Error> {
+        let required = self
+            .redeem_script
+            .as_ref()
+            .and_then(|redeem_script| redeem_script.multisig_keys())
+            .map_or(0, |(required, _)| required);
+        Ok(tx.size()? + tx.input.len() * required * (SIGNATURE_LEN + 1))
+    }
+
+    /// the wallet signs the transaction, without one it is a multisig spend
+    fn build_with_fee(
+        &self,
+        wallet: Option<&Wallet>,
+        fee: Amount,
+    ) -> Result<Transaction, failure::Error> {
+        let from = Script::from_address(&self.from)?;
+        let pub_key_hash = from
+            .address_hash()
+            .ok_or_else(|| format_err!("invalid address {}", self.from))?;
+
+        let total = Amount::checked_sum(self.outputs.iter().map(|out| out.value))
+            .and_then(|payments| payments.checked_add(fee))
+            .ok_or_else(|| format_err!("outputs and fee are above the money supply"))?;
+        let view = self.view();
+        let (available, spent) = if self.inputs.is_empty() {
+            let default = BranchAndBound::default();
+            let selector = self.selector.unwrap_or(&default);
+            let (available, spent) =
+                self.utxo
+                    .find_spendable_outputs(&view, pub_key_hash, total, selector)?;
+            let spent = spent
+                .into_iter()
+                .flat_map(|(txid, vouts)| {
+                    vouts
+                        .into_iter()
+                        .map(move |vout| OutPoint::new(&txid, vout as u32))
+                })
+                .collect();
+            (available, spent)
+        } else {
+            self.explicit_inputs(&view, pub_key_hash)?
+        };
+        if available < total {
+            error!("Not Enough balance");
+            return Err(format_err!(
+                "Not Enough balance: current balance {}",
+                available
+            ));
+        }
+
+        // inputs opt in to the lock time with any sequence number but the final one,
+        // relative locks are low enough to opt in to replacement as well
+        let sequence = match self.relative_lock {
+            Some(lock) => lock.to_sequence()?,
+            None if self.replaceable => MAX_RBF_SEQUENCE,
+            None if self.lock_time != 0 => SEQUENCE_FINAL - 1,
+            None => SEQUENCE_FINAL,
+        };
+        let mut input = Vec::new();
+        for outpoint in spent {
+            input.push(TXInput {
+                txid: outpoint.txid,
+                vout: outpoint.vout as i32,
+                script_sig: Script::new(), // to be filled in sign phase
+                sequence,
+            });
+        }
+
+        let mut output = self.outputs.clone();
+        if let Some(change) = available.checked_sub(total).filter(|c| *c > Amount::ZERO) {
+            let change_address = self.change_address.as_ref().unwrap_or(&self.from);
+            output.push(TXOutput::new(change, change_address.clone())?);
+        }
+
+        let mut tx = Transaction {
+            id: String::new(),
+            input,
+            output,
+            lock_time: self.lock_time,
+        };
+        tx.id = tx.hash()?;
+        match (wallet, &self.redeem_script) {
+            (Some(wallet), _) => {
+                let prev_txs = view.prev_txs(&tx)?;
+                tx.sign(&wallet.secret_key, prev_txs)?
+            }
+            (None, Some(redeem_script)) => {
+                for vin in &mut tx.input {
+                    vin.script_sig = Script::multisig_unlock(&[], redeem_script);
+                }
+            }
+            (None, None) => return Err(format_err!("from wallet not found")),
+        }
+        Ok(tx)
+    }
+
+    /// checks that the given inputs are unspent, owned by the sender and mature
+    fn explicit_inputs(
+        &self,
+        view: &UtxoView,
+        pub_key_hash: &[u8],
+    ) -> Result<(Amount, Vec<OutPoint>), failure::Error> {
+        let chain = &self.utxo.chain;
+        let height = chain.get_best_height()? + 1;
+        let mut available = Amount::ZERO;
+        let mut spent = Vec::new();
+        let mut seen = HashSet::new();
+        for outpoint in &self.inputs {
+            if !seen.insert(outpoint) {
+                return Err(format_err!(
+                    "input {}:{} is given twice",
+                    outpoint.txid,
+                    outpoint.vout
+                ));
+            }
+            let entry = view.get_utxo(outpoint)?.ok_or_else(|| {
+                format_err!("input {}:{} is not unspent", outpoint.txid, outpoint.vout)
+            })?;
+            if entry.script_pubkey.address_hash() != Some(pub_key_hash) {
+                return Err(format_err!(
+                    "input {}:{} does not belong to {}",
+                    outpoint.txid,
+                    outpoint.vout,
+                    self.from
+                ));
+            }
+            if !entry.is_spendable_at(height, chain.config().params()) {
+                return Err(format_err!(
+                    "input {}:{} is not mature yet",
+                    outpoint.txid,
+                    outpoint.vout
+                ));
+            }
+            available = available
+                .checked_add(entry.value)
+                .ok_or_else(|| format_err!("inputs are above the money supply"))?;
+            spent.push(outpoint.clone());
+        }
+        Ok((available, spent))
+    }
+}
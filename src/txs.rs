@@ -1,51 +1,102 @@
-use bitcoincash_addr::Address;
-
+use failure::format_err;
 use log::debug;
 use serde::{Deserialize, Serialize};
 
-use crate::utils::hash_pub_key;
+use crate::amount::Amount;
+use crate::r#const::{
+    MAX_DATA_OUTPUT_LEN, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_MASK,
+    SEQUENCE_LOCKTIME_TYPE_FLAG, SEQUENCE_TIME_UNIT_MS,
+};
+use crate::script::Script;
 
+/// TXInput spends an output, `script_sig` is the unlocking script
+/// that satisfies the locking script of that output
+/// `sequence` is `SEQUENCE_FINAL` unless the input takes part in a lock time
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
     pub txid: String,
     pub vout: i32,
-    pub signature: Vec<u8>,
-    pub pub_key: Vec<u8>,
+    pub script_sig: Script,
+    pub sequence: u32,
+}
+
+/// how old the output an input spends has to be, in blocks or milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelativeLock {
+    Blocks(u16),
+    Time(u64),
+}
+
+impl RelativeLock {
+    /// the sequence number encoding the lock, time is rounded up to whole periods
+    pub fn to_sequence(self) -> Result<u32, failure::Error> {
+        match self {
+            RelativeLock::Blocks(blocks) => Ok(blocks as u32),
+            RelativeLock::Time(ms) => {
+                let periods = ms.div_ceil(SEQUENCE_TIME_UNIT_MS);
+                if periods > SEQUENCE_LOCKTIME_MASK as u64 {
+                    return Err(format_err!("relative lock of {} ms is too long", ms));
+                }
+                Ok(SEQUENCE_LOCKTIME_TYPE_FLAG | periods as u32)
+            }
+        }
+    }
 }
 
-// recheck the logic
 impl TXInput {
-    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
-        let mut pubkeyhash = self.pub_key.clone();
-        hash_pub_key(&mut pubkeyhash);
-        pubkeyhash == pub_key_hash
+    pub fn relative_lock(&self) -> Option<RelativeLock> {
+        if self.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = self.sequence & SEQUENCE_LOCKTIME_MASK;
+        if self.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            Some(RelativeLock::Time(value as u64 * SEQUENCE_TIME_UNIT_MS))
+        } else {
+            Some(RelativeLock::Blocks(value as u16))
+        }
     }
 }
 
+/// TXOutput locks `value` with `script_pubkey`, key addresses are paid to their
+/// public key hash and script addresses to their script hash
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutput {
-    pub value: i32,
-    pub pub_key_hash: Vec<u8>,
+    pub value: Amount,
+    pub script_pubkey: Script,
 }
 
 impl TXOutput {
-    pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash == pub_key_hash
-    }
-
-    pub fn new(value: i32, address: String) -> Result<Self, failure::Error> {
+    pub fn new(value: Amount, address: String) -> Result<Self, failure::Error> {
         let mut txo = TXOutput {
             value,
-            pub_key_hash: Vec::new(),
+            script_pubkey: Script::new(),
         };
-        txo.lock(&address);
+        txo.lock(&address)?;
         Ok(txo)
     }
 
+    /// a zero value output carrying `data`, it is never added to the UTXO set
+    pub fn new_data(data: &[u8]) -> Result<Self, failure::Error> {
+        if data.len() > MAX_DATA_OUTPUT_LEN {
+            return Err(format_err!(
+                "data output of {} bytes is larger than {}",
+                data.len(),
+                MAX_DATA_OUTPUT_LEN
+            ));
+        }
+        Ok(TXOutput {
+            value: Amount::ZERO,
+            script_pubkey: Script::data(data),
+        })
+    }
+
+    pub fn is_data(&self) -> bool {
+        self.value == Amount::ZERO && self.script_pubkey.is_data()
+    }
+
     fn lock(&mut self, address: &str) -> Result<(), failure::Error> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
         debug!("lock,{}", address);
-        self.pub_key_hash = pub_key_hash;
+        self.script_pubkey = Script::from_address(address)?;
         Ok(())
     }
 }
@@ -1,10 +1,12 @@
-use crate::block::Block;
+use crate::amount::Amount;
+use crate::block_store::Tree;
 use crate::blockchain::Blockchain;
+use crate::coin_selection::{Coin, CoinSelector};
 use crate::txs::TXOutput;
-use bincode::{deserialize, serialize};
+use crate::utxo_set::OutPoint;
+use crate::utxo_view::UtxoView;
 use serde::Deserialize;
-use std::collections::HashMap;
-use std::fs::remove_dir_all;
+use std::collections::{HashMap, HashSet};
 use turbosql::Serialize;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -12,85 +14,64 @@ pub struct TxOutputs {
     pub outputs: Vec<TXOutput>,
 }
 
+/// UnspentTXUtil reads the UTXO set kept in the block store
+/// the set is updated together with every block written to the chain
 pub struct UnspentTXUtil {
     pub chain: Blockchain,
 }
 
 impl UnspentTXUtil {
+    /// rebuilds the UTXO set from the blocks of the active chain
     pub fn reindex(&self) -> Result<(), failure::Error> {
-        remove_dir_all("data/utxo")?;
-        let db = sled::open("data/utxo")?;
-        let utxos = self.chain.find_utxo_all();
-        for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), bincode::serialize(&outs)?)?;
-        }
-        Ok(())
-    }
-
-    pub fn update(&self, block: &Block) -> Result<(), failure::Error> {
-        let db = sled::open("data/utxo")?;
-        for tx in block.get_transaction() {
-            if !tx.is_coinbase() {
-                for vin in &tx.input {
-                    let mut updated_outputs = TxOutputs { outputs: vec![] };
-                    let outs: TxOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for idx in 0..outs.outputs.len() {
-                        if idx != vin.vout as usize {
-                            updated_outputs.outputs.push(outs.outputs[idx].clone());
-                        }
-                    }
-                    if updated_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), serialize(&updated_outputs)?)?;
-                    }
-                }
-            }
-            let mut new_outputs = TxOutputs {
-                outputs: Vec::new(),
-            };
-            for out in &tx.output {
-                new_outputs.outputs.push(out.clone());
-            }
-            db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
-        }
-        Ok(())
+        self.chain.rebuild_utxos()
     }
 
+    /// number of transactions that still have at least one unspent output
     pub fn count_transactions(&self) -> Result<i32, failure::Error> {
-        let mut counter = 0;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            kv?;
-            counter += 1;
+        let mut txids = HashSet::new();
+        for (key, _) in self.chain.store.scan_prefix(Tree::Utxos, &[])? {
+            txids.insert(OutPoint::from_key(&key)?.txid);
         }
-        Ok(counter)
+        Ok(txids.len() as i32)
     }
 
+    /// picks outputs of `pub_key_hash` in `view` worth at least `amount` with `selector`,
+    /// coinbase outputs that are not mature in the next block are skipped
+    /// if the outputs are not enough, their total is returned without any outputs
     pub fn find_spendable_outputs(
         &self,
+        view: &UtxoView,
         pub_key_hash: &[u8],
-        amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<i32>>), failure::Error> {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated = 0;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            let (k, v) = kv?;
-            let txid = String::from_utf8(k.to_vec())?;
-            let outs: TxOutputs = deserialize(&v.to_vec())?;
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
-                }
+        amount: Amount,
+        selector: &dyn CoinSelector,
+    ) -> Result<(Amount, HashMap<String, Vec<i32>>), failure::Error> {
+        let height = self.chain.get_best_height()? + 1;
+        let mut coins = Vec::new();
+        for (outpoint, entry) in view.get_address_utxos(pub_key_hash)? {
+            if entry.is_spendable_at(height, self.chain.config().params()) {
+                coins.push(Coin {
+                    outpoint,
+                    value: entry.value,
+                });
             }
         }
+
+        // a total above the money supply can't be spent anyway, so it is capped
+        let total = |coins: &[Coin]| {
+            Amount::checked_sum(coins.iter().map(|coin| coin.value)).unwrap_or(Amount::MAX)
+        };
+        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
+        let selected = match selector.select(&coins, amount) {
+            Some(selected) => selected,
+            None => return Ok((total(&coins), unspent_outputs)),
+        };
+        let accumulated = total(&selected);
+        for coin in selected {
+            unspent_outputs
+                .entry(coin.outpoint.txid)
+                .or_default()
+                .push(coin.outpoint.vout as i32);
+        }
         Ok((accumulated, unspent_outputs))
     }
 
@@ -99,15 +80,8 @@ impl UnspentTXUtil {
         let mut utxos = TxOutputs {
             outputs: Vec::new(),
         };
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            let (_, v) = kv?;
-            let outs: TxOutputs = deserialize(&v.to_vec())?;
-            for out in outs.outputs {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
-                }
-            }
+        for (_, entry) in self.chain.store.get_address_utxos(pub_key_hash)? {
+            utxos.outputs.push(entry.to_output());
         }
         Ok(utxos)
     }
@@ -1,5 +1,7 @@
 use crypto::digest::Digest;
 use crypto::ripemd160::Ripemd160;
+use std::path::Path;
+use std::time::SystemTime;
 
 pub fn print_bytes(bytes: &[u8]) {
     for b in bytes {
@@ -18,3 +20,39 @@ pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     pub_key.resize(20, 0);
     hasher2.result(pub_key);
 }
+
+pub fn hex_decode(data: &str) -> Result<Vec<u8>, failure::Error> {
+    if !data.len().is_multiple_of(2) {
+        return Err(failure::format_err!("odd length hex string {}", data));
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&data[i..i + 2], 16)?))
+        .collect()
+}
+
+pub fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// milliseconds since the unix epoch, the unit of block timestamps
+pub fn now_ms() -> Result<u64, failure::Error> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis() as u64)
+}
+
+/// opens a sled database, sled releases the file lock of a dropped handle
+/// from a background thread, so a busy lock is retried for a short while
+pub fn open_db<P: AsRef<Path>>(path: P) -> Result<sled::Db, failure::Error> {
+    let mut attempts = 0;
+    loop {
+        match sled::open(path.as_ref()) {
+            Err(sled::Error::Io(e)) if e.kind() == std::io::ErrorKind::Other && attempts < 50 => {
+                attempts += 1;
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            db => return Ok(db?),
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::block_store::{BlockStore, StoreBatch};
+use crate::block_validation::BlockValidationError;
+use crate::config::ChainParams;
+use crate::script::Script;
+use crate::transaction::Transaction;
+use crate::txs::{RelativeLock, TXOutput};
+
+/// OutPoint names one output of a transaction
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub txid: String,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: &str, vout: u32) -> OutPoint {
+        OutPoint {
+            txid: txid.to_string(),
+            vout,
+        }
+    }
+
+    /// txid followed by the big endian output index,
+    /// so the outputs of a transaction are next to each other in the store
+    pub fn to_key(&self) -> Vec<u8> {
+        let mut key = self.txid.as_bytes().to_vec();
+        key.extend_from_slice(&self.vout.to_be_bytes());
+        key
+    }
+
+    pub fn from_key(key: &[u8]) -> Result<OutPoint, failure::Error> {
+        if key.len() < 4 {
+            return Err(format_err!("outpoint key is too short"));
+        }
+        let (txid, vout) = key.split_at(key.len() - 4);
+        Ok(OutPoint {
+            txid: String::from_utf8(txid.to_vec())?,
+            vout: u32::from_be_bytes(vout.try_into()?),
+        })
+    }
+}
+
+/// UtxoEntry is an unspent output together with the height of the block that created it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UtxoEntry {
+    pub value: Amount,
+    pub script_pubkey: Script,
+    pub height: i32,
+    /// timestamp of the block that created it
+    pub time: u64,
+    pub is_coinbase: bool,
+}
+
+impl UtxoEntry {
+    /// coinbase outputs can only be spent `coinbase_maturity` blocks after their creation
+    pub fn is_spendable_at(&self, height: i32, params: &ChainParams) -> bool {
+        !self.is_coinbase || height - self.height >= params.coinbase_maturity
+    }
+
+    /// whether an input with a relative `lock` may spend the output in a block
+    /// at `height` with timestamp `time`
+    pub fn is_unlocked(&self, lock: RelativeLock, height: i32, time: u64) -> bool {
+        match lock {
+            RelativeLock::Blocks(blocks) => height - self.height >= blocks as i32,
+            RelativeLock::Time(ms) => time.saturating_sub(self.time) >= ms,
+        }
+    }
+
+    pub fn to_output(&self) -> TXOutput {
+        TXOutput {
+            value: self.value,
+            script_pubkey: self.script_pubkey.clone(),
+        }
+    }
+}
+
+/// BlockUndo holds the value every UTXO entry had before a block was connected
+/// replaying it backwards restores the set as it was before the block
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BlockUndo {
+    entries: Vec<(OutPoint, Option<UtxoEntry>)>,
+}
+
+/// UtxoOverlay collects UTXO changes on top of the store
+/// reads see the pending changes, so several blocks can be connected or
+/// disconnected before everything is written with the block in one batch
+pub struct UtxoOverlay<'a> {
+    store: &'a dyn BlockStore,
+    changes: HashMap<OutPoint, Option<UtxoEntry>>,
+}
+
+impl<'a> UtxoOverlay<'a> {
+    pub fn new(store: &'a dyn BlockStore) -> UtxoOverlay<'a> {
+        UtxoOverlay {
+            store,
+            changes: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Result<Option<UtxoEntry>, failure::Error> {
+        match self.changes.get(outpoint) {
+            Some(entry) => Ok(entry.clone()),
+            None => self.store.get_utxo(outpoint),
+        }
+    }
+
+    /// sets the entry of `outpoint` and returns the previous value
+    fn set(
+        &mut self,
+        outpoint: &OutPoint,
+        entry: Option<UtxoEntry>,
+    ) -> Result<Option<UtxoEntry>, failure::Error> {
+        let prev = self.get(outpoint)?;
+        self.changes.insert(outpoint.clone(), entry);
+        Ok(prev)
+    }
+
+    /// removes the outputs spent by `block` and adds the ones it creates
+    /// fails if an input is not unspent or not mature yet, if an output already exists,
+    /// if a transaction spends more than its inputs or if the coinbase claims more
+    /// than subsidy and fees
+    pub fn connect_block(
+        &mut self,
+        block: &Block,
+        params: &ChainParams,
+    ) -> Result<BlockUndo, failure::Error> {
+        let mut undo = BlockUndo::default();
+        let mut allowed = params.subsidy(block.get_height());
+        let mut coinbase_value = Amount::ZERO;
+        for tx in block.get_transaction() {
+            if tx.is_coinbase() {
+                coinbase_value = tx.output_value()?;
+            }
+            let fee = self.connect_transaction(
+                tx,
+                block.get_height(),
+                block.get_timestamp(),
+                params,
+                &mut undo,
+            )?;
+            allowed = allowed
+                .checked_add(fee)
+                .ok_or_else(|| BlockValidationError::BadAmount(tx.id.clone()))?;
+        }
+        if coinbase_value > allowed {
+            return Err(BlockValidationError::BadCoinbaseValue(coinbase_value, allowed).into());
+        }
+        Ok(undo)
+    }
+
+    /// spends the inputs of `tx` and adds its outputs, returns the fee it pays
+    /// `height` and `time` are those of the block the transaction is in
+    pub fn connect_transaction(
+        &mut self,
+        tx: &Transaction,
+        height: i32,
+        time: u64,
+        params: &ChainParams,
+        undo: &mut BlockUndo,
+    ) -> Result<Amount, failure::Error> {
+        let output_value = tx.output_value()?;
+        let mut input_value = Amount::ZERO;
+        if !tx.is_coinbase() {
+            for vin in &tx.input {
+                let outpoint = OutPoint::new(&vin.txid, vin.vout as u32);
+                let entry = match self.get(&outpoint)? {
+                    Some(entry) if vin.vout >= 0 => entry,
+                    _ => {
+                        return Err(
+                            BlockValidationError::MissingOutput(vin.txid.clone(), vin.vout).into(),
+                        )
+                    }
+                };
+                if !entry.is_spendable_at(height, params) {
+                    return Err(BlockValidationError::ImmatureCoinbase(
+                        vin.txid.clone(),
+                        entry.height,
+                    )
+                    .into());
+                }
+                if let Some(lock) = vin.relative_lock() {
+                    if !entry.is_unlocked(lock, height, time) {
+                        return Err(BlockValidationError::RelativeLockNotMet(
+                            vin.txid.clone(),
+                            vin.vout,
+                        )
+                        .into());
+                    }
+                }
+                input_value = input_value
+                    .checked_add(entry.value)
+                    .ok_or_else(|| BlockValidationError::BadAmount(tx.id.clone()))?;
+                let prev = self.set(&outpoint, None)?;
+                undo.entries.push((outpoint, prev));
+            }
+        }
+        for (vout, out) in tx.output.iter().enumerate() {
+            if out.is_data() {
+                continue;
+            }
+            let outpoint = OutPoint::new(&tx.id, vout as u32);
+            // a transaction repeating the id of one with unspent outputs would overwrite them
+            if self.get(&outpoint)?.is_some() {
+                return Err(
+                    BlockValidationError::DuplicateOutput(tx.id.clone(), vout as u32).into(),
+                );
+            }
+            let entry = UtxoEntry {
+                value: out.value,
+                script_pubkey: out.script_pubkey.clone(),
+                height,
+                time,
+                is_coinbase: tx.is_coinbase(),
+            };
+            let prev = self.set(&outpoint, Some(entry))?;
+            undo.entries.push((outpoint, prev));
+        }
+        if tx.is_coinbase() {
+            return Ok(Amount::ZERO);
+        }
+        input_value
+            .checked_sub(output_value)
+            .ok_or_else(|| BlockValidationError::InputsBelowOutputs(tx.id.clone()).into())
+    }
+
+    /// restores the set to the state before `connect_block` was applied
+    pub fn disconnect_block(&mut self, undo: BlockUndo) -> Result<(), failure::Error> {
+        for (outpoint, prev) in undo.entries.into_iter().rev() {
+            self.set(&outpoint, prev)?;
+        }
+        Ok(())
+    }
+
+    /// adds the pending changes to `batch`, keeping the address index in step
+    pub fn write_to(self, batch: &mut StoreBatch) -> Result<(), failure::Error> {
+        for (outpoint, entry) in self.changes {
+            let old = self.store.get_utxo(&outpoint)?;
+            if let Some(hash) = old
+                .as_ref()
+                .and_then(|old| old.script_pubkey.address_hash())
+            {
+                batch.remove_address_utxo(hash, &outpoint);
+            }
+            match entry {
+                Some(entry) => {
+                    if let Some(hash) = entry.script_pubkey.address_hash() {
+                        batch.put_address_utxo(hash, &outpoint);
+                    }
+                    batch.put_utxo(&outpoint, &entry)?;
+                }
+                None => batch.remove_utxo(&outpoint),
+            }
+        }
+        Ok(())
+    }
+}
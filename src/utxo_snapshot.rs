@@ -0,0 +1,247 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use bincode::{deserialize_from, serialize, serialize_into};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use crate::block_store::{StoreBatch, Tree};
+use crate::unspent_tx_util::UnspentTXUtil;
+use crate::utils::hex_encode;
+use crate::utxo_set::{OutPoint, UtxoEntry};
+
+/// layout of a snapshot file
+/// | magic 8 | version u32 le | block hash, height, count | entries | commitment 32 |
+/// the header and every `(OutPoint, UtxoEntry)` entry are bincode encoded
+const SNAPSHOT_MAGIC: &[u8; 8] = b"CRSUTXO\0";
+const SNAPSHOT_VERSION: u32 = 4;
+
+/// SnapshotInfo describes a UTXO set snapshot
+/// the commitment is a rolling sha256 over the block it was taken at
+/// and every entry in outpoint order, so equal sets give equal commitments
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub block_hash: String,
+    pub height: i32,
+    pub count: u64,
+    pub commitment: [u8; 32],
+}
+
+impl SnapshotInfo {
+    pub fn commitment_hex(&self) -> String {
+        hex_encode(&self.commitment)
+    }
+}
+
+/// UtxoCommitment hashes a UTXO set one entry at a time
+/// each step hashes the previous state together with the next entry
+pub struct UtxoCommitment {
+    state: [u8; 32],
+    count: u64,
+}
+
+impl UtxoCommitment {
+    pub fn new(block_hash: &str, height: i32) -> Result<UtxoCommitment, failure::Error> {
+        let mut hasher = Sha256::new();
+        hasher.input(&serialize(&(block_hash, height))?);
+        let mut state = [0u8; 32];
+        hasher.result(&mut state);
+        Ok(UtxoCommitment { state, count: 0 })
+    }
+
+    /// entries have to be added in outpoint key order
+    pub fn add(&mut self, outpoint: &OutPoint, entry: &UtxoEntry) -> Result<(), failure::Error> {
+        let mut hasher = Sha256::new();
+        hasher.input(&self.state);
+        hasher.input(&outpoint.to_key());
+        hasher.input(&serialize(entry)?);
+        hasher.result(&mut self.state);
+        self.count += 1;
+        Ok(())
+    }
+}
+
+impl UnspentTXUtil {
+    /// commitment of the UTXO set at the current tip
+    pub fn commitment(&self) -> Result<SnapshotInfo, failure::Error> {
+        self.export_to(&mut std::io::sink())
+    }
+
+    /// writes the UTXO set at the current tip to `path`
+    pub fn export_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<SnapshotInfo, failure::Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let info = self.export_to(&mut file)?;
+        file.flush()?;
+        Ok(info)
+    }
+
+    fn export_to<W: Write>(&self, w: &mut W) -> Result<SnapshotInfo, failure::Error> {
+        let block_hash = self
+            .chain
+            .store
+            .get_head()?
+            .ok_or_else(|| format_err!("chain has no head"))?;
+        let height = self.chain.get_best_height()?;
+        // the store iterates in key order, which is the commitment order
+        let entries = self.chain.store.scan_prefix(Tree::Utxos, &[])?;
+
+        w.write_all(SNAPSHOT_MAGIC)?;
+        w.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        serialize_into(&mut *w, &(&block_hash, height, entries.len() as u64))?;
+        let mut commitment = UtxoCommitment::new(&block_hash, height)?;
+        for (key, value) in entries {
+            let outpoint = OutPoint::from_key(&key)?;
+            let entry: UtxoEntry = bincode::deserialize(&value)?;
+            commitment.add(&outpoint, &entry)?;
+            serialize_into(&mut *w, &(&outpoint, &entry))?;
+        }
+        w.write_all(&commitment.state)?;
+
+        Ok(SnapshotInfo {
+            block_hash,
+            height,
+            count: commitment.count,
+            commitment: commitment.state,
+        })
+    }
+
+    /// replaces the UTXO set with the one in the snapshot at `path`
+    /// the snapshot has to be taken at the current tip of the chain,
+    /// its commitment is recomputed and, if given, compared with `expected`
+    pub fn import_snapshot<P: AsRef<Path>>(
+        &self,
+        path: P,
+        expected: Option<&str>,
+    ) -> Result<SnapshotInfo, failure::Error> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(format_err!("not a UTXO snapshot file"));
+        }
+        let mut version = [0u8; 4];
+        r.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != SNAPSHOT_VERSION {
+            return Err(format_err!("unsupported snapshot version {}", version));
+        }
+
+        let (block_hash, height, count): (String, i32, u64) = deserialize_from(&mut r)?;
+        let tip = self.chain.store.get_head()?;
+        if tip.as_deref() != Some(block_hash.as_str()) {
+            return Err(format_err!(
+                "snapshot is taken at block {} (height {}), the chain tip is {:?}",
+                block_hash,
+                height,
+                tip
+            ));
+        }
+
+        let mut batch = StoreBatch::new();
+        batch.clear(self.chain.store.as_ref(), Tree::Utxos)?;
+        batch.clear(self.chain.store.as_ref(), Tree::AddressUtxos)?;
+        let mut commitment = UtxoCommitment::new(&block_hash, height)?;
+        let mut last_key: Option<Vec<u8>> = None;
+        for _ in 0..count {
+            let (outpoint, entry): (OutPoint, UtxoEntry) = deserialize_from(&mut r)?;
+            let key = outpoint.to_key();
+            if last_key.as_ref().is_some_and(|last| *last >= key) {
+                return Err(format_err!("snapshot entries are not in outpoint order"));
+            }
+            commitment.add(&outpoint, &entry)?;
+            if let Some(hash) = entry.script_pubkey.address_hash() {
+                batch.put_address_utxo(hash, &outpoint);
+            }
+            batch.put_utxo(&outpoint, &entry)?;
+            last_key = Some(key);
+        }
+        let mut stored = [0u8; 32];
+        r.read_exact(&mut stored)?;
+        if stored != commitment.state {
+            return Err(format_err!(
+                "snapshot commitment does not match its entries"
+            ));
+        }
+        let info = SnapshotInfo {
+            block_hash,
+            height,
+            count,
+            commitment: commitment.state,
+        };
+        if let Some(expected) = expected {
+            if expected != info.commitment_hex() {
+                return Err(format_err!(
+                    "snapshot commitment {} is not the trusted {}",
+                    info.commitment_hex(),
+                    expected
+                ));
+            }
+        }
+
+        self.chain.store.write(batch)?;
+        self.chain.store.flush()?;
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coin_selection::LargestFirst;
+    use crate::test_util::{coins, fork_from_genesis, TestChain};
+    use crate::transaction::{Fee, Transaction};
+
+    #[test]
+    fn utxo_snapshot_round_trip() {
+        let mut chain = TestChain::new(2);
+        let (alice, bob) = (chain.wallets[0].clone(), chain.wallets[1].clone());
+        let mut peer = fork_from_genesis(&chain.bc, &chain.config);
+        let pay = Transaction::new(
+            &alice,
+            &bob,
+            coins(30),
+            Fee::default(),
+            &LargestFirst,
+            &chain.utxo(),
+        )
+        .unwrap();
+        let coinbase = chain.coinbase(&alice);
+        let b1 = chain.bc.mine_block(vec![coinbase, pay]).unwrap();
+
+        let path = chain.path().join("utxo.snapshot");
+        let utxo = chain.utxo();
+        let info = utxo.export_snapshot(&path).unwrap();
+        assert_eq!(info.count, 3);
+        assert_eq!(utxo.commitment().unwrap(), info);
+
+        // the peer has the blocks but not the set at the tip
+        let peer_utxo = UnspentTXUtil {
+            chain: peer.clone(),
+        };
+        assert!(peer_utxo.import_snapshot(&path, None).is_err());
+        peer.add_block(b1).unwrap();
+        let mut batch = StoreBatch::new();
+        batch.clear(peer.store.as_ref(), Tree::Utxos).unwrap();
+        peer.store.write(batch).unwrap();
+        assert_ne!(peer_utxo.commitment().unwrap(), info);
+
+        assert!(peer_utxo
+            .import_snapshot(&path, Some(&"00".repeat(32)))
+            .is_err());
+        let imported = peer_utxo
+            .import_snapshot(&path, Some(&info.commitment_hex()))
+            .unwrap();
+        assert_eq!(imported, info);
+        assert_eq!(peer_utxo.commitment().unwrap(), info);
+
+        // a modified entry no longer matches the commitment in the file
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 40;
+        data[last] ^= 1;
+        std::fs::write(&path, data).unwrap();
+        assert!(peer_utxo.import_snapshot(&path, None).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::transaction::Transaction;
+use crate::utxo_set::{OutPoint, UtxoEntry};
+
+/// UtxoView is the UTXO set of the chain with unconfirmed transactions on top
+/// outputs of pending transactions count as unspent and the outputs they spend as spent,
+/// so transactions can be built and verified on top of parents that are not mined yet
+pub struct UtxoView<'a> {
+    chain: &'a Blockchain,
+    pending: HashMap<&'a str, &'a Transaction>,
+    spent: HashSet<OutPoint>,
+}
+
+impl<'a> UtxoView<'a> {
+    /// a view of the confirmed outputs only
+    pub fn new(chain: &'a Blockchain) -> UtxoView<'a> {
+        UtxoView {
+            chain,
+            pending: HashMap::new(),
+            spent: HashSet::new(),
+        }
+    }
+
+    /// adds every transaction of `mempool` on top
+    pub fn with_mempool(mut self, mempool: &'a Mempool) -> UtxoView<'a> {
+        for tx in mempool.transactions() {
+            self.add_pending(tx);
+        }
+        self
+    }
+
+    /// adds `tx` on top, it is expected to fit the view
+    pub fn add_pending(&mut self, tx: &'a Transaction) {
+        for outpoint in tx.spent_outpoints() {
+            self.spent.insert(outpoint);
+        }
+        self.pending.insert(&tx.id, tx);
+    }
+
+    /// the output at `outpoint` if it is unspent, outputs of pending transactions
+    /// are treated as created in the next block and are not confirmed at any time
+    pub fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<UtxoEntry>, failure::Error> {
+        if self.spent.contains(outpoint) {
+            return Ok(None);
+        }
+        let tx = match self.pending.get(outpoint.txid.as_str()) {
+            Some(tx) => tx,
+            None => return self.chain.store.get_utxo(outpoint),
+        };
+        let out = match tx.output.get(outpoint.vout as usize) {
+            Some(out) if !out.is_data() => out,
+            _ => return Ok(None),
+        };
+        Ok(Some(UtxoEntry {
+            value: out.value,
+            script_pubkey: out.script_pubkey.clone(),
+            height: self.chain.get_best_height()? + 1,
+            time: u64::MAX,
+            is_coinbase: false,
+        }))
+    }
+
+    /// the unspent outputs paying to `pub_key_hash`
+    pub fn get_address_utxos(
+        &self,
+        pub_key_hash: &[u8],
+    ) -> Result<Vec<(OutPoint, UtxoEntry)>, failure::Error> {
+        let mut utxos: Vec<(OutPoint, UtxoEntry)> = self
+            .chain
+            .store
+            .get_address_utxos(pub_key_hash)?
+            .into_iter()
+            .filter(|(outpoint, _)| !self.spent.contains(outpoint))
+            .collect();
+        for tx in self.pending.values() {
+            for (vout, out) in tx.output.iter().enumerate() {
+                if out.script_pubkey.address_hash() != Some(pub_key_hash) {
+                    continue;
+                }
+                let outpoint = OutPoint::new(&tx.id, vout as u32);
+                if let Some(entry) = self.get_utxo(&outpoint)? {
+                    utxos.push((outpoint, entry));
+                }
+            }
+        }
+        Ok(utxos)
+    }
+
+    /// a pending transaction or one of the active chain
+    pub fn find_transaction(&self, id: &str) -> Result<Transaction, failure::Error> {
+        match self.pending.get(id) {
+            Some(tx) => Ok((*tx).clone()),
+            None => self.chain.find_transaction(id),
+        }
+    }
+
+    /// the transactions the inputs of `tx` spend outputs of
+    pub fn prev_txs(
+        &self,
+        tx: &Transaction,
+    ) -> Result<HashMap<String, Transaction>, failure::Error> {
+        let mut prev_txs = HashMap::new();
+        for vin in &tx.input {
+            let prev_tx = self.find_transaction(&vin.txid)?;
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+        }
+        Ok(prev_txs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::test_util::{coinbase, coins, TestChain};
+    use crate::transaction::Fee;
+    use crate::transaction_builder::TransactionBuilder;
+    use crate::unspent_tx_util::UnspentTXUtil;
+    use crate::utils::now_ms;
+    use bitcoincash_addr::Address;
+
+    #[test]
+    fn unconfirmed_chains() {
+        let mut chain = TestChain::new(3);
+        let (alice, bob, carol) = (&chain.wallets[0], &chain.wallets[1], &chain.wallets[2]);
+        let config = chain.config.clone();
+        let utxo = chain.utxo();
+        let bc = &mut chain.bc;
+        let now = now_ms().unwrap();
+        let mut mempool = Mempool::new(config.mempool_policy().clone());
+        fn payment<'a>(
+            utxo: &'a UnspentTXUtil,
+            from: &str,
+            to: &str,
+            amount: u64,
+            mempool: &'a Mempool,
+        ) -> TransactionBuilder<'a> {
+            TransactionBuilder::new(from, utxo)
+                .pay_to(to, coins(amount))
+                .unwrap()
+                .with_fee(Fee::PerByte(Amount::from_units(1)))
+                .with_mempool(mempool)
+        }
+
+        let first = payment(&utxo, alice, bob, 10, &mempool).build().unwrap();
+        mempool.add(first.clone(), bc, now).unwrap();
+        // alice sends again from the change of her unconfirmed payment
+        let second = payment(&utxo, alice, carol, 5, &mempool).build().unwrap();
+        assert_eq!(second.input[0].txid, first.id);
+        assert!(!bc.verify_transacton(&second).unwrap());
+        let view = UtxoView::new(bc).with_mempool(&mempool);
+        assert!(bc.verify_transaction_in(&second, &view).unwrap());
+        mempool.add(second.clone(), bc, now).unwrap();
+
+        // bob spends what he got before it is mined, naming the output himself
+        let third = payment(&utxo, bob, carol, 4, &mempool)
+            .with_input(OutPoint::new(&first.id, 0))
+            .build()
+            .unwrap();
+        assert!(payment(&utxo, bob, carol, 4, &Mempool::default())
+            .with_input(OutPoint::new(&first.id, 0))
+            .build()
+            .is_err());
+        mempool.add(third.clone(), bc, now).unwrap();
+        assert_eq!(
+            mempool.ancestors(&third.id),
+            HashSet::from([first.id.clone()])
+        );
+
+        // parents have to come first in a block
+        assert!(bc
+            .prepare_block(vec![coinbase(bob, &config), second.clone(), first.clone()])
+            .is_err());
+        bc.mine_block(vec![coinbase(bob, &config), first, second, third])
+            .unwrap();
+        let balance = |address: &str| {
+            let hash = Address::decode(address).unwrap().body;
+            let outputs = utxo.find_UTXO(&hash).unwrap().outputs;
+            Amount::checked_sum(outputs.iter().map(|out| out.value)).unwrap()
+        };
+        assert_eq!(balance(carol), coins(9));
+        assert!(balance(bob) > coins(105) && balance(bob) < coins(106));
+    }
+}
@@ -29,7 +29,7 @@ impl Wallet {
         let address = Address {
             body: pub_hash,
             scheme: Scheme::Base58,
-            hash_type: HashType::Script,
+            hash_type: HashType::Key,
             ..Default::default()
         };
         address.encode().unwrap()
@@ -1,22 +1,28 @@
+use crate::config::ChainConfig;
+use crate::utils::open_db;
 use crate::wallet::Wallet;
 use log::info;
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 pub struct WalletChain {
     wallets: HashMap<String, Wallet>,
+    path: PathBuf,
 }
 
 impl WalletChain {
-    pub fn new() -> Result<WalletChain, failure::Error> {
+    pub fn new(config: &ChainConfig) -> Result<WalletChain, failure::Error> {
         let mut wlt = WalletChain {
             wallets: HashMap::<String, Wallet>::new(),
+            path: config.wallets_path(),
         };
-        let db = sled::open("data/wallets")?;
+        let db = open_db(&wlt.path)?;
         for item in db.into_iter() {
             let i = item?;
-            let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1.to_vec())?;
-            wlt.wallets.insert(address, wallet);
+            let wallet: Wallet = bincode::deserialize(&i.1)?;
+            // wallets are keyed by their current address, older
+            // versions saved them under a script address
+            wlt.wallets.insert(wallet.get_address(), wallet);
         }
         drop(db);
         Ok(wlt)
@@ -43,7 +49,7 @@ impl WalletChain {
     }
 
     pub fn save_all(&self) -> Result<(), failure::Error> {
-        let db = sled::open("data/wallets")?;
+        let db = open_db(&self.path)?;
         for (address, wallet) in &self.wallets {
             let data = bincode::serialize(wallet)?;
             db.insert(address, data)?;
@@ -79,12 +85,14 @@ mod test {
 
     #[test]
     fn test_wallets() {
-        let mut ws = WalletChain::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let config = ChainConfig::new(dir.path());
+        let mut ws = WalletChain::new(&config).unwrap();
         let wa1 = ws.create_wallet();
         let w1 = ws.get_wallet(&wa1).unwrap().clone();
         ws.save_all().unwrap();
 
-        let ws2 = WalletChain::new().unwrap();
+        let ws2 = WalletChain::new(&config).unwrap();
         let w2 = ws2.get_wallet(&wa1).unwrap();
         assert_eq!(&w1, w2);
     }
@@ -93,7 +101,8 @@ mod test {
     #[should_panic]
     fn test_wallets_not_exist() {
         let w3 = Wallet::new();
-        let ws2 = WalletChain::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let ws2 = WalletChain::new(&ChainConfig::new(dir.path())).unwrap();
         ws2.get_wallet(&w3.get_address()).unwrap();
     }
 